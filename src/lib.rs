@@ -0,0 +1,13 @@
+// the codebase favours explicit returns and the hardware's own mnemonics (CPU, NOP, RETI...)
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod runtime;
+
+pub use runtime::Runtime;
+pub use runtime::cpu::registers::Registers;
+pub use runtime::{CYCLES_PER_SECOND, CYCLES_PER_FRAME};
+pub use runtime::cartridge::rtc::RtcClock;
+pub use runtime::joypad::Button;
+pub use runtime::apu::DEFAULT_SAMPLE_RATE;
+pub use runtime::ppu::{Renderer, SCREEN_WIDTH, SCREEN_HEIGHT};
+pub use runtime::save_state::StateError;
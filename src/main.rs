@@ -1,18 +1,321 @@
-mod runtime;
-
-use runtime::Runtime;
+use dmg_e::{Renderer, Runtime, RtcClock, CYCLES_PER_SECOND};
+use dmg_e::runtime::printer::Printer;
+use dmg_e::runtime::tcp_link::TcpLink;
+use dmg_e::runtime::gbs::{GbsHeader, GbsPlayer};
+use dmg_e::runtime::test_runner::{run_test_rom, TestLimits, TestResult};
+use std::cell::RefCell;
 use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
 use std::process::exit;
-use ctrlc;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const USAGE: &str = "usage: dmg-e <rom> [--rtc-host] [--save-interval <seconds>] [--pixel-fifo] [--link-listen <[host:]port> | --link-connect <host:port> | --printer <png>] [--record-audio <wav> [--stems]] [--record-vgm <vgm>] [--load-state <state>] [--save-state <state>]
+       dmg-e gbs <gbs> <wav> [--track <number>] [--length <emulated seconds>]
+       dmg-e test <rom> [--timeout <emulated seconds>] [--time-limit <host seconds>] [--pixel-fifo] [--record-audio <wav> [--stems]] [--record-vgm <vgm>]";
+
+// exit codes for test mode
+const EXIT_PASSED: i32 = 0;
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_TIMEOUT: i32 = 3;
+
+const DEFAULT_TEST_TIMEOUT: usize = 120; // emulated seconds
+const DEFAULT_TRACK_LENGTH: usize = 120; // emulated seconds
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("test") => { run_test(&args[2..]); }
+        Some("gbs") => { run_gbs(&args[2..]); }
+        _ => { run_rom(&args[1..]); }
+    }
+}
+
+fn usage_error() -> ! {
+    eprintln!("{}", USAGE);
+    exit(EXIT_USAGE);
+}
+
+fn parse_number(arg: Option<&String>) -> usize {
+    arg.and_then(|arg| arg.parse().ok()).unwrap_or_else(|| usage_error())
+}
+
+fn start_recording(runtime: &mut Runtime, record_audio: &Option<PathBuf>, stems: bool, record_vgm: &Option<PathBuf>) {
+    if let Some(path) = record_audio {
+        if let Err(error) = runtime.start_audio_recording(path, stems) {
+            eprintln!("failed to create {}: {}", path.display(), error);
+            exit(EXIT_USAGE);
+        }
+    }
+    if let Some(path) = record_vgm {
+        if let Err(error) = runtime.start_vgm_log(path) {
+            eprintln!("failed to create {}: {}", path.display(), error);
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn open_rom(rom: String) -> Runtime {
+    Runtime::initialize(rom.clone()).unwrap_or_else(|error| {
+        eprintln!("failed to load {}: {}", rom, error);
+        exit(EXIT_USAGE);
+    })
+}
+
+fn open_link(link: io::Result<TcpLink>) -> TcpLink {
+    link.unwrap_or_else(|error| {
+        eprintln!("failed to set up the link cable: {}", error);
+        exit(EXIT_USAGE);
+    })
+}
+
+// exit() skips destructors, so the recordings have to be finished by hand
+fn stop_recording(runtime: &mut Runtime) {
+    if let Err(error) = runtime.stop_audio_recording() {
+        eprintln!("failed to write audio recording: {}", error);
+    }
+    if let Err(error) = runtime.stop_vgm_log() {
+        eprintln!("failed to write vgm log: {}", error);
+    }
+}
+
+// what's plugged into the serial port
+enum SerialOption {
+    None,
+    Listen(String), // a port on localhost, or host:port
+    Connect(String),
+    Printer(PathBuf),
+}
+
+fn run_rom(args: &[String]) {
+    let mut rom: Option<String> = None;
+    let mut rtc_host: bool = false;
+    let mut serial: SerialOption = SerialOption::None;
+    let mut save_interval: Option<usize> = None;
+    let mut renderer: Renderer = Renderer::Scanline;
+    let mut record_audio: Option<PathBuf> = None;
+    let mut stems: bool = false;
+    let mut record_vgm: Option<PathBuf> = None;
+    let mut load_state: Option<PathBuf> = None;
+    let mut save_state: Option<PathBuf> = None;
+    let mut index: usize = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--rtc-host" => {
+                rtc_host = true;
+            }
+            "--save-interval" => {
+                index += 1;
+                save_interval = Some(parse_number(args.get(index)) * CYCLES_PER_SECOND);
+            }
+            "--link-listen" => {
+                index += 1;
+                serial = SerialOption::Listen(args.get(index).cloned().unwrap_or_else(|| usage_error()));
+            }
+            "--link-connect" => {
+                index += 1;
+                serial = SerialOption::Connect(args.get(index).cloned().unwrap_or_else(|| usage_error()));
+            }
+            "--printer" => {
+                index += 1;
+                serial = SerialOption::Printer(args.get(index).map(PathBuf::from).unwrap_or_else(|| usage_error()));
+            }
+            "--pixel-fifo" => {
+                renderer = Renderer::PixelFifo;
+            }
+            "--record-audio" => {
+                index += 1;
+                record_audio = Some(args.get(index).map(PathBuf::from).unwrap_or_else(|| usage_error()));
+            }
+            "--stems" => {
+                stems = true;
+            }
+            "--record-vgm" => {
+                index += 1;
+                record_vgm = Some(args.get(index).map(PathBuf::from).unwrap_or_else(|| usage_error()));
+            }
+            "--load-state" => {
+                index += 1;
+                load_state = Some(args.get(index).map(PathBuf::from).unwrap_or_else(|| usage_error()));
+            }
+            "--save-state" => {
+                index += 1;
+                save_state = Some(args.get(index).map(PathBuf::from).unwrap_or_else(|| usage_error()));
+            }
+            arg => {
+                rom = Some(arg.to_owned());
+            }
+        }
+        index += 1;
+    }
+    let Some(rom) = rom else { usage_error(); };
+
+    let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+    let handler_running: Arc<AtomicBool> = running.clone();
     ctrlc::set_handler(move || {
-        exit(1);
+        handler_running.store(false, Ordering::SeqCst);
     }).expect("Error setting Ctrl-C handler");
 
-    let args: Vec<String> = env::args().collect();
+    let mut runtime: Runtime = open_rom(rom);
+    runtime.set_renderer(renderer);
+    let mut printer: Option<Rc<RefCell<Printer>>> = None;
+    match &serial {
+        SerialOption::None => {
+            // with nothing plugged in whatever the game sends over serial goes to the terminal
+            runtime.set_serial_callback(|byte| {
+                print!("{}", byte as char);
+                let _ = io::stdout().flush();
+            });
+        }
+        SerialOption::Listen(address) => {
+            println!("waiting for a link partner on {}", address);
+            let link: io::Result<TcpLink> = match address.parse::<u16>() {
+                Ok(port) => TcpLink::listen(port),
+                Err(_) => TcpLink::listen_on(address.as_str()),
+            };
+            runtime.connect_serial(Box::new(open_link(link)));
+        }
+        SerialOption::Connect(address) => {
+            runtime.connect_serial(Box::new(open_link(TcpLink::connect(address.as_str()))));
+        }
+        SerialOption::Printer(path) => {
+            let mut device: Printer = Printer::initialize(path);
+            device.set_callback(Box::new(|result| match result {
+                Ok(path) => println!("printed {}", path.display()),
+                Err(error) => eprintln!("failed to save print: {}", error),
+            }));
+            let device: Rc<RefCell<Printer>> = Rc::new(RefCell::new(device));
+            runtime.connect_serial(Box::new(device.clone()));
+            printer = Some(device);
+        }
+    }
+    if rtc_host {
+        runtime.set_rtc_clock(RtcClock::Host);
+    }
+    if let Err(error) = runtime.load_battery_save() {
+        eprintln!("failed to read save file: {}", error);
+    }
+    if let Some(path) = &load_state {
+        // the state has its own copy of the save ram, so it goes on top of the battery save
+        if let Err(error) = runtime.load_state_from(path) {
+            eprintln!("failed to load {}: {}", path.display(), error);
+            exit(EXIT_USAGE);
+        }
+    }
+    runtime.set_autosave_interval(save_interval);
+    start_recording(&mut runtime, &record_audio, stems, &record_vgm);
+
+    runtime.run_until(|_| !running.load(Ordering::Relaxed));
+
+    stop_recording(&mut runtime);
+    if let Some(path) = &save_state {
+        if let Err(error) = runtime.save_state_to(path) {
+            eprintln!("failed to write {}: {}", path.display(), error);
+        }
+    }
+    if let Some(printer) = printer {
+        printer.borrow_mut().finish();
+    }
+    if let Err(error) = runtime.flush_battery_save() {
+        eprintln!("failed to write save file: {}", error);
+    }
+}
+
+fn run_test(args: &[String]) {
+    let mut rom: Option<String> = None;
+    let mut limits: TestLimits = TestLimits::from_seconds(DEFAULT_TEST_TIMEOUT);
+    let mut renderer: Renderer = Renderer::Scanline;
+    let mut record_audio: Option<PathBuf> = None;
+    let mut stems: bool = false;
+    let mut record_vgm: Option<PathBuf> = None;
+    let mut index: usize = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--timeout" => {
+                index += 1;
+                limits.cycles = parse_number(args.get(index)) * CYCLES_PER_SECOND;
+            }
+            "--time-limit" => {
+                index += 1;
+                limits.time = Some(Duration::from_secs(parse_number(args.get(index)) as u64));
+            }
+            "--pixel-fifo" => {
+                renderer = Renderer::PixelFifo;
+            }
+            "--record-audio" => {
+                index += 1;
+                record_audio = Some(args.get(index).map(PathBuf::from).unwrap_or_else(|| usage_error()));
+            }
+            "--stems" => {
+                stems = true;
+            }
+            "--record-vgm" => {
+                index += 1;
+                record_vgm = Some(args.get(index).map(PathBuf::from).unwrap_or_else(|| usage_error()));
+            }
+            arg => {
+                rom = Some(arg.to_owned());
+            }
+        }
+        index += 1;
+    }
+    let Some(rom) = rom else { usage_error(); };
+
+    let mut runtime: Runtime = open_rom(rom);
+    runtime.set_renderer(renderer);
+    start_recording(&mut runtime, &record_audio, stems, &record_vgm);
+    let result: TestResult = run_test_rom(&mut runtime, &limits);
+    stop_recording(&mut runtime);
+    println!("{}", result);
+    match result {
+        TestResult::Passed(_) => { exit(EXIT_PASSED); }
+        TestResult::Failed(_) => { exit(EXIT_FAILED); }
+        TestResult::Timeout(_) => { exit(EXIT_TIMEOUT); }
+    }
+}
 
-    let mut runtime: Runtime = Runtime::initialize(args[1].clone());
+fn run_gbs(args: &[String]) {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut track: Option<usize> = None;
+    let mut length: usize = DEFAULT_TRACK_LENGTH;
+    let mut index: usize = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--track" => {
+                index += 1;
+                track = Some(parse_number(args.get(index)));
+            }
+            "--length" => {
+                index += 1;
+                length = parse_number(args.get(index));
+            }
+            arg => {
+                files.push(PathBuf::from(arg));
+            }
+        }
+        index += 1;
+    }
+    let [gbs, wav] = files.as_slice() else { usage_error(); };
 
-    runtime.run();
+    let mut player: GbsPlayer = GbsPlayer::load(gbs).unwrap_or_else(|error| {
+        eprintln!("failed to read {}: {}", gbs.display(), error);
+        exit(EXIT_USAGE);
+    });
+    let header: &GbsHeader = &player.header;
+    println!("{} - {} ({})", header.title, header.author, header.copyright);
+    // tracks are numbered from 1 like players show them
+    let track: usize = track.unwrap_or(header.first_song.max(1) as usize);
+    if track == 0 { usage_error(); }
+    if let Err(error) = player.start_track(track - 1) {
+        eprintln!("{}", error);
+        exit(EXIT_USAGE);
+    }
+    start_recording(player.runtime(), &Some(wav.clone()), false, &None);
+    player.play_for(length);
+    stop_recording(player.runtime());
 }
@@ -0,0 +1,43 @@
+use std::collections::VecDeque;
+
+// interleaved stereo samples waiting for the embedder, the oldest are dropped if nobody drains it
+pub struct SampleBuffer {
+    samples: VecDeque<i16>,
+    capacity: usize,
+}
+
+impl SampleBuffer {
+    pub fn initialize(capacity: usize) -> SampleBuffer {
+        return SampleBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        };
+    }
+
+    pub fn push(&mut self, left: i16, right: i16) {
+        while self.samples.len() + 2 > self.capacity {
+            self.samples.pop_front();
+            self.samples.pop_front();
+        }
+        self.samples.push_back(left);
+        self.samples.push_back(right);
+    }
+
+    // samples waiting, counting left and right separately
+    pub fn len(&self) -> usize {
+        return self.samples.len();
+    }
+
+    pub fn drain(&mut self) -> Vec<i16> {
+        return self.samples.drain(..).collect();
+    }
+
+    // fills out with as many samples as fit, returns how many were written
+    pub fn drain_into(&mut self, out: &mut [i16]) -> usize {
+        let count: usize = out.len().min(self.samples.len()) & !1;
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *slot = sample;
+        }
+        return count;
+    }
+}
@@ -0,0 +1,66 @@
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// NRx2 on the square and noise channels, steps the volume up or down at 64Hz
+pub struct Envelope {
+    register: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn initialize() -> Envelope {
+        return Envelope {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        };
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    // the top 5 bits of NRx2 double as the channel's DAC power
+    pub fn dac_enabled(&self) -> bool {
+        return self.register & 0xF8 != 0;
+    }
+
+    fn period(&self) -> u8 {
+        return self.register & 0x07;
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+
+    pub fn clock(&mut self) {
+        if self.period() == 0 { return; }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 { return; }
+        self.timer = self.period();
+
+        let increase: bool = self.register & 0x08 != 0;
+        if increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        self.volume = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        if self.volume > 15 || self.timer > 8 { return Err(StateError::Invalid("envelope")); }
+        return Ok(());
+    }
+}
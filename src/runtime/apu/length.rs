@@ -0,0 +1,62 @@
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// counts a channel down to silence, clocked at 256Hz by the frame sequencer
+pub struct LengthCounter {
+    max: u16, // 64, or 256 for the wave channel
+    counter: u16,
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn initialize(max: u16) -> LengthCounter {
+        return LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        };
+    }
+
+    // NRx1, the length is written as the number of clocks already passed
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - (value as u16 & (self.max - 1));
+    }
+
+    // returns true when the count just ran out, which turns the channel off
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 { return false; }
+        self.counter -= 1;
+        return self.counter == 0;
+    }
+
+    // the length half of a NRx4 write, returns true if the channel should be turned off.
+    // when the frame sequencer's next step won't clock length, enabling it clocks it once straight away
+    pub fn write_control(&mut self, enable: bool, trigger: bool, next_step_clocks_length: bool) -> bool {
+        let was_enabled: bool = self.enabled;
+        self.enabled = enable;
+        let mut disable: bool = false;
+        if !was_enabled && enable && !next_step_clocks_length && self.counter != 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if enable && !next_step_clocks_length { self.counter -= 1; }
+        }
+        return disable;
+    }
+}
+
+// the max comes with the channel, only the count is state
+impl SaveState for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        if self.counter > self.max { return Err(StateError::Invalid("length counter")); }
+        return Ok(());
+    }
+}
@@ -0,0 +1,438 @@
+mod buffer;
+mod envelope;
+mod length;
+mod noise;
+mod recorder;
+mod resampler;
+mod square;
+mod vgm;
+mod wav;
+mod wave;
+
+use buffer::SampleBuffer;
+use noise::NoiseChannel;
+use recorder::AudioRecorder;
+use resampler::Resampler;
+use square::SquareChannel;
+use wave::WaveChannel;
+pub use vgm::{VgmLogger, VgmTags};
+use std::io;
+use std::path::Path;
+use super::CYCLES_PER_SECOND;
+use super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// output samples per second, per side, unless the embedder asks for something else
+pub const DEFAULT_SAMPLE_RATE: usize = 48_000;
+
+// the apu runs at 4 t-cycles to a machine cycle
+const T_CYCLES_PER_CYCLE: u32 = 4;
+
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR12: u16 = 0xFF12;
+pub const NR13: u16 = 0xFF13;
+pub const NR14: u16 = 0xFF14;
+pub const NR21: u16 = 0xFF16;
+pub const NR22: u16 = 0xFF17;
+pub const NR23: u16 = 0xFF18;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR32: u16 = 0xFF1C;
+pub const NR33: u16 = 0xFF1D;
+pub const NR34: u16 = 0xFF1E;
+pub const NR41: u16 = 0xFF20;
+pub const NR42: u16 = 0xFF21;
+pub const NR43: u16 = 0xFF22;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+// bits that always read back as 1, for NR10 through NR52
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+];
+
+// the sound hardware, four channels mixed down to stereo
+pub struct APU {
+    registers: [u8; 0x17], // NR10 through NR52 as last written
+    powered: bool,
+    frame_step: u8, // the next frame sequencer step, length on even steps, sweep on 2 and 6, envelope on 7
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    sample_rate: usize,
+    left: Resampler,
+    right: Resampler,
+    buffer: SampleBuffer, // a second of stereo audio
+    recorder: Option<AudioRecorder>,
+}
+
+impl APU {
+    pub fn initialize() -> APU {
+        let mut apu: APU = APU {
+            registers: [0; 0x17],
+            powered: false,
+            frame_step: 0,
+            square1: SquareChannel::initialize(true),
+            square2: SquareChannel::initialize(false),
+            wave: WaveChannel::initialize(),
+            noise: NoiseChannel::initialize(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            left: Resampler::initialize(CYCLES_PER_SECOND, DEFAULT_SAMPLE_RATE),
+            right: Resampler::initialize(CYCLES_PER_SECOND, DEFAULT_SAMPLE_RATE),
+            buffer: SampleBuffer::initialize(DEFAULT_SAMPLE_RATE * 2),
+            recorder: None,
+        };
+        // the boot rom leaves the apu on with everything routed to both sides at full volume
+        apu.write_register(NR52, 0x80);
+        apu.write_register(NR50, 0x77);
+        apu.write_register(NR51, 0xF3);
+        return apu;
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            if self.powered {
+                self.square1.tick(T_CYCLES_PER_CYCLE);
+                self.square2.tick(T_CYCLES_PER_CYCLE);
+                self.wave.tick(T_CYCLES_PER_CYCLE);
+                self.noise.tick(T_CYCLES_PER_CYCLE);
+            }
+            self.mix();
+        }
+    }
+
+    // clocked at 512Hz off DIV
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered { return; }
+        if self.frame_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // the four dacs panned by NR51 and scaled by NR50, fed to the resamplers as they change
+    fn mix(&mut self) {
+        let outputs: [(u8, bool); 4] = [
+            (self.square1.output(), self.square1.envelope.dac_enabled()),
+            (self.square2.output(), self.square2.envelope.dac_enabled()),
+            (self.wave.output(), self.wave.dac_enabled()),
+            (self.noise.output(), self.noise.envelope.dac_enabled()),
+        ];
+        let panning: u8 = self.registers[(NR51 - NR10) as usize];
+        let mut left: f32 = 0.0;
+        let mut right: f32 = 0.0;
+        let mut levels: [f32; 4] = [0.0; 4];
+        for (channel, (output, dac_enabled)) in outputs.iter().enumerate() {
+            if !dac_enabled { continue; }
+            // each dac turns 0-15 into a voltage from -1 to 1
+            let analog: f32 = *output as f32 / 7.5 - 1.0;
+            levels[channel] = analog / 2.0;
+            if panning & (0x10 << channel) != 0 { left += analog; }
+            if panning & (0x01 << channel) != 0 { right += analog; }
+        }
+        if let Some(recorder) = &mut self.recorder { recorder.record_channels(&levels); }
+        let volume: u8 = self.registers[(NR50 - NR10) as usize];
+        self.left.set_level(left * (((volume >> 4) & 0x07) + 1) as f32 / 32.0);
+        self.right.set_level(right * ((volume & 0x07) + 1) as f32 / 32.0);
+
+        self.left.advance(1);
+        self.right.advance(1);
+        // both sides advance in step so they always have a sample ready together
+        while let (Some(left), Some(right)) = (self.left.next_sample(), self.right.next_sample()) {
+            let (left, right): (i16, i16) = (to_sample(left), to_sample(right));
+            self.buffer.push(left, right);
+            if let Some(recorder) = &mut self.recorder { recorder.record_mix(left, right); }
+        }
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        return self.sample_rate;
+    }
+
+    // starts over at a new output rate, anything not drained yet is dropped and a recording is finished
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        assert!(sample_rate > 0, "sample rate must be above 0");
+        let _ = self.stop_recording();
+        self.sample_rate = sample_rate;
+        self.left = Resampler::initialize(CYCLES_PER_SECOND, sample_rate);
+        self.right = Resampler::initialize(CYCLES_PER_SECOND, sample_rate);
+        self.buffer = SampleBuffer::initialize(sample_rate * 2);
+    }
+
+    // samples waiting to be drained, left and right interleaved
+    pub fn samples_available(&self) -> usize {
+        return self.buffer.len();
+    }
+
+    pub fn drain_samples(&mut self) -> Vec<i16> {
+        return self.buffer.drain();
+    }
+
+    pub fn drain_samples_into(&mut self, out: &mut [i16]) -> usize {
+        return self.buffer.drain_into(out);
+    }
+
+    // everything mixed from here on also goes to a .wav at the output rate, stems are each channel
+    // before panning and volume in their own mono file next to it
+    pub fn start_recording(&mut self, path: &Path, stems: bool) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(AudioRecorder::create(path, self.sample_rate, stems)?);
+        return Ok(());
+    }
+
+    pub fn is_recording(&self) -> bool {
+        return self.recorder.is_some();
+    }
+
+    // finishes the files, and reports any write that failed along the way
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => { return recorder.finish(); }
+            None => { return Ok(()); }
+        }
+    }
+
+    // register writes that bring a freshly powered apu to the current settings, for logs started
+    // mid game. channels aren't retriggered, whatever is playing picks up on its next note
+    pub fn state_writes(&self) -> Vec<(u16, u8)> {
+        let mut writes: Vec<(u16, u8)> = Vec::new();
+        writes.push((NR52, (self.powered as u8) << 7));
+        // wave ram can only be written with channel 3 off
+        writes.push((NR30, 0x00));
+        for (index, value) in self.wave.ram().iter().enumerate() {
+            writes.push((WAVE_RAM_START + index as u16, *value));
+        }
+        if !self.powered { return writes; }
+        for address in NR10..=NR51 {
+            let mut value: u8 = self.registers[(address - NR10) as usize];
+            if matches!(address, NR14 | NR24 | NR34 | NR44) { value &= 0x7F; }
+            writes.push((address, value));
+        }
+        return writes;
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on == self.powered { return; }
+        if !on {
+            // every register is cleared, apart from the length counters on the dmg
+            for address in NR10..NR52 {
+                if matches!(address, NR11 | NR21 | NR31 | NR41) { continue; }
+                self.write_register(address, 0);
+            }
+            self.registers = [0; 0x17];
+            self.square1.write_duty(0);
+            self.square2.write_duty(0);
+            self.powered = false;
+        } else {
+            self.powered = true;
+            self.frame_step = 0;
+            self.square1.reset_duty_position();
+            self.square2.reset_duty_position();
+            self.wave.reset_sample_buffer();
+        }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                return self.wave.read_ram((address - WAVE_RAM_START) as usize);
+            }
+            NR52 => {
+                let status: u8 = (self.square1.enabled as u8)
+                    | (self.square2.enabled as u8) << 1
+                    | (self.wave.enabled as u8) << 2
+                    | (self.noise.enabled as u8) << 3;
+                return (self.powered as u8) << 7 | READ_MASKS[(NR52 - NR10) as usize] | status;
+            }
+            NR10..=NR51 => {
+                let index: usize = (address - NR10) as usize;
+                return self.registers[index] | READ_MASKS[index];
+            }
+            _ => {
+                // 0xFF27-0xFF2F aren't connected
+                return 0xFF;
+            }
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                self.wave.write_ram((address - WAVE_RAM_START) as usize, value);
+                return;
+            }
+            NR52 => {
+                self.set_power(value & 0x80 != 0);
+                return;
+            }
+            NR10..=NR51 => {}
+            _ => { return; }
+        }
+
+        if !self.powered {
+            // with the power off only the dmg's length counters can be written
+            match address {
+                NR11 => { self.square1.length.load(value & 0x3F); }
+                NR21 => { self.square2.length.load(value & 0x3F); }
+                NR31 => { self.wave.length.load(value); }
+                NR41 => { self.noise.length.load(value & 0x3F); }
+                _ => {}
+            }
+            return;
+        }
+
+        self.registers[(address - NR10) as usize] = value;
+        let next_step_clocks_length: bool = self.frame_step.is_multiple_of(2);
+        match address {
+            NR10 => { self.square1.write_sweep(value); }
+            NR11 => { self.square1.write_length(value); }
+            NR12 => { self.square1.write_envelope(value); }
+            NR13 => { self.square1.write_frequency_low(value); }
+            NR14 => { self.square1.write_control(value, next_step_clocks_length); }
+            NR21 => { self.square2.write_length(value); }
+            NR22 => { self.square2.write_envelope(value); }
+            NR23 => { self.square2.write_frequency_low(value); }
+            NR24 => { self.square2.write_control(value, next_step_clocks_length); }
+            NR30 => { self.wave.write_dac(value); }
+            NR31 => { self.wave.write_length(value); }
+            NR32 => { self.wave.write_volume(value); }
+            NR33 => { self.wave.write_frequency_low(value); }
+            NR34 => { self.wave.write_control(value, next_step_clocks_length); }
+            NR41 => { self.noise.write_length(value); }
+            NR42 => { self.noise.write_envelope(value); }
+            NR43 => { self.noise.write_polynomial(value); }
+            NR44 => { self.noise.write_control(value, next_step_clocks_length); }
+            _ => {} // NR50, NR51 and the unused slots are only stored
+        }
+    }
+}
+
+fn to_sample(value: f32) -> i16 {
+    return (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+}
+
+// the sound the machine makes is state, where it goes (sample rate, the drain buffer, recordings)
+// belongs to the host and carries on across a load
+impl SaveState for APU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bool(self.powered);
+        state.write_u8(self.frame_step);
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.registers)?;
+        self.powered = state.read_bool()?;
+        self.frame_step = state.read_u8()? % 8;
+        self.square1.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // which channels NR52 shows as playing
+    fn playing(apu: &APU) -> u8 {
+        return apu.read_register(NR52) & 0x0F;
+    }
+
+    #[test]
+    fn a_sweep_past_2047_turns_channel_1_off() {
+        let mut apu: APU = APU::initialize();
+        // every frame sequencer sweep clock, adding a quarter each time
+        apu.write_register(NR10, 0x12);
+        apu.write_register(NR12, 0xF0);
+        apu.write_register(NR13, 0x00);
+        apu.write_register(NR14, 0x85);
+        assert_eq!(playing(&apu), 0x01);
+        // 1280 goes to 1600 on step 2, with 2000 checked after
+        for _ in 0..3 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(playing(&apu), 0x01);
+        // 2000 to 2500 on step 6
+        for _ in 0..4 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(playing(&apu), 0x00);
+    }
+
+    #[test]
+    fn a_sweep_already_overflowing_on_trigger_never_plays() {
+        let mut apu: APU = APU::initialize();
+        apu.write_register(NR10, 0x11);
+        apu.write_register(NR12, 0xF0);
+        apu.write_register(NR13, 0xFF);
+        apu.write_register(NR14, 0x87);
+        assert_eq!(playing(&apu), 0x00);
+    }
+
+    #[test]
+    fn the_length_counter_runs_out_on_even_steps() {
+        let mut apu: APU = APU::initialize();
+        // 2 clocks of length left
+        apu.write_register(NR21, 0x3E);
+        apu.write_register(NR22, 0xF0);
+        apu.write_register(NR24, 0xC0);
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(playing(&apu), 0x02);
+        apu.clock_frame_sequencer();
+        assert_eq!(playing(&apu), 0x00);
+    }
+
+    #[test]
+    fn power_off_clears_the_registers_but_not_the_lengths() {
+        let mut apu: APU = APU::initialize();
+        apu.write_register(NR21, 0xBE);
+        apu.write_register(NR10, 0x7F);
+        apu.write_register(NR52, 0x00);
+        assert_eq!(apu.read_register(NR52), 0x70);
+        assert_eq!(apu.read_register(NR50), 0x00);
+        assert_eq!(apu.read_register(NR10), 0x80);
+        assert_eq!(apu.read_register(NR21), 0x3F);
+        // only the lengths take writes while it's off
+        apu.write_register(NR50, 0x77);
+        assert_eq!(apu.read_register(NR50), 0x00);
+
+        apu.write_register(NR52, 0x80);
+        apu.write_register(NR22, 0xF0);
+        apu.write_register(NR24, 0xC0);
+        // the 2 clocks loaded before the power went off are still there
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(playing(&apu), 0x02);
+        apu.clock_frame_sequencer();
+        assert_eq!(playing(&apu), 0x00);
+    }
+}
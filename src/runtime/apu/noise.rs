@@ -0,0 +1,125 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// channel 4, white noise out of a 15 bit linear feedback shift register
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    clock_shift: u8,
+    short_mode: bool, // 7 bit lfsr, gives a metallic tone
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl NoiseChannel {
+    pub fn initialize() -> NoiseChannel {
+        return NoiseChannel {
+            enabled: false,
+            length: LengthCounter::initialize(64),
+            envelope: Envelope::initialize(),
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        };
+    }
+
+    fn period(&self) -> u32 {
+        return DIVISORS[self.divisor_code as usize] << self.clock_shift;
+    }
+
+    pub fn tick(&mut self, t_cycles: u32) {
+        let mut remaining: u32 = t_cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+            // shifts 14 and 15 stop the lfsr
+            if self.clock_shift < 14 { self.step_lfsr(); }
+        }
+        self.timer -= remaining;
+    }
+
+    fn step_lfsr(&mut self) {
+        let feedback: u16 = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.short_mode {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 == 1 { return 0; }
+        return self.envelope.volume;
+    }
+
+    // NR41
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value & 0x3F);
+    }
+
+    // NR42
+    pub fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        if !self.envelope.dac_enabled() { self.enabled = false; }
+    }
+
+    // NR43
+    pub fn write_polynomial(&mut self, value: u8) {
+        self.clock_shift = value >> 4;
+        self.short_mode = value & 0x08 != 0;
+        self.divisor_code = value & 0x07;
+    }
+
+    // NR44
+    pub fn write_control(&mut self, value: u8, next_step_clocks_length: bool) {
+        let trigger: bool = value & 0x80 != 0;
+        if self.length.write_control(value & 0x40 != 0, trigger, next_step_clocks_length) {
+            self.enabled = false;
+        }
+        if trigger {
+            self.enabled = self.envelope.dac_enabled();
+            self.timer = self.period();
+            self.envelope.trigger();
+            self.lfsr = 0x7FFF;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() { self.enabled = false; }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u8(self.clock_shift);
+        state.write_bool(self.short_mode);
+        state.write_u8(self.divisor_code);
+        state.write_u16(self.lfsr);
+        state.write_u32(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.clock_shift = state.read_u8()? & 0x0F;
+        self.short_mode = state.read_bool()?;
+        self.divisor_code = state.read_u8()? & 0x07;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        self.timer = state.read_u32()?;
+        return Ok(());
+    }
+}
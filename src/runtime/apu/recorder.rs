@@ -0,0 +1,73 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use super::resampler::Resampler;
+use super::wav::WavWriter;
+use super::super::CYCLES_PER_SECOND;
+
+// one mono stem for each channel, each through its own resampler
+struct Stem {
+    resampler: Resampler,
+    writer: WavWriter,
+}
+
+// writes the apu's output to a .wav, and optionally every channel on its own next to it
+pub struct AudioRecorder {
+    mix: WavWriter,
+    stems: Vec<Stem>,
+    error: Option<io::Error>, // the first write that failed, recording stops there
+}
+
+impl AudioRecorder {
+    // stems go next to the mix as <name>.ch1.wav to <name>.ch4.wav
+    pub fn create(path: &Path, sample_rate: usize, stems: bool) -> io::Result<AudioRecorder> {
+        let mut recorder: AudioRecorder = AudioRecorder {
+            mix: WavWriter::create(path, 2, sample_rate as u32)?,
+            stems: Vec::new(),
+            error: None,
+        };
+        if stems {
+            for channel in 1..=4 {
+                recorder.stems.push(Stem {
+                    resampler: Resampler::initialize(CYCLES_PER_SECOND, sample_rate),
+                    writer: WavWriter::create(&stem_path(path, channel), 1, sample_rate as u32)?,
+                });
+            }
+        }
+        return Ok(recorder);
+    }
+
+    pub fn record_mix(&mut self, left: i16, right: i16) {
+        if self.error.is_some() { return; }
+        let result: io::Result<()> = self.mix.write_sample(left).and_then(|_| self.mix.write_sample(right));
+        if let Err(error) = result { self.error = Some(error); }
+    }
+
+    // takes a machine cycle of each channel's dac output
+    pub fn record_channels(&mut self, levels: &[f32; 4]) {
+        if self.stems.is_empty() || self.error.is_some() { return; }
+        for (stem, level) in self.stems.iter_mut().zip(levels.iter()) {
+            stem.resampler.set_level(*level);
+            stem.resampler.advance(1);
+            while let Some(sample) = stem.resampler.next_sample() {
+                if let Err(error) = stem.writer.write_sample(super::to_sample(sample)) {
+                    self.error = Some(error);
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() { return Err(error); }
+        self.mix.finish()?;
+        for stem in self.stems.iter_mut() {
+            stem.writer.finish()?;
+        }
+        return Ok(());
+    }
+}
+
+fn stem_path(path: &Path, channel: usize) -> PathBuf {
+    let name: String = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    return path.with_file_name(format!("{}.ch{}.wav", name, channel));
+}
@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// the step kernel is spread over this many output samples, at this many sub-sample offsets
+const TAPS: usize = 16;
+const PHASES: usize = 32;
+// passband edge as a fraction of the output rate, a bit under nyquist leaves room for the window's rolloff
+const CUTOFF: f64 = 0.45;
+// the dmg's output capacitor leaks this much charge every t-cycle
+const CAPACITOR_CHARGE_PER_T_CYCLE: f64 = 0.999958;
+const T_CYCLES_PER_SECOND: f64 = 4_194_304.0;
+
+// band limited synthesis of one output signal. instead of sampling the apu's output the level
+// changes are added as band limited steps, so there's no aliasing and the cost only depends on
+// how often the level changes
+pub struct Resampler {
+    ratio: f64, // output samples per machine cycle
+    time: f64, // the current position in output samples, counted from the front of pending
+    pending: VecDeque<f32>, // steps added but not yet summed into samples
+    level: f32, // the signal level at the current time, what the steps add up to
+    integrator: f32,
+    capacitor: f32,
+    charge_factor: f32,
+    kernel: [[f32; TAPS]; PHASES],
+}
+
+impl Resampler {
+    pub fn initialize(cycles_per_second: usize, sample_rate: usize) -> Resampler {
+        return Resampler {
+            ratio: sample_rate as f64 / cycles_per_second as f64,
+            time: 0.0,
+            pending: VecDeque::from(vec![0.0; TAPS + 2]),
+            level: 0.0,
+            integrator: 0.0,
+            capacitor: 0.0,
+            charge_factor: CAPACITOR_CHARGE_PER_T_CYCLE.powf(T_CYCLES_PER_SECOND / sample_rate as f64) as f32,
+            kernel: build_kernel(),
+        };
+    }
+
+    // sets the signal level from now on
+    pub fn set_level(&mut self, level: f32) {
+        let delta: f32 = level - self.level;
+        if delta == 0.0 { return; }
+        self.level = level;
+
+        let index: usize = self.time as usize;
+        let phase: usize = ((self.time - index as f64) * PHASES as f64) as usize;
+        while self.pending.len() < index + TAPS + 1 {
+            self.pending.push_back(0.0);
+        }
+        for (tap, weight) in self.kernel[phase].iter().enumerate() {
+            self.pending[index + tap] += delta * weight;
+        }
+    }
+
+    pub fn advance(&mut self, cycles: usize) {
+        self.time += self.ratio * cycles as f64;
+    }
+
+    // the next finished sample, if a step still to come can't change it anymore
+    pub fn next_sample(&mut self) -> Option<f32> {
+        if self.time < 1.0 { return None; }
+        self.time -= 1.0;
+        let step: f32 = self.pending.pop_front().unwrap_or(0.0);
+        if self.pending.len() < TAPS + 1 { self.pending.push_back(0.0); }
+        self.integrator += step;
+
+        // high pass like the capacitor on the real output, which takes the dc offset back out
+        let output: f32 = self.integrator - self.capacitor;
+        self.capacitor = self.integrator - output * self.charge_factor;
+        return Some(output);
+    }
+}
+
+// a windowed sinc impulse for every sub-sample phase, each summing to 1 so a step of 1 lands as 1
+fn build_kernel() -> [[f32; TAPS]; PHASES] {
+    let mut kernel: [[f32; TAPS]; PHASES] = [[0.0; TAPS]; PHASES];
+    let center: f64 = TAPS as f64 / 2.0;
+    for (phase, weights) in kernel.iter_mut().enumerate() {
+        let offset: f64 = phase as f64 / PHASES as f64;
+        let mut sum: f64 = 0.0;
+        let mut values: [f64; TAPS] = [0.0; TAPS];
+        for (tap, value) in values.iter_mut().enumerate() {
+            let x: f64 = tap as f64 - center - offset;
+            let sinc: f64 = if x == 0.0 { 1.0 } else { (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x) };
+            // blackman window over the kernel's span
+            let position: f64 = (x + center + 1.0) / (TAPS + 1) as f64;
+            let window: f64 = 0.42 - 0.5 * (2.0 * PI * position).cos() + 0.08 * (4.0 * PI * position).cos();
+            *value = sinc * window;
+            sum += *value;
+        }
+        for (weight, value) in weights.iter_mut().zip(values.iter()) {
+            *weight = (value / sum) as f32;
+        }
+    }
+    return kernel;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::CYCLES_PER_SECOND;
+
+    // runs the given machine cycles a cycle at a time, collecting every sample that's finished
+    fn run(resampler: &mut Resampler, cycles: usize) -> Vec<f32> {
+        let mut samples: Vec<f32> = Vec::new();
+        for _ in 0..cycles {
+            resampler.advance(1);
+            while let Some(sample) = resampler.next_sample() {
+                samples.push(sample);
+            }
+        }
+        return samples;
+    }
+
+    #[test]
+    fn every_phase_of_the_kernel_sums_to_1() {
+        for weights in build_kernel() {
+            assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn a_second_of_cycles_is_a_second_of_samples() {
+        let mut resampler: Resampler = Resampler::initialize(CYCLES_PER_SECOND, 48_000);
+        assert_eq!(run(&mut resampler, CYCLES_PER_SECOND).len(), 48_000);
+        let mut resampler: Resampler = Resampler::initialize(CYCLES_PER_SECOND, 44_100);
+        assert_eq!(run(&mut resampler, CYCLES_PER_SECOND).len(), 44_100);
+    }
+
+    #[test]
+    fn a_dc_step_settles_back_to_0() {
+        let mut resampler: Resampler = Resampler::initialize(CYCLES_PER_SECOND, 48_000);
+        resampler.set_level(1.0);
+        let samples: Vec<f32> = run(&mut resampler, CYCLES_PER_SECOND / 4);
+        let peak: f32 = samples.iter().cloned().fold(0.0, f32::max);
+        assert!(peak > 0.95 && peak < 1.1);
+        // the capacitor has taken the offset back out
+        assert!(samples.last().unwrap().abs() < 0.001);
+        // and the level never swings the other way
+        assert!(samples.iter().all(|sample| *sample > -0.1));
+    }
+}
@@ -0,0 +1,230 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// the waveforms for 12.5%, 25%, 50% and 75% duty, played from the top bit down
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// NR10 on channel 1, bends the frequency up or down every few 128Hz clocks
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16, // the frequency the sweep works from, copied on trigger
+    negate_used: bool, // a subtraction happened since the last trigger
+}
+
+// channels 1 and 2
+pub struct SquareChannel {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    sweep: Option<Sweep>,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32, // t-cycles until the next duty step
+}
+
+impl SquareChannel {
+    pub fn initialize(with_sweep: bool) -> SquareChannel {
+        let sweep: Option<Sweep> = if with_sweep {
+            Some(Sweep {
+                period: 0,
+                negate: false,
+                shift: 0,
+                timer: 0,
+                enabled: false,
+                shadow: 0,
+                negate_used: false,
+            })
+        } else {
+            None
+        };
+        return SquareChannel {
+            enabled: false,
+            length: LengthCounter::initialize(64),
+            envelope: Envelope::initialize(),
+            sweep,
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+        };
+    }
+
+    fn period(&self) -> u32 {
+        return (2048 - self.frequency as u32) * 4;
+    }
+
+    pub fn tick(&mut self, t_cycles: u32) {
+        let mut remaining: u32 = t_cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= remaining;
+    }
+
+    // the digital output, 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled { return 0; }
+        let high: bool = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_position)) & 1 == 1;
+        return if high { self.envelope.volume } else { 0 };
+    }
+
+    pub fn reset_duty_position(&mut self) {
+        self.duty_position = 0;
+    }
+
+    // NR10
+    pub fn write_sweep(&mut self, value: u8) {
+        let Some(sweep) = self.sweep.as_mut() else { return; };
+        sweep.period = (value >> 4) & 0x07;
+        sweep.negate = value & 0x08 != 0;
+        sweep.shift = value & 0x07;
+        // leaving negate mode after it was used to calculate kills the channel
+        if !sweep.negate && sweep.negate_used { self.enabled = false; }
+    }
+
+    // NRx1
+    pub fn write_length(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length.load(value & 0x3F);
+    }
+
+    // clears the duty without touching the length counter, for powering off
+    pub fn write_duty(&mut self, duty: u8) {
+        self.duty = duty;
+    }
+
+    // NRx2
+    pub fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        if !self.envelope.dac_enabled() { self.enabled = false; }
+    }
+
+    // NRx3
+    pub fn write_frequency_low(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0x700) | value as u16;
+    }
+
+    // NRx4
+    pub fn write_control(&mut self, value: u8, next_step_clocks_length: bool) {
+        self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+        let trigger: bool = value & 0x80 != 0;
+        if self.length.write_control(value & 0x40 != 0, trigger, next_step_clocks_length) {
+            self.enabled = false;
+        }
+        if trigger { self.trigger(); }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        let frequency: u16 = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = frequency;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            sweep.negate_used = false;
+            if sweep.shift != 0 {
+                // only checked for overflow, nothing is written back yet
+                self.calculate_sweep();
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() { self.enabled = false; }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else { return; };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 { return; }
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 { return; }
+
+        let frequency: u16 = self.calculate_sweep();
+        let Some(sweep) = self.sweep.as_mut() else { return; };
+        if frequency <= 2047 && sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // the new frequency is checked again straight away, without being used
+            self.calculate_sweep();
+        }
+    }
+
+    // the next frequency from the shadow register, anything past 2047 turns the channel off
+    fn calculate_sweep(&mut self) -> u16 {
+        let Some(sweep) = self.sweep.as_mut() else { return self.frequency; };
+        let delta: u16 = sweep.shadow >> sweep.shift;
+        let frequency: u16 = if sweep.negate {
+            sweep.negate_used = true;
+            sweep.shadow - delta
+        } else {
+            sweep.shadow + delta
+        };
+        if frequency > 2047 { self.enabled = false; }
+        return frequency;
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.period);
+        state.write_bool(self.negate);
+        state.write_u8(self.shift);
+        state.write_u8(self.timer);
+        state.write_bool(self.enabled);
+        state.write_u16(self.shadow);
+        state.write_bool(self.negate_used);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.period = state.read_u8()? & 0x07;
+        self.negate = state.read_bool()?;
+        self.shift = state.read_u8()? & 0x07;
+        self.timer = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.shadow = state.read_u16()? & 0x07FF;
+        self.negate_used = state.read_bool()?;
+        return Ok(());
+    }
+}
+
+// only channel 1 has the sweep fields, which channel is loading decides the layout
+impl SaveState for SquareChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        if let Some(sweep) = &self.sweep { sweep.save_state(state); }
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_position);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        if let Some(sweep) = &mut self.sweep { sweep.load_state(state)?; }
+        self.duty = state.read_u8()? & 0x03;
+        self.duty_position = state.read_u8()? & 0x07;
+        self.frequency = state.read_u16()? & 0x07FF;
+        self.timer = state.read_u32()?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use super::NR10;
+use super::super::CYCLES_PER_SECOND;
+
+// vgm timestamps are always in samples at 44.1kHz
+const VGM_SAMPLE_RATE: usize = 44_100;
+// the dmg chip's clock field, the t-cycle rate
+const DMG_CLOCK: u32 = 4_194_304;
+// 1.61 is the first version with the dmg, the header is padded to 0x100 bytes
+const VERSION: u32 = 0x161;
+const HEADER_SIZE: usize = 0x100;
+
+const COMMAND_DMG_WRITE: u8 = 0xB3;
+const COMMAND_WAIT: u8 = 0x61;
+const COMMAND_WAIT_60HZ: u8 = 0x62;
+const COMMAND_WAIT_50HZ: u8 = 0x63;
+const COMMAND_SHORT_WAIT: u8 = 0x70; // waits 1 to 16 samples, the low nibble is the count minus one
+const COMMAND_END: u8 = 0x66;
+
+// the gd3 tag's strings, in the order they're stored. the japanese fields are left empty
+pub struct VgmTags {
+    pub track: String,
+    pub game: String,
+    pub system: String,
+    pub author: String,
+    pub date: String,
+    pub ripper: String,
+    pub notes: String,
+}
+
+// logs sound register writes as vgm commands. it's kept in memory and written out on finish,
+// the header needs the totals
+pub struct VgmLogger {
+    path: PathBuf,
+    tags: VgmTags,
+    commands: Vec<u8>,
+    cycles: usize, // machine cycles since logging started
+    samples: usize, // samples waited so far, kept separately so rounding doesn't drift
+    finished: bool,
+}
+
+impl VgmLogger {
+    // the file is created right away so a bad path shows up now rather than at the end
+    pub fn create(path: &Path, tags: VgmTags) -> io::Result<VgmLogger> {
+        fs::write(path, [])?;
+        return Ok(VgmLogger {
+            path: path.to_path_buf(),
+            tags,
+            commands: Vec::new(),
+            cycles: 0,
+            samples: 0,
+            finished: false,
+        });
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+    }
+
+    // takes any address from 0xFF10 to 0xFF3F
+    pub fn log_write(&mut self, address: u16, value: u8) {
+        self.catch_up();
+        self.commands.push(COMMAND_DMG_WRITE);
+        self.commands.push((address - NR10) as u8);
+        self.commands.push(value);
+    }
+
+    // emits waits up to the current cycle
+    fn catch_up(&mut self) {
+        let target: usize = (self.cycles as u128 * VGM_SAMPLE_RATE as u128 / CYCLES_PER_SECOND as u128) as usize;
+        let mut remaining: usize = target - self.samples;
+        self.samples = target;
+        while remaining > 0 {
+            match remaining {
+                735 => {
+                    self.commands.push(COMMAND_WAIT_60HZ);
+                    remaining = 0;
+                }
+                882 => {
+                    self.commands.push(COMMAND_WAIT_50HZ);
+                    remaining = 0;
+                }
+                1..=16 => {
+                    self.commands.push(COMMAND_SHORT_WAIT | (remaining - 1) as u8);
+                    remaining = 0;
+                }
+                _ => {
+                    let wait: usize = remaining.min(0xFFFF);
+                    self.commands.push(COMMAND_WAIT);
+                    self.commands.extend_from_slice(&(wait as u16).to_le_bytes());
+                    remaining -= wait;
+                }
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished { return Ok(()); }
+        self.finished = true;
+        self.catch_up();
+        self.commands.push(COMMAND_END);
+
+        let gd3: Vec<u8> = self.gd3();
+        let mut file: Vec<u8> = vec![0; HEADER_SIZE];
+        file.extend_from_slice(&self.commands);
+        let gd3_offset: usize = file.len();
+        file.extend_from_slice(&gd3);
+
+        let length: usize = file.len();
+        let mut header = |offset: usize, value: u32| {
+            file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        // offsets in the header are relative to the field they're stored in
+        header(0x04, (length - 0x04) as u32);
+        header(0x08, VERSION);
+        header(0x14, (gd3_offset - 0x14) as u32);
+        header(0x18, self.samples as u32);
+        header(0x34, (HEADER_SIZE - 0x34) as u32);
+        header(0x80, DMG_CLOCK);
+        file[0..4].copy_from_slice(b"Vgm ");
+        return fs::write(&self.path, file);
+    }
+
+    fn gd3(&self) -> Vec<u8> {
+        let tags: &VgmTags = &self.tags;
+        let strings: [&str; 11] = [
+            &tags.track, "", &tags.game, "", &tags.system, "", &tags.author, "", &tags.date, &tags.ripper, &tags.notes,
+        ];
+        let mut text: Vec<u8> = Vec::new();
+        for string in strings {
+            for unit in string.encode_utf16().chain(std::iter::once(0)) {
+                text.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        let mut gd3: Vec<u8> = b"Gd3 ".to_vec();
+        gd3.extend_from_slice(&0x100u32.to_le_bytes());
+        gd3.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        gd3.extend_from_slice(&text);
+        return gd3;
+    }
+}
+
+impl Drop for VgmLogger {
+    fn drop(&mut self) {
+        // the log only exists in memory until it's finished, so it's written out here rather than lost.
+        // any error writing it has nowhere to go, finish() should be called to see them
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        return u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    }
+
+    fn tags() -> VgmTags {
+        return VgmTags {
+            track: "Title".to_string(),
+            game: "ポケモン".to_string(),
+            system: "Game Boy".to_string(),
+            author: String::new(),
+            date: String::new(),
+            ripper: String::new(),
+            notes: String::new(),
+        };
+    }
+
+    #[test]
+    fn the_log_has_the_header_commands_and_tags() {
+        let directory: PathBuf = std::env::temp_dir().join(format!("dmg-e-vgm-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path: PathBuf = directory.join("out.vgm");
+        let mut logger: VgmLogger = VgmLogger::create(&path, tags()).unwrap();
+        logger.log_write(0xFF12, 0xF0);
+        // 10 samples
+        logger.tick(238);
+        logger.log_write(0xFF13, 0x42);
+        // 88200 samples, more than one wait holds
+        logger.tick(CYCLES_PER_SECOND * 2);
+        logger.log_write(0xFF26, 0x80);
+        // 735 samples, a 60th of a second
+        logger.tick(17_477);
+        logger.finish().unwrap();
+        let file: Vec<u8> = fs::read(&path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(&file[0..4], b"Vgm ");
+        assert_eq!(u32_at(&file, 0x04) as usize, file.len() - 0x04);
+        assert_eq!(u32_at(&file, 0x08), 0x161);
+        assert_eq!(u32_at(&file, 0x18), 10 + 88_200 + 735);
+        assert_eq!(u32_at(&file, 0x34) as usize + 0x34, HEADER_SIZE);
+        assert_eq!(u32_at(&file, 0x80), 4_194_304);
+
+        let commands: [u8; 24] = [
+            0xB3, 0x02, 0xF0,
+            0x79,
+            0xB3, 0x03, 0x42,
+            0x61, 0xFF, 0xFF, 0x61, 0x89, 0x58,
+            0xB3, 0x16, 0x80,
+            0x62,
+            0x66,
+            // the gd3 tag follows straight on
+            b'G', b'd', b'3', b' ', 0x00, 0x01,
+        ];
+        assert_eq!(file[HEADER_SIZE..HEADER_SIZE + commands.len()], commands);
+
+        let gd3_offset: usize = u32_at(&file, 0x14) as usize + 0x14;
+        assert_eq!(gd3_offset, HEADER_SIZE + 18);
+        assert_eq!(u32_at(&file, gd3_offset + 8) as usize, file.len() - gd3_offset - 12);
+        let text: Vec<u16> = file[gd3_offset + 12..].chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+        let strings: Vec<String> = text.split(|unit| *unit == 0).map(String::from_utf16_lossy).collect();
+        // 11 strings and the empty tail after the last terminator
+        assert_eq!(strings.len(), 12);
+        assert_eq!(strings[0], "Title");
+        assert_eq!(strings[2], "ポケモン");
+        assert_eq!(strings[4], "Game Boy");
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const HEADER_SIZE: u32 = 44;
+// the header is patched with the current length this often, so a killed run still leaves a playable file
+const HEADER_UPDATE_INTERVAL: u32 = 1 << 16;
+// the RIFF size is a u32 that counts the header after its first 8 bytes, kept to whole stereo frames
+const MAX_DATA_BYTES: u32 = (u32::MAX - (HEADER_SIZE - 8)) & !3;
+
+// 16 bit pcm .wav output. past the 4GiB a wav can hold the file is finished and the recording
+// carries on in <name>.2.wav, <name>.3.wav...
+pub struct WavWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    channels: u16,
+    sample_rate: u32,
+    part: usize,
+    data_bytes: u32,
+    data_limit: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<WavWriter> {
+        return WavWriter::create_with_limit(path, channels, sample_rate, MAX_DATA_BYTES);
+    }
+
+    fn create_with_limit(path: &Path, channels: u16, sample_rate: u32, data_limit: u32) -> io::Result<WavWriter> {
+        let mut writer: WavWriter = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            path: path.to_path_buf(),
+            channels,
+            sample_rate,
+            part: 1,
+            data_bytes: 0,
+            data_limit,
+            finished: false,
+        };
+        writer.write_header()?;
+        return Ok(writer);
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align: u16 = self.channels * 2;
+        let header: &mut BufWriter<File> = &mut self.file;
+        header.write_all(b"RIFF")?;
+        header.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        header.write_all(b"WAVE")?;
+        header.write_all(b"fmt ")?;
+        header.write_all(&16u32.to_le_bytes())?;
+        header.write_all(&1u16.to_le_bytes())?; // pcm
+        header.write_all(&self.channels.to_le_bytes())?;
+        header.write_all(&self.sample_rate.to_le_bytes())?;
+        header.write_all(&(self.sample_rate * block_align as u32).to_le_bytes())?;
+        header.write_all(&block_align.to_le_bytes())?;
+        header.write_all(&16u16.to_le_bytes())?;
+        header.write_all(b"data")?;
+        header.write_all(&0u32.to_le_bytes())?;
+        return Ok(());
+    }
+
+    pub fn write_sample(&mut self, sample: i16) -> io::Result<()> {
+        let data_bytes: u32 = match self.data_bytes.checked_add(2) {
+            Some(data_bytes) if data_bytes <= self.data_limit => data_bytes,
+            _ => {
+                self.next_part()?;
+                2
+            }
+        };
+        self.file.write_all(&sample.to_le_bytes())?;
+        self.data_bytes = data_bytes;
+        if self.data_bytes.is_multiple_of(HEADER_UPDATE_INTERVAL) { self.update_header()?; }
+        return Ok(());
+    }
+
+    // finishes the full file and starts the next one
+    fn next_part(&mut self) -> io::Result<()> {
+        self.update_header()?;
+        self.file.flush()?;
+        self.part += 1;
+        let name: String = self.path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let path: PathBuf = self.path.with_file_name(format!("{}.{}.wav", name, self.part));
+        self.file = BufWriter::new(File::create(path)?);
+        self.data_bytes = 0;
+        return self.write_header();
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let file: &mut File = self.file.get_mut();
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(HEADER_SIZE - 8 + self.data_bytes).to_le_bytes())?;
+        file.seek(SeekFrom::Start(40))?;
+        file.write_all(&self.data_bytes.to_le_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        return Ok(());
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished { return Ok(()); }
+        self.finished = true;
+        self.update_header()?;
+        return self.file.flush();
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        // nowhere to report an error from here, finish() should be called to see them
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sizes(path: &Path) -> (u32, u32, usize) {
+        let bytes: Vec<u8> = fs::read(path).unwrap();
+        let riff: u32 = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let data: u32 = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        return (riff, data, bytes.len());
+    }
+
+    #[test]
+    fn the_limit_fits_the_riff_size() {
+        assert!((HEADER_SIZE - 8).checked_add(MAX_DATA_BYTES).is_some());
+        assert_eq!(MAX_DATA_BYTES % 4, 0);
+    }
+
+    #[test]
+    fn full_files_carry_on_in_the_next_part() {
+        let directory: PathBuf = std::env::temp_dir().join(format!("dmg-e-wav-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path: PathBuf = directory.join("out.wav");
+        // 2 stereo frames a file
+        let mut writer: WavWriter = WavWriter::create_with_limit(&path, 2, 48_000, 8).unwrap();
+        for sample in 0..10 {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(sizes(&path), (36 + 8, 8, 44 + 8));
+        assert_eq!(sizes(&directory.join("out.2.wav")), (36 + 8, 8, 44 + 8));
+        assert_eq!(sizes(&directory.join("out.3.wav")), (36 + 4, 4, 44 + 4));
+        // the third part starts on the ninth sample
+        assert_eq!(fs::read(directory.join("out.3.wav")).unwrap()[44..], [8, 0, 9, 0]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
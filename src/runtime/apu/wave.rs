@@ -0,0 +1,171 @@
+use super::length::LengthCounter;
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// channel 3, plays 32 4-bit samples from wave ram
+pub struct WaveChannel {
+    pub enabled: bool,
+    dac_enabled: bool,
+    pub length: LengthCounter,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32, // t-cycles until the next sample is read
+    position: u8,
+    sample_buffer: u8, // the last byte read from wave ram
+    just_read: bool, // wave ram was read in the last cycle, the cpu can only get at it then
+    ram: [u8; 16],
+}
+
+impl WaveChannel {
+    pub fn initialize() -> WaveChannel {
+        return WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::initialize(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            just_read: false,
+            ram: [0; 16],
+        };
+    }
+
+    fn period(&self) -> u32 {
+        return (2048 - self.frequency as u32) * 2;
+    }
+
+    pub fn tick(&mut self, t_cycles: u32) {
+        self.just_read = false;
+        if !self.enabled { return; }
+        let mut remaining: u32 = t_cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            self.sample_buffer = self.ram[self.position as usize / 2];
+            self.just_read = true;
+        }
+        self.timer -= remaining;
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled { return 0; }
+        let sample: u8 = if self.position.is_multiple_of(2) { self.sample_buffer >> 4 } else { self.sample_buffer & 0x0F };
+        match self.volume_code {
+            0 => { return 0; }
+            1 => { return sample; }
+            2 => { return sample >> 1; }
+            _ => { return sample >> 2; }
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        return self.dac_enabled;
+    }
+
+    pub fn reset_sample_buffer(&mut self) {
+        self.sample_buffer = 0;
+    }
+
+    // while playing, the cpu sees the byte the channel is reading, and only in the cycle it reads it
+    pub fn read_ram(&self, index: usize) -> u8 {
+        if !self.enabled { return self.ram[index]; }
+        if self.just_read { return self.ram[self.position as usize / 2]; }
+        return 0xFF;
+    }
+
+    // wave ram as it is, without the access rules
+    pub fn ram(&self) -> [u8; 16] {
+        return self.ram;
+    }
+
+    pub fn write_ram(&mut self, index: usize, value: u8) {
+        if !self.enabled {
+            self.ram[index] = value;
+        } else if self.just_read {
+            self.ram[self.position as usize / 2] = value;
+        }
+    }
+
+    // NR30
+    pub fn write_dac(&mut self, value: u8) {
+        self.dac_enabled = value & 0x80 != 0;
+        if !self.dac_enabled { self.enabled = false; }
+    }
+
+    // NR31
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value);
+    }
+
+    // NR32
+    pub fn write_volume(&mut self, value: u8) {
+        self.volume_code = (value >> 5) & 0x03;
+    }
+
+    // NR33
+    pub fn write_frequency_low(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0x700) | value as u16;
+    }
+
+    // NR34
+    pub fn write_control(&mut self, value: u8, next_step_clocks_length: bool) {
+        self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+        let trigger: bool = value & 0x80 != 0;
+        if self.length.write_control(value & 0x40 != 0, trigger, next_step_clocks_length) {
+            self.enabled = false;
+        }
+        if trigger { self.trigger(); }
+    }
+
+    fn trigger(&mut self) {
+        if self.enabled && self.timer <= 2 {
+            // retriggering just as the channel reads wave ram corrupts its first bytes on the dmg
+            let index: usize = ((self.position as usize + 1) % 32) / 2;
+            if index < 4 {
+                self.ram[0] = self.ram[index];
+            } else {
+                let start: usize = index & !3;
+                self.ram.copy_within(start..start + 4, 0);
+            }
+        }
+        self.enabled = self.dac_enabled;
+        self.position = 0;
+        // there's a short delay before the first sample is read, the buffer keeps playing until then
+        self.timer = self.period() + 6;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() { self.enabled = false; }
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        self.length.save_state(state);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position);
+        state.write_u8(self.sample_buffer);
+        state.write_bool(self.just_read);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.volume_code = state.read_u8()? & 0x03;
+        self.frequency = state.read_u16()? & 0x07FF;
+        self.timer = state.read_u32()?;
+        self.position = state.read_u8()? % 32;
+        self.sample_buffer = state.read_u8()?;
+        self.just_read = state.read_bool()?;
+        state.read_into(&mut self.ram)?;
+        return Ok(());
+    }
+}
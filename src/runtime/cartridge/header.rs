@@ -0,0 +1,122 @@
+use std::io;
+
+pub const TITLE_START: usize = 0x0134;
+pub const TITLE_END: usize = 0x0143;
+pub const CGB_FLAG_ADDRESS: usize = 0x0143;
+pub const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+pub const ROM_SIZE_ADDRESS: usize = 0x0148;
+pub const RAM_SIZE_ADDRESS: usize = 0x0149;
+pub const HEADER_END: usize = 0x0150;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapperKind {
+    RomOnly,
+    MBC1,
+    MBC3,
+    MBC5,
+}
+
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub cartridge_type: u8,
+    pub mapper: MapperKind,
+    pub has_ram: bool,
+    pub has_battery: bool,
+    pub has_timer: bool,
+    pub has_rumble: bool,
+    pub rom_size: usize, // in bytes
+    pub ram_size: usize, // in bytes
+}
+
+impl CartridgeHeader {
+    // parses the header block at 0x0134-0x014F, roms too short to carry one are treated as plain 32KiB roms.
+    // cartridges this emulator doesn't support are InvalidData errors
+    pub fn parse(rom: &[u8]) -> io::Result<CartridgeHeader> {
+        if rom.len() < HEADER_END {
+            return Ok(CartridgeHeader {
+                title: String::new(),
+                cgb_flag: 0,
+                cartridge_type: 0,
+                mapper: MapperKind::RomOnly,
+                has_ram: false,
+                has_battery: false,
+                has_timer: false,
+                has_rumble: false,
+                rom_size: 2 * ROM_BANK_SIZE,
+                ram_size: 0,
+            });
+        }
+
+        let title: String = rom[TITLE_START..TITLE_END]
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| *byte as char)
+            .collect();
+        let cartridge_type: u8 = rom[CARTRIDGE_TYPE_ADDRESS];
+
+        // (mapper, ram, battery, timer, rumble)
+        let (mapper, has_ram, has_battery, has_timer, has_rumble) = match cartridge_type {
+            0x00 => (MapperKind::RomOnly, false, false, false, false),
+            0x08 => (MapperKind::RomOnly, true, false, false, false),
+            0x09 => (MapperKind::RomOnly, true, true, false, false),
+            0x01 => (MapperKind::MBC1, false, false, false, false),
+            0x02 => (MapperKind::MBC1, true, false, false, false),
+            0x03 => (MapperKind::MBC1, true, true, false, false),
+            0x0F => (MapperKind::MBC3, false, true, true, false),
+            0x10 => (MapperKind::MBC3, true, true, true, false),
+            0x11 => (MapperKind::MBC3, false, false, false, false),
+            0x12 => (MapperKind::MBC3, true, false, false, false),
+            0x13 => (MapperKind::MBC3, true, true, false, false),
+            0x19 => (MapperKind::MBC5, false, false, false, false),
+            0x1A => (MapperKind::MBC5, true, false, false, false),
+            0x1B => (MapperKind::MBC5, true, true, false, false),
+            0x1C => (MapperKind::MBC5, false, false, false, true),
+            0x1D => (MapperKind::MBC5, true, false, false, true),
+            0x1E => (MapperKind::MBC5, true, true, false, true),
+            _ => {
+                return Err(invalid_header(format!("unsupported cartridge type 0x{:02x}", cartridge_type)));
+            }
+        };
+
+        let rom_size: usize = match rom[ROM_SIZE_ADDRESS] {
+            code @ 0x00..=0x08 => (2 * ROM_BANK_SIZE) << code,
+            code => {
+                return Err(invalid_header(format!("unknown rom size code 0x{:02x}", code)));
+            }
+        };
+
+        let ram_size: usize = match rom[RAM_SIZE_ADDRESS] {
+            0x00 => 0,
+            0x01 => 0x800, // unofficial 2KiB ram found on some homebrew
+            0x02 => RAM_BANK_SIZE,
+            0x03 => 4 * RAM_BANK_SIZE,
+            0x04 => 16 * RAM_BANK_SIZE,
+            0x05 => 8 * RAM_BANK_SIZE,
+            code => {
+                return Err(invalid_header(format!("unknown ram size code 0x{:02x}", code)));
+            }
+        };
+
+        return Ok(CartridgeHeader {
+            title,
+            cgb_flag: rom[CGB_FLAG_ADDRESS],
+            cartridge_type,
+            mapper,
+            has_ram: has_ram && ram_size > 0,
+            has_battery,
+            has_timer,
+            has_rumble,
+            rom_size,
+            ram_size: if has_ram { ram_size } else { 0 },
+        });
+    }
+}
+
+fn invalid_header(message: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
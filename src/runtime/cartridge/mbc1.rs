@@ -0,0 +1,226 @@
+use super::{Mapper, rom_index};
+use super::super::cpu::memory::EXTERNAL_RAM_START;
+use super::header::{ROM_BANK_SIZE, RAM_BANK_SIZE};
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const NINTENDO_LOGO_OFFSET: usize = 0x0104;
+const NINTENDO_LOGO_LEN: usize = 48;
+const MULTICART_GAME_SIZE: usize = 0x10 * ROM_BANK_SIZE;
+
+// up to 2MiB rom and 32KiB ram
+pub struct MBC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8, // 5 bit register at 0x2000-0x3FFF, the low rom bank bits
+    bank2: u8, // 2 bit register at 0x4000-0x5FFF, the high rom bank bits or the ram bank
+    advanced_banking: bool, // mode select at 0x6000-0x7FFF
+    multicart: bool, // MBC1M wires bank2 one bit lower so each game sees 16 banks
+}
+
+impl MBC1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> MBC1 {
+        let multicart: bool = MBC1::detect_multicart(&rom);
+        return MBC1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            advanced_banking: false,
+            multicart,
+        };
+    }
+
+    // multicarts are 1MiB images with a second copy of the boot logo at the start of the second game
+    fn detect_multicart(rom: &[u8]) -> bool {
+        if rom.len() != 4 * MULTICART_GAME_SIZE { return false; }
+        let logo = &rom[NINTENDO_LOGO_OFFSET..NINTENDO_LOGO_OFFSET + NINTENDO_LOGO_LEN];
+        let second = MULTICART_GAME_SIZE + NINTENDO_LOGO_OFFSET;
+        return &rom[second..second + NINTENDO_LOGO_LEN] == logo;
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        return if self.multicart { 4 } else { 5 };
+    }
+
+    fn low_bank1(&self) -> u8 {
+        return if self.multicart { self.bank1 & 0x0F } else { self.bank1 };
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let bank: usize = if self.advanced_banking { self.bank2 as usize } else { 0 };
+        let index: usize = bank * RAM_BANK_SIZE + ((address - EXTERNAL_RAM_START) as usize);
+        return index % self.ram.len();
+    }
+}
+
+impl Mapper for MBC1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank: usize = match address {
+            0x0000..=0x3FFF => {
+                if self.advanced_banking { (self.bank2 << self.bank2_shift()) as usize } else { 0 }
+            }
+            _ => {
+                ((self.bank2 << self.bank2_shift()) | self.low_bank1()) as usize
+            }
+        };
+        return *self.rom.get(rom_index(&self.rom, bank, address)).unwrap_or(&0xFF);
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x3FFF => {
+                // the zero check looks at all five bits, so bank 0x20 becomes 0x21 and so on
+                self.bank1 = value & 0x1F;
+                if self.bank1 == 0 { self.bank1 = 1; }
+            }
+            0x4000..=0x5FFF => {
+                self.bank2 = value & 0x03;
+            }
+            _ => {
+                self.advanced_banking = value & 0x01 == 1;
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() { return 0xFF; }
+        return self.ram[self.ram_index(address)];
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_enabled || self.ram.is_empty() { return false; }
+        let index: usize = self.ram_index(address);
+        let changed: bool = self.ram[index] != value;
+        self.ram[index] = value;
+        return changed;
+    }
+
+    fn ram(&self) -> &[u8] {
+        return &self.ram;
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        return &mut self.ram;
+    }
+}
+
+impl SaveState for MBC1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.bank1);
+        state.write_u8(self.bank2);
+        state.write_bool(self.advanced_banking);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.ram)?;
+        self.ram_enabled = state.read_bool()?;
+        self.bank1 = state.read_u8()? & 0x1F;
+        self.bank2 = state.read_u8()? & 0x03;
+        self.advanced_banking = state.read_bool()?;
+        if self.bank1 == 0 { return Err(StateError::Invalid("rom bank")); }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every bank starts with its own number
+    fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom: Vec<u8> = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        return rom;
+    }
+
+    #[test]
+    fn bank_0_in_the_low_bits_maps_bank_1() {
+        let mut mbc1: MBC1 = MBC1::new(numbered_rom(128), 0);
+        mbc1.write_rom(0x2000, 0x00);
+        assert_eq!(mbc1.read_rom(0x4000), 0x01);
+        // the check is before the upper bits are added, so 0x20 is 0x21
+        mbc1.write_rom(0x4000, 0x01);
+        assert_eq!(mbc1.read_rom(0x4000), 0x21);
+        // and only looks at the 5 bits the register keeps
+        mbc1.write_rom(0x2000, 0xE0);
+        assert_eq!(mbc1.read_rom(0x4000), 0x21);
+        mbc1.write_rom(0x2000, 0x1F);
+        assert_eq!(mbc1.read_rom(0x4000), 0x3F);
+    }
+
+    #[test]
+    fn mode_1_puts_the_upper_bits_on_bank_0_too() {
+        let mut mbc1: MBC1 = MBC1::new(numbered_rom(128), 0);
+        mbc1.write_rom(0x4000, 0x02);
+        mbc1.write_rom(0x2000, 0x03);
+        assert_eq!(mbc1.read_rom(0x0000), 0x00);
+        assert_eq!(mbc1.read_rom(0x4000), 0x43);
+        mbc1.write_rom(0x6000, 0x01);
+        assert_eq!(mbc1.read_rom(0x0000), 0x40);
+        assert_eq!(mbc1.read_rom(0x4000), 0x43);
+    }
+
+    #[test]
+    fn banks_past_the_end_wrap() {
+        let mut mbc1: MBC1 = MBC1::new(numbered_rom(4), 0);
+        mbc1.write_rom(0x2000, 0x05);
+        assert_eq!(mbc1.read_rom(0x4000), 0x01);
+        mbc1.write_rom(0x4000, 0x01);
+        mbc1.write_rom(0x6000, 0x01);
+        assert_eq!(mbc1.read_rom(0x0000), 0x00);
+    }
+
+    #[test]
+    fn mode_1_banks_the_ram() {
+        let mut mbc1: MBC1 = MBC1::new(numbered_rom(4), 4 * RAM_BANK_SIZE);
+        mbc1.write_rom(0x0000, 0x0A);
+        mbc1.write_rom(0x4000, 0x02);
+        mbc1.write_ram(0xA000, 0x12);
+        mbc1.write_rom(0x6000, 0x01);
+        mbc1.write_ram(0xA000, 0x34);
+        assert_eq!(mbc1.ram()[0], 0x12);
+        assert_eq!(mbc1.ram()[2 * RAM_BANK_SIZE], 0x34);
+        mbc1.write_rom(0x6000, 0x00);
+        assert_eq!(mbc1.read_ram(0xA000), 0x12);
+    }
+
+    #[test]
+    fn multicarts_see_16_banks_a_game() {
+        let mut rom: Vec<u8> = numbered_rom(64);
+        let logo: Vec<u8> = (0..NINTENDO_LOGO_LEN as u8).collect();
+        for game in 0..4 {
+            let start: usize = game * MULTICART_GAME_SIZE + NINTENDO_LOGO_OFFSET;
+            rom[start..start + NINTENDO_LOGO_LEN].copy_from_slice(&logo);
+        }
+        let mut mbc1: MBC1 = MBC1::new(rom, 0);
+        assert!(mbc1.multicart);
+        mbc1.write_rom(0x4000, 0x01);
+        mbc1.write_rom(0x2000, 0x12);
+        assert_eq!(mbc1.read_rom(0x4000), 0x12);
+        mbc1.write_rom(0x2000, 0x03);
+        assert_eq!(mbc1.read_rom(0x4000), 0x13);
+        // bank 0x10 of the low bits still counts as not 0
+        mbc1.write_rom(0x2000, 0x10);
+        assert_eq!(mbc1.read_rom(0x4000), 0x10);
+        mbc1.write_rom(0x6000, 0x01);
+        assert_eq!(mbc1.read_rom(0x0000), 0x10);
+    }
+
+    #[test]
+    fn plain_1mib_roms_are_not_multicarts() {
+        // the logo only at the start
+        let mut rom: Vec<u8> = numbered_rom(64);
+        rom[NINTENDO_LOGO_OFFSET..NINTENDO_LOGO_OFFSET + NINTENDO_LOGO_LEN].fill(0xCE);
+        let mbc1: MBC1 = MBC1::new(rom, 0);
+        assert!(!mbc1.multicart);
+    }
+}
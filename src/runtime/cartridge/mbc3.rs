@@ -0,0 +1,149 @@
+use super::{Mapper, rom_index};
+use super::super::cpu::memory::EXTERNAL_RAM_START;
+use super::header::RAM_BANK_SIZE;
+use super::rtc::{RealTimeClock, RtcClock};
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// up to 2MiB rom, 32KiB ram and an optional real time clock
+pub struct MBC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<RealTimeClock>,
+    ram_and_timer_enabled: bool,
+    rom_bank: u8, // 7 bits
+    ram_select: u8, // 0x00-0x03 selects a ram bank, 0x08-0x0C an rtc register
+}
+
+impl MBC3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_timer: bool) -> MBC3 {
+        return MBC3 {
+            rom,
+            ram: vec![0; ram_size],
+            rtc: if has_timer { Some(RealTimeClock::new(RtcClock::Emulated)) } else { None },
+            ram_and_timer_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+        };
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let index: usize = self.ram_select as usize * RAM_BANK_SIZE + (address - EXTERNAL_RAM_START) as usize;
+        return index % self.ram.len();
+    }
+}
+
+impl Mapper for MBC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank: usize = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        return *self.rom.get(rom_index(&self.rom, bank, address)).unwrap_or(&0xFF);
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_and_timer_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x7F;
+                if self.rom_bank == 0 { self.rom_bank = 1; }
+            }
+            0x4000..=0x5FFF => {
+                self.ram_select = value & 0x0F;
+            }
+            _ => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_and_timer_enabled { return 0xFF; }
+        match self.ram_select {
+            0x00..=0x07 => {
+                if self.ram.is_empty() { return 0xFF; }
+                return self.ram[self.ram_index(address)];
+            }
+            0x08..=0x0C => {
+                return match &self.rtc {
+                    Some(rtc) => rtc.read(self.ram_select),
+                    None => 0xFF,
+                };
+            }
+            _ => {
+                return 0xFF;
+            }
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_and_timer_enabled { return false; }
+        match self.ram_select {
+            0x00..=0x07 => {
+                if self.ram.is_empty() { return false; }
+                let index: usize = self.ram_index(address);
+                let changed: bool = self.ram[index] != value;
+                self.ram[index] = value;
+                return changed;
+            }
+            0x08..=0x0C => {
+                // the clock is saved after the ram in the .sav, so setting it needs the save written too
+                let Some(rtc) = &mut self.rtc else { return false; };
+                rtc.write(self.ram_select, value);
+                return true;
+            }
+            _ => {
+                return false;
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_clock(clock);
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        return &self.ram;
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        return &mut self.ram;
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut RealTimeClock> {
+        return self.rtc.as_mut();
+    }
+}
+
+// whether there's a clock comes from the header, so a state has one exactly when the cartridge does
+impl SaveState for MBC3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        if let Some(rtc) = &self.rtc { rtc.save_state(state); }
+        state.write_bool(self.ram_and_timer_enabled);
+        state.write_u8(self.rom_bank);
+        state.write_u8(self.ram_select);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.ram)?;
+        if let Some(rtc) = &mut self.rtc { rtc.load_state(state)?; }
+        self.ram_and_timer_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u8()? & 0x7F;
+        self.ram_select = state.read_u8()? & 0x0F;
+        if self.rom_bank == 0 { return Err(StateError::Invalid("rom bank")); }
+        return Ok(());
+    }
+}
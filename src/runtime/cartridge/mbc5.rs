@@ -0,0 +1,227 @@
+use super::{Mapper, rom_index};
+use super::super::cpu::memory::EXTERNAL_RAM_START;
+use super::header::RAM_BANK_SIZE;
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const RUMBLE_BIT: u8 = 3;
+
+// up to 8MiB rom and 128KiB ram, rumble carts steal a ram bank bit for the motor
+pub struct MBC5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16, // 9 bits, bank 0 can be mapped to 0x4000-0x7FFF
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+    motor: bool, // what the callback was last told
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
+}
+
+impl MBC5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> MBC5 {
+        return MBC5 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+            motor: false,
+            rumble_callback: None,
+        };
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        let index: usize = self.ram_bank as usize * RAM_BANK_SIZE + (address - EXTERNAL_RAM_START) as usize;
+        return index % self.ram.len();
+    }
+
+    fn set_rumble(&mut self, rumble: bool) {
+        self.rumble = rumble;
+        self.drive_motor();
+    }
+
+    fn drive_motor(&mut self) {
+        if self.motor == self.rumble { return; }
+        self.motor = self.rumble;
+        if let Some(callback) = &mut self.rumble_callback {
+            callback(self.motor);
+        }
+    }
+}
+
+impl Mapper for MBC5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank: usize = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        return *self.rom.get(rom_index(&self.rom, bank, address)).unwrap_or(&0xFF);
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x2FFF => {
+                self.rom_bank = (self.rom_bank & 0x100) | value as u16;
+            }
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((value & 1) as u16) << 8;
+            }
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.ram_bank = value & 0x07;
+                    self.set_rumble((value >> RUMBLE_BIT) & 1 == 1);
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() { return 0xFF; }
+        return self.ram[self.ram_index(address)];
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_enabled || self.ram.is_empty() { return false; }
+        let index: usize = self.ram_index(address);
+        let changed: bool = self.ram[index] != value;
+        self.ram[index] = value;
+        return changed;
+    }
+
+    fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.rumble_callback = Some(callback);
+    }
+
+    fn state_loaded(&mut self) {
+        self.drive_motor();
+    }
+
+    fn ram(&self) -> &[u8] {
+        return &self.ram;
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        return &mut self.ram;
+    }
+}
+
+// the motor is only driven once the whole state has loaded, a load that gets rolled back never shakes it
+impl SaveState for MBC5 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.rumble);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.ram)?;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u16()? & 0x01FF;
+        // rumble carts only have 3 bank bits, the fourth is the motor
+        self.ram_bank = state.read_u8()? & if self.has_rumble { 0x07 } else { 0x0F };
+        let rumble: bool = state.read_bool()?;
+        self.rumble = rumble && self.has_rumble;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::header::ROM_BANK_SIZE;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // every bank starts with its own 9 bit number, low byte first
+    fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom: Vec<u8> = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE..bank * ROM_BANK_SIZE + 2].copy_from_slice(&(bank as u16).to_le_bytes());
+        }
+        return rom;
+    }
+
+    fn bank_at_0x4000(mbc5: &MBC5) -> u16 {
+        return u16::from_le_bytes([mbc5.read_rom(0x4000), mbc5.read_rom(0x4001)]);
+    }
+
+    // an MBC5 with the motor's calls logged
+    fn rumble_mbc5() -> (MBC5, Rc<RefCell<Vec<bool>>>) {
+        let calls: Rc<RefCell<Vec<bool>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut mbc5: MBC5 = MBC5::new(numbered_rom(4), 4 * RAM_BANK_SIZE, true);
+        let log: Rc<RefCell<Vec<bool>>> = calls.clone();
+        mbc5.set_rumble_callback(Box::new(move |on| log.borrow_mut().push(on)));
+        return (mbc5, calls);
+    }
+
+    #[test]
+    fn the_ninth_rom_bank_bit_is_written_at_0x3000() {
+        let mut mbc5: MBC5 = MBC5::new(numbered_rom(512), 0, false);
+        mbc5.write_rom(0x2000, 0x05);
+        mbc5.write_rom(0x3000, 0x01);
+        assert_eq!(bank_at_0x4000(&mbc5), 0x105);
+        // the low byte write leaves the ninth bit alone
+        mbc5.write_rom(0x2000, 0xFF);
+        assert_eq!(bank_at_0x4000(&mbc5), 0x1FF);
+        mbc5.write_rom(0x3000, 0xFE);
+        assert_eq!(bank_at_0x4000(&mbc5), 0x0FF);
+    }
+
+    #[test]
+    fn bank_0_can_be_mapped_at_0x4000() {
+        let mut mbc5: MBC5 = MBC5::new(numbered_rom(4), 0, false);
+        mbc5.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at_0x4000(&mbc5), 0);
+        assert_eq!(mbc5.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn bit_3_of_the_ram_bank_drives_the_motor_on_rumble_carts() {
+        let (mut mbc5, calls) = rumble_mbc5();
+        mbc5.write_rom(0x0000, 0x0A);
+        mbc5.write_rom(0x4000, 0x0B);
+        assert_eq!(*calls.borrow(), [true]);
+        mbc5.write_ram(EXTERNAL_RAM_START, 0x42);
+        // same bank 3 with the motor off
+        mbc5.write_rom(0x4000, 0x03);
+        assert_eq!(mbc5.read_ram(EXTERNAL_RAM_START), 0x42);
+        assert_eq!(*calls.borrow(), [true, false]);
+        // the motor is only told about changes
+        mbc5.write_rom(0x4000, 0x01);
+        assert_eq!(*calls.borrow(), [true, false]);
+
+        // without rumble bit 3 is a bank bit
+        let mut plain: MBC5 = MBC5::new(numbered_rom(4), 16 * RAM_BANK_SIZE, false);
+        plain.write_rom(0x0000, 0x0A);
+        plain.write_rom(0x4000, 0x0B);
+        plain.write_ram(EXTERNAL_RAM_START, 0x42);
+        plain.write_rom(0x4000, 0x03);
+        assert_eq!(plain.read_ram(EXTERNAL_RAM_START), 0x00);
+    }
+
+    #[test]
+    fn a_loaded_state_drives_the_motor_once_it_is_in() {
+        let (mut running, _) = rumble_mbc5();
+        running.write_rom(0x4000, 0x08);
+        let mut state: StateWriter = StateWriter::initialize();
+        running.save_state(&mut state);
+        let bytes: Vec<u8> = state.into_bytes();
+
+        let (mut mbc5, calls) = rumble_mbc5();
+        mbc5.load_state(&mut StateReader::initialize(&bytes)).unwrap();
+        assert!(calls.borrow().is_empty());
+        mbc5.state_loaded();
+        assert_eq!(*calls.borrow(), [true]);
+    }
+}
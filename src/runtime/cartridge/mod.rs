@@ -0,0 +1,233 @@
+pub mod header;
+pub mod rtc;
+mod rom_only;
+mod mbc1;
+mod mbc3;
+mod mbc5;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use header::*;
+use rtc::{RealTimeClock, RtcClock, RTC_SAVE_SIZE_SHORT};
+use rom_only::RomOnly;
+use mbc1::MBC1;
+use mbc3::MBC3;
+use mbc5::MBC5;
+use super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// the banking hardware on the cartridge, owns the rom and any external ram. the rom isn't part
+// of its save state, the banking registers, ram and clock are
+pub trait Mapper: SaveState {
+    // 0x0000-0x7FFF
+    fn read_rom(&self, address: u16) -> u8;
+    // writes to the rom area drive the mapper's control registers
+    fn write_rom(&mut self, address: u16, value: u8);
+    // 0xA000-0xBFFF
+    fn read_ram(&self, address: u16) -> u8;
+    // returns whether what goes in the .sav changed, a byte of the external ram or the clock
+    fn write_ram(&mut self, address: u16, value: u8) -> bool;
+
+    // advances anything on the cartridge that runs off the system clock
+    fn tick(&mut self, _cycles: usize) {}
+    fn set_rtc_clock(&mut self, _clock: RtcClock) {}
+    fn set_rumble_callback(&mut self, _callback: Box<dyn FnMut(bool)>) {}
+    // called once a save state has loaded without errors, for anything outside the machine that follows it
+    fn state_loaded(&mut self) {}
+
+    // the external ram, this is what a battery keeps alive
+    fn ram(&self) -> &[u8] { return &[]; }
+    fn ram_mut(&mut self) -> &mut [u8] { return &mut []; }
+    fn rtc_mut(&mut self) -> Option<&mut RealTimeClock> { return None; }
+}
+
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    mapper: Box<dyn Mapper>,
+    ram_dirty: bool, // external ram or the clock was written since the last save
+}
+
+impl Cartridge {
+    pub fn from_bytes(rom: Vec<u8>) -> io::Result<Cartridge> {
+        let header: CartridgeHeader = CartridgeHeader::parse(&rom)?;
+        let mapper: Box<dyn Mapper> = match header.mapper {
+            MapperKind::RomOnly => Box::new(RomOnly::new(rom, header.ram_size)),
+            MapperKind::MBC1 => Box::new(MBC1::new(rom, header.ram_size)),
+            MapperKind::MBC3 => Box::new(MBC3::new(rom, header.ram_size, header.has_timer)),
+            MapperKind::MBC5 => Box::new(MBC5::new(rom, header.ram_size, header.has_rumble)),
+        };
+        return Ok(Cartridge { header, mapper, ram_dirty: false });
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        return self.mapper.read_rom(address);
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        self.mapper.write_rom(address, value);
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        return self.mapper.read_ram(address);
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if self.mapper.write_ram(address, value) { self.ram_dirty = true; }
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        self.mapper.tick(cycles);
+    }
+
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.mapper.set_rtc_clock(clock);
+    }
+
+    // the callback is told whenever the rumble motor switches on or off
+    pub fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.mapper.set_rumble_callback(callback);
+    }
+
+    pub fn state_loaded(&mut self) {
+        self.mapper.state_loaded();
+    }
+}
+
+impl Cartridge {
+    pub fn has_battery(&self) -> bool {
+        return self.header.has_battery;
+    }
+
+    pub fn ram_dirty(&self) -> bool {
+        return self.ram_dirty;
+    }
+
+    // puts the flag back after a state load that was rolled back
+    pub fn restore_ram_dirty(&mut self, ram_dirty: bool) {
+        self.ram_dirty = ram_dirty;
+    }
+
+    // writes the battery backed ram, followed by the rtc block for clock carts
+    pub fn save_battery(&mut self, path: &Path) -> io::Result<()> {
+        let mut contents: Vec<u8> = self.mapper.ram().to_vec();
+        if let Some(rtc) = self.mapper.rtc_mut() {
+            contents.extend_from_slice(&rtc.to_save_bytes());
+        }
+        fs::write(path, contents)?;
+        self.ram_dirty = false;
+        return Ok(());
+    }
+
+    // returns false if there was no save to load
+    pub fn load_battery(&mut self, path: &Path) -> io::Result<bool> {
+        if !path.exists() { return Ok(false); }
+        let contents: Vec<u8> = fs::read(path)?;
+
+        let ram = self.mapper.ram_mut();
+        let ram_len: usize = ram.len().min(contents.len());
+        ram[..ram_len].copy_from_slice(&contents[..ram_len]);
+
+        let trailing: &[u8] = &contents[ram_len..];
+        if let Some(rtc) = self.mapper.rtc_mut() {
+            if trailing.len() >= RTC_SAVE_SIZE_SHORT {
+                rtc.load_save_bytes(trailing);
+            }
+        }
+        self.ram_dirty = false;
+        return Ok(true);
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.mapper.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ram: Vec<u8> = self.mapper.ram().to_vec();
+        self.mapper.load_state(state)?;
+        // the .sav only falls behind if the state brought different ram with it
+        if self.mapper.ram() != ram.as_slice() { self.ram_dirty = true; }
+        return Ok(());
+    }
+}
+
+// index into a rom image for the given bank and offset, wrapping banks past the end of the image like the address lines do
+pub fn rom_index(rom: &[u8], bank: usize, offset: u16) -> usize {
+    let bank_count: usize = rom.len().div_ceil(ROM_BANK_SIZE).max(1);
+    return (bank % bank_count) * ROM_BANK_SIZE + (offset as usize & (ROM_BANK_SIZE - 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge(cartridge_type: u8, ram_size_code: u8) -> Cartridge {
+        let mut rom: Vec<u8> = vec![0; 2 * ROM_BANK_SIZE];
+        rom[CARTRIDGE_TYPE_ADDRESS] = cartridge_type;
+        rom[RAM_SIZE_ADDRESS] = ram_size_code;
+        return Cartridge::from_bytes(rom).unwrap();
+    }
+
+    #[test]
+    fn writes_that_change_nothing_leave_the_ram_clean() {
+        // MBC1+RAM+BATTERY with 8KiB
+        let mut cartridge: Cartridge = cartridge(0x03, 0x02);
+        cartridge.write_ram(0xA000, 0x12);
+        assert!(!cartridge.ram_dirty(), "ram is disabled");
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x00);
+        assert!(!cartridge.ram_dirty(), "same value");
+        cartridge.write_ram(0xA000, 0x12);
+        assert!(cartridge.ram_dirty());
+    }
+
+    #[test]
+    fn cartridges_without_ram_never_get_dirty() {
+        let mut cartridge: Cartridge = cartridge(0x01, 0x00);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x12);
+        assert!(!cartridge.ram_dirty());
+    }
+
+    #[test]
+    fn setting_the_clock_dirties_the_save() {
+        // MBC3+TIMER+RAM+BATTERY
+        let mut cartridge: Cartridge = cartridge(0x10, 0x02);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x08);
+        // latching only copies the clock
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        assert!(!cartridge.ram_dirty());
+        cartridge.write_ram(0xA000, 0x30);
+        assert!(cartridge.ram_dirty());
+    }
+
+    #[test]
+    fn only_a_state_with_other_ram_dirties_the_save() {
+        // MBC1+RAM+BATTERY
+        let mut other: Cartridge = cartridge(0x03, 0x02);
+        let mut cartridge: Cartridge = cartridge(0x03, 0x02);
+        let mut state: StateWriter = StateWriter::initialize();
+        cartridge.save_state(&mut state);
+        let same: Vec<u8> = state.into_bytes();
+        cartridge.load_state(&mut StateReader::initialize(&same)).unwrap();
+        assert!(!cartridge.ram_dirty());
+
+        other.write_rom(0x0000, 0x0A);
+        other.write_ram(0xA000, 0x42);
+        let mut state: StateWriter = StateWriter::initialize();
+        other.save_state(&mut state);
+        cartridge.load_state(&mut StateReader::initialize(&state.into_bytes())).unwrap();
+        assert!(cartridge.ram_dirty());
+    }
+
+    #[test]
+    fn unsupported_cartridges_are_errors() {
+        let mut rom: Vec<u8> = vec![0; 2 * ROM_BANK_SIZE];
+        // the pocket camera
+        rom[CARTRIDGE_TYPE_ADDRESS] = 0xFC;
+        assert_eq!(Cartridge::from_bytes(rom).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
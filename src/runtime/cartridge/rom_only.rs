@@ -0,0 +1,58 @@
+use super::Mapper;
+use super::super::cpu::memory::EXTERNAL_RAM_START;
+use super::header::RAM_BANK_SIZE;
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// 32KiB of rom with optionally up to 8KiB of ram, no banking
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        return RomOnly {
+            rom,
+            ram: vec![0; ram_size.min(RAM_BANK_SIZE)],
+        };
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        return *self.rom.get(address as usize).unwrap_or(&0xFF);
+    }
+
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ram.is_empty() { return 0xFF; }
+        return self.ram[((address - EXTERNAL_RAM_START) as usize) % self.ram.len()];
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if self.ram.is_empty() { return false; }
+        let index: usize = ((address - EXTERNAL_RAM_START) as usize) % self.ram.len();
+        let changed: bool = self.ram[index] != value;
+        self.ram[index] = value;
+        return changed;
+    }
+
+    fn ram(&self) -> &[u8] {
+        return &self.ram;
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        return &mut self.ram;
+    }
+}
+
+impl SaveState for RomOnly {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        return state.read_into(&mut self.ram);
+    }
+}
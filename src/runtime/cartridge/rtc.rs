@@ -0,0 +1,397 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::super::CYCLES_PER_SECOND;
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// the rtc block appended to .sav files by other emulators: 5 live and 5 latched registers as u32s and a u64 unix timestamp
+pub const RTC_SAVE_SIZE: usize = 48;
+// older files carry a 32 bit timestamp instead
+pub const RTC_SAVE_SIZE_SHORT: usize = 44;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const DAY_HIGH_BIT: u8 = 0;
+const HALT_BIT: u8 = 6;
+const DAY_CARRY_BIT: u8 = 7;
+
+// where the clock gets its idea of passing time from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcClock {
+    Emulated, // advances with the machine cycles run, deterministic
+    Host, // follows the host's wall clock, like a real cartridge left on a shelf
+}
+
+// the register file of the MBC3 clock, in the order they're selected (0x08-0x0C)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub day_low: u8,
+    pub day_high: u8, // bit 0: day bit 8, bit 6: halt, bit 7: day carry
+}
+
+impl RtcRegisters {
+    pub fn read(&self, select: u8) -> u8 {
+        match select {
+            0x08 => { return self.seconds; }
+            0x09 => { return self.minutes; }
+            0x0A => { return self.hours; }
+            0x0B => { return self.day_low; }
+            _ => { return self.day_high; }
+        }
+    }
+
+    fn day(&self) -> u16 {
+        return (((self.day_high >> DAY_HIGH_BIT) & 1) as u16) << 8 | self.day_low as u16;
+    }
+
+    fn set_day(&mut self, day: u16) {
+        self.day_low = (day & 0xFF) as u8;
+        self.day_high = (self.day_high & !(1 << DAY_HIGH_BIT)) | (((day >> 8) & 1) as u8) << DAY_HIGH_BIT;
+    }
+
+    // the given register values with the bits the chip doesn't have cleared, the same as writing them
+    fn masked(values: [u8; 5]) -> RtcRegisters {
+        return RtcRegisters {
+            seconds: values[0] & 0x3F,
+            minutes: values[1] & 0x3F,
+            hours: values[2] & 0x1F,
+            day_low: values[3],
+            day_high: values[4] & 0b1100_0001,
+        };
+    }
+
+    fn out_of_range(&self) -> bool {
+        return self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24;
+    }
+
+    fn halted(&self) -> bool {
+        return (self.day_high >> HALT_BIT) & 1 == 1;
+    }
+}
+
+pub struct RealTimeClock {
+    pub registers: RtcRegisters,
+    pub latched: RtcRegisters,
+    clock: RtcClock,
+    cycle_counter: usize, // machine cycles into the current second
+    host_base: SystemTime, // wall clock time the registers were last brought up to date
+    latch_primed: bool, // a 0x00 was written to the latch register
+}
+
+impl RealTimeClock {
+    pub fn new(clock: RtcClock) -> RealTimeClock {
+        return RealTimeClock {
+            registers: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            clock,
+            cycle_counter: 0,
+            host_base: SystemTime::now(),
+            latch_primed: false,
+        };
+    }
+
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.sync_host();
+        self.clock = clock;
+        self.host_base = SystemTime::now();
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        if self.clock != RtcClock::Emulated || self.registers.halted() { return; }
+        self.cycle_counter += cycles;
+        if self.cycle_counter >= CYCLES_PER_SECOND {
+            let seconds: usize = self.cycle_counter / CYCLES_PER_SECOND;
+            self.cycle_counter %= CYCLES_PER_SECOND;
+            self.advance_seconds(seconds as u64);
+        }
+    }
+
+    // writes of 0x00 then 0x01 to 0x6000-0x7FFF copy the live registers into the readable ones
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_primed && value == 0x01 {
+            self.sync_host();
+            self.latched = self.registers;
+        }
+        self.latch_primed = value == 0x00;
+    }
+
+    pub fn read(&self, select: u8) -> u8 {
+        return self.latched.read(select);
+    }
+
+    pub fn write(&mut self, select: u8, value: u8) {
+        self.sync_host();
+        match select {
+            0x08 => {
+                self.registers.seconds = value & 0x3F;
+                // writing the seconds restarts the current second
+                self.cycle_counter = 0;
+            }
+            0x09 => { self.registers.minutes = value & 0x3F; }
+            0x0A => { self.registers.hours = value & 0x1F; }
+            0x0B => { self.registers.day_low = value; }
+            _ => { self.registers.day_high = value & 0b1100_0001; }
+        }
+    }
+
+    pub fn to_save_bytes(&mut self) -> [u8; RTC_SAVE_SIZE] {
+        self.sync_host();
+        let mut bytes: [u8; RTC_SAVE_SIZE] = [0; RTC_SAVE_SIZE];
+        let values: [u8; 10] = [
+            self.registers.seconds, self.registers.minutes, self.registers.hours, self.registers.day_low, self.registers.day_high,
+            self.latched.seconds, self.latched.minutes, self.latched.hours, self.latched.day_low, self.latched.day_high,
+        ];
+        for (index, value) in values.iter().enumerate() {
+            bytes[index * 4..index * 4 + 4].copy_from_slice(&(*value as u32).to_le_bytes());
+        }
+        let timestamp: u64 = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs();
+        bytes[40..48].copy_from_slice(&timestamp.to_le_bytes());
+        return bytes;
+    }
+
+    // accepts both the 48 and 44 byte layouts, on a host clock the time spent switched off is caught up
+    pub fn load_save_bytes(&mut self, bytes: &[u8]) {
+        let value = |index: usize| -> u8 {
+            return u32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap()) as u8;
+        };
+        self.registers = RtcRegisters::masked([value(0), value(1), value(2), value(3), value(4)]);
+        self.latched = RtcRegisters::masked([value(5), value(6), value(7), value(8), value(9)]);
+        self.cycle_counter = 0;
+
+        let timestamp: u64 = if bytes.len() >= RTC_SAVE_SIZE {
+            u64::from_le_bytes(bytes[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as u64
+        };
+        self.host_base = UNIX_EPOCH + Duration::from_secs(timestamp);
+        if self.clock == RtcClock::Host {
+            self.sync_host();
+        } else {
+            self.host_base = SystemTime::now();
+        }
+    }
+
+    // catches the registers up with the wall clock, only does something in host mode
+    pub fn sync_host(&mut self) {
+        if self.clock != RtcClock::Host { return; }
+        let elapsed: Duration = SystemTime::now().duration_since(self.host_base).unwrap_or(Duration::ZERO);
+        let seconds: u64 = elapsed.as_secs();
+        self.host_base += Duration::from_secs(seconds);
+        if !self.registers.halted() {
+            self.advance_seconds(seconds);
+        }
+    }
+
+    pub fn advance_seconds(&mut self, seconds: u64) {
+        let mut seconds: u64 = seconds;
+        // out of range values count up to their bit width before wrapping, so they're run a minute at a
+        // time until every register is back in range. that's a few hundred steps at most
+        while seconds > 0 && self.registers.out_of_range() {
+            if self.registers.seconds < 60 {
+                // straight to the last second before the minute carries
+                let skip: u64 = (59 - self.registers.seconds as u64).min(seconds - 1);
+                self.registers.seconds += skip as u8;
+                seconds -= skip;
+            }
+            self.tick_second();
+            seconds -= 1;
+        }
+        if seconds == 0 { return; }
+
+        let registers = &mut self.registers;
+        let total: u64 = registers.day() as u64 * SECONDS_PER_DAY
+            + registers.hours as u64 * 3600
+            + registers.minutes as u64 * 60
+            + registers.seconds as u64
+            + seconds;
+        let mut day: u64 = total / SECONDS_PER_DAY;
+        if day > 0x1FF {
+            registers.day_high |= 1 << DAY_CARRY_BIT;
+            day &= 0x1FF;
+        }
+        registers.set_day(day as u16);
+        registers.hours = ((total % SECONDS_PER_DAY) / 3600) as u8;
+        registers.minutes = ((total % 3600) / 60) as u8;
+        registers.seconds = (total % 60) as u8;
+    }
+
+    fn tick_second(&mut self) {
+        let registers = &mut self.registers;
+        registers.seconds = (registers.seconds + 1) & 0x3F;
+        if registers.seconds != 60 { return; }
+        registers.seconds = 0;
+
+        registers.minutes = (registers.minutes + 1) & 0x3F;
+        if registers.minutes != 60 { return; }
+        registers.minutes = 0;
+
+        registers.hours = (registers.hours + 1) & 0x1F;
+        if registers.hours != 24 { return; }
+        registers.hours = 0;
+
+        let day: u16 = registers.day() + 1;
+        if day > 0x1FF {
+            registers.day_high |= 1 << DAY_CARRY_BIT;
+        }
+        registers.set_day(day & 0x1FF);
+    }
+}
+
+impl SaveState for RtcRegisters {
+    fn save_state(&self, state: &mut StateWriter) {
+        for value in [self.seconds, self.minutes, self.hours, self.day_low, self.day_high] {
+            state.write_u8(value);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = state.read_u8()? & 0x3F;
+        self.minutes = state.read_u8()? & 0x3F;
+        self.hours = state.read_u8()? & 0x1F;
+        self.day_low = state.read_u8()?;
+        self.day_high = state.read_u8()? & 0b1100_0001;
+        return Ok(());
+    }
+}
+
+// which clock it follows is up to the embedder. like a .sav, a state loaded on a host clock
+// catches up the time since it was saved, on the emulated clock it carries on from the save
+impl SaveState for RealTimeClock {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        self.latched.save_state(state);
+        state.write_usize(self.cycle_counter);
+        let base: Duration = self.host_base.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        state.write_u64(base.as_secs());
+        state.write_u32(base.subsec_nanos());
+        state.write_bool(self.latch_primed);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(state)?;
+        self.latched.load_state(state)?;
+        self.cycle_counter = state.read_usize()?;
+        let seconds: u64 = state.read_u64()?;
+        let nanos: u32 = state.read_u32()?;
+        self.latch_primed = state.read_bool()?;
+        if self.cycle_counter >= CYCLES_PER_SECOND || nanos >= 1_000_000_000 { return Err(StateError::Invalid("rtc")); }
+        self.host_base = UNIX_EPOCH + Duration::new(seconds, nanos);
+        if self.clock == RtcClock::Host {
+            self.sync_host();
+        } else {
+            self.host_base = SystemTime::now();
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(seconds: u8, minutes: u8, hours: u8, day: u16) -> RealTimeClock {
+        let mut rtc: RealTimeClock = RealTimeClock::new(RtcClock::Emulated);
+        rtc.registers = RtcRegisters { seconds, minutes, hours, ..RtcRegisters::default() };
+        rtc.registers.set_day(day);
+        return rtc;
+    }
+
+    fn time(rtc: &RealTimeClock) -> (u8, u8, u8, u16) {
+        return (rtc.registers.seconds, rtc.registers.minutes, rtc.registers.hours, rtc.registers.day());
+    }
+
+    #[test]
+    fn latching_needs_0_then_1() {
+        let mut rtc: RealTimeClock = clock(5, 4, 3, 2);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 0);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!([0x08, 0x09, 0x0A, 0x0B].map(|select| rtc.read(select)), [5, 4, 3, 2]);
+        // reads stay on the latched time while the clock runs
+        rtc.tick(CYCLES_PER_SECOND);
+        assert_eq!(rtc.read(0x08), 5);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 5);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 6);
+    }
+
+    #[test]
+    fn rolls_over_into_the_next_day() {
+        let mut rtc: RealTimeClock = clock(59, 59, 23, 0x0FF);
+        rtc.advance_seconds(1);
+        assert_eq!(time(&rtc), (0, 0, 0, 0x100));
+        assert_eq!(rtc.registers.day_high & (1 << DAY_CARRY_BIT), 0);
+    }
+
+    #[test]
+    fn the_day_counter_overflows_into_the_carry() {
+        let mut rtc: RealTimeClock = clock(59, 59, 23, 0x1FF);
+        rtc.advance_seconds(61);
+        assert_eq!(time(&rtc), (0, 1, 0, 0));
+        assert_ne!(rtc.registers.day_high & (1 << DAY_CARRY_BIT), 0);
+        // the carry sticks until it's written
+        rtc.advance_seconds(SECONDS_PER_DAY);
+        assert_ne!(rtc.registers.day_high & (1 << DAY_CARRY_BIT), 0);
+        rtc.write(0x0C, 0x00);
+        assert_eq!(rtc.registers.day_high, 0);
+    }
+
+    #[test]
+    fn loaded_save_bytes_only_keep_the_bits_the_chip_has() {
+        let mut bytes: [u8; RTC_SAVE_SIZE] = [0; RTC_SAVE_SIZE];
+        for index in 0..10 {
+            bytes[index * 4..index * 4 + 4].copy_from_slice(&0xFFu32.to_le_bytes());
+        }
+        let mut rtc: RealTimeClock = RealTimeClock::new(RtcClock::Emulated);
+        rtc.load_save_bytes(&bytes);
+        for registers in [rtc.registers, rtc.latched] {
+            assert_eq!([0x08, 0x09, 0x0A, 0x0B, 0x0C].map(|select| registers.read(select)), [0x3F, 0x3F, 0x1F, 0xFF, 0xC1]);
+        }
+    }
+
+    #[test]
+    fn a_halted_clock_stands_still() {
+        let mut rtc: RealTimeClock = clock(10, 0, 0, 0);
+        rtc.write(0x0C, 1 << HALT_BIT);
+        rtc.tick(3 * CYCLES_PER_SECOND);
+        assert_eq!(time(&rtc), (10, 0, 0, 0));
+    }
+
+    #[test]
+    fn out_of_range_registers_count_to_their_width() {
+        // 62 goes to 63 and wraps to 0 without carrying into the minutes
+        let mut rtc: RealTimeClock = clock(62, 0, 0, 0);
+        rtc.advance_seconds(2);
+        assert_eq!(time(&rtc), (0, 0, 0, 0));
+        let mut rtc: RealTimeClock = clock(0, 0, 31, 0);
+        rtc.advance_seconds(3600);
+        assert_eq!(time(&rtc), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn fast_forwarding_matches_ticking() {
+        for (seconds, minutes, hours) in [(61, 10, 5), (30, 62, 5), (30, 10, 26), (63, 63, 31), (0, 0, 0)] {
+            for elapsed in [1, 59, 60, 3599, 3600, 100_000] {
+                let mut fast: RealTimeClock = clock(seconds, minutes, hours, 0x1FE);
+                let mut ticked: RealTimeClock = clock(seconds, minutes, hours, 0x1FE);
+                fast.advance_seconds(elapsed);
+                for _ in 0..elapsed {
+                    ticked.tick_second();
+                }
+                assert_eq!(time(&fast), time(&ticked), "{:?} after {}", (seconds, minutes, hours), elapsed);
+                assert_eq!(fast.registers.day_high, ticked.registers.day_high);
+            }
+        }
+    }
+
+    #[test]
+    fn years_off_with_bad_registers_catch_up_quickly() {
+        let mut rtc: RealTimeClock = clock(63, 63, 31, 0);
+        rtc.advance_seconds(10 * 365 * SECONDS_PER_DAY);
+        assert!(!rtc.registers.out_of_range());
+        assert_ne!(rtc.registers.day_high & (1 << DAY_CARRY_BIT), 0);
+    }
+}
@@ -70,6 +70,7 @@ pub enum Instruction {
   PopRR(DoubleRegisterTarget), // pop from the stack to the double register
 
   Halt(), // stop CPU processing until interrupt is requested
+  Stop(), // enter low power mode until a button is pressed, or switch speed on CGB (2 bytes, the second is ignored)
 
   DI(), // disable interrupts
   EI(), // enable interrupts
@@ -154,6 +155,7 @@ impl Instruction {
       0x0E => Some(Instruction::LoadRN(RegisterTarget::C)),
       0x0F => Some(Instruction::RightShift(ShiftOp::RotateZ, RegisterTarget::A)),
       
+      0x10 => Some(Instruction::Stop()),
       0x11 => Some(Instruction::LoadRRNN(DoubleRegisterTarget::DE)),
       0x12 => Some(Instruction::LoadMemR(DoubleRegisterTarget::DE, RegisterTarget::A, PostOp::Nop)),
       0x13 => Some(Instruction::INC16(DoubleRegisterTarget::DE)),
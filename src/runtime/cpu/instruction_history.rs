@@ -6,6 +6,7 @@ pub struct InstructionHistory {
     pub inst: Instruction,
     pub pc: u16,
     pub spi: u32,
+    #[allow(dead_code)] // only shown by the verbose format below
     pub nops: usize,
 }
 
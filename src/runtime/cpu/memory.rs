@@ -1,12 +1,35 @@
 use std::fs;
+use std::io;
 use std::path::Path;
+use super::super::cartridge::Cartridge;
+use super::super::timer::Timer;
+use super::super::interrupt::Interrupt;
+use super::super::ppu::PPU;
+use super::super::joypad::{Button, Joypad};
+use super::super::serial::{Serial, SERIAL_DATA_REGISTER, SERIAL_CONTROL_REGISTER};
+use super::super::apu::{APU, NR10, WAVE_RAM_END, VgmLogger};
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
 
+pub const JOYPAD_REGISTER: u16 = 0xFF00;
 pub const DIVIDER_REGISTER: u16 = 0xFF04;
 pub const TIMER_REGISTER: u16 = 0xFF05;
 pub const TIMER_MODULO_REGISTER: u16 = 0xFF06;
 pub const TIMER_CONTROL_REGISTER: u16 = 0xFF07;
 pub const INTERRUPT_REQUEST_REGISTER: u16 = 0xFF0F;
+pub const LCD_CONTROL_REGISTER: u16 = 0xFF40;
+pub const LCD_STATUS_REGISTER: u16 = 0xFF41;
+pub const SCROLL_Y_REGISTER: u16 = 0xFF42;
+pub const SCROLL_X_REGISTER: u16 = 0xFF43;
+pub const LCD_Y_REGISTER: u16 = 0xFF44;
+pub const LCD_Y_COMPARE_REGISTER: u16 = 0xFF45;
+pub const DMA_REGISTER: u16 = 0xFF46;
+pub const BACKGROUND_PALETTE_REGISTER: u16 = 0xFF47;
+pub const OBJECT_PALETTE_0_REGISTER: u16 = 0xFF48;
+pub const OBJECT_PALETTE_1_REGISTER: u16 = 0xFF49;
+pub const WINDOW_Y_REGISTER: u16 = 0xFF4A;
+pub const WINDOW_X_REGISTER: u16 = 0xFF4B;
 pub const INTERRUPT_ENABLE_REGISTER: u16 = 0xFFFF;
+pub const SPEED_SWITCH_REGISTER: u16 = 0xFF4D; // KEY1, CGB only
 
 // interrupt bit layout
 pub const VBLANK_BIT: u8 = 0;
@@ -15,71 +38,540 @@ pub const TIMER_BIT: u8 = 2;
 pub const SERIAL_BIT: u8 = 3;
 pub const JOYPAD_BIT: u8 = 4;
 
+// memory map region starts
+pub const VRAM_START: u16 = 0x8000;
+pub const EXTERNAL_RAM_START: u16 = 0xA000;
+pub const WRAM_START: u16 = 0xC000;
+pub const ECHO_START: u16 = 0xE000;
+pub const OAM_START: u16 = 0xFE00;
+pub const IO_START: u16 = 0xFF00;
+pub const HRAM_START: u16 = 0xFF80;
+
+// OAM DMA copies a byte a machine cycle
+const DMA_LENGTH: u16 = 0xA0;
+
+// the address space as seen by the cpu, implementors decide which device backs each address
+pub trait Bus {
+    fn read_byte(&self, address: u16) -> u8;
+    fn write_byte(&mut self, address: u16, value: u8);
+    // the end of the machine cycle of a read or write the cpu made during an instruction
+    fn access_cycle(&mut self);
+}
+
+// an OAM DMA transfer, the source is XX00 from the value written to 0xFF46
+#[derive(Clone, Copy)]
+struct OamDma {
+    source: u16,
+    index: u16,
+}
+
 pub struct Memory {
-    memory: [u8; 0x10000]
+    pub cartridge: Cartridge,
+    pub timer: Timer,
+    pub ppu: PPU,
+    pub joypad: Joypad,
+    pub apu: APU,
+    pub serial: Serial,
+    wram: [u8; 0x2000],
+    io: [u8; 0x80],
+    hram: [u8; 0x7F],
+    interrupt_enable: u8,
+    cgb_mode: bool,
+    pub double_speed: bool,
+    pub speed_switch_armed: bool,
+    speed_remainder: usize, // odd double speed cycle waiting for its pair
+    dma_register: u8,
+    dma: Option<OamDma>,
+    dma_starting: Option<OamDma>, // spends a cycle setting up, a transfer already running keeps going meanwhile
+    dma_value: u8, // the byte the transfer last put on the bus
+    timer_cycles_ahead: usize, // cycles of the current instruction the timer has already run
+    pub vgm_logger: Option<VgmLogger>,
 }
-  
+
+impl Bus for Memory {
+    fn read_byte(&self, address: u16) -> u8 {
+        if self.dma.is_some() {
+            match self.dma_conflict(address) {
+                DmaConflict::None => {}
+                DmaConflict::Oam => { return 0xFF; }
+                DmaConflict::Bus => { return self.dma_value; }
+            }
+        }
+        return self.read_mapped(address);
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        if self.dma.is_some() && !matches!(self.dma_conflict(address), DmaConflict::None) {
+            // the bus belongs to the transfer, the write goes nowhere
+            return;
+        }
+        self.write_mapped(address, value);
+    }
+
+    // the timer is run through the access's cycle right away, so DIV, TAC and TIMA are read and written
+    // on the cycle the access happens in. the rest of the bus catches up at the end of the instruction
+    fn access_cycle(&mut self) {
+        self.timer.tick(1);
+        self.timer_cycles_ahead += 1;
+        self.poll_timer();
+    }
+}
+
+// what the cpu runs into when touching an address during OAM DMA
+enum DmaConflict {
+    None,
+    Oam, // oam is locked and reads 0xFF
+    Bus, // the transfer is using the same bus, reads see the byte being copied
+}
+
+// the dmg has an external bus (cartridge and work ram) and a separate video ram bus,
+// io and hram are inside the cpu so they stay reachable
+fn shares_dma_bus(address: u16, source: u16) -> bool {
+    let video: bool = (VRAM_START..EXTERNAL_RAM_START).contains(&address);
+    let source_video: bool = (VRAM_START..EXTERNAL_RAM_START).contains(&source);
+    return address < OAM_START && video == source_video;
+}
+
 impl Memory {
-    pub fn initialize(file_name: String) -> Memory {
-        let mut memory: [u8; 0x10000] = [0; 0x10000];
+    fn read_mapped(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => {
+                return self.cartridge.read_rom(address);
+            }
+            0x8000..=0x9FFF => {
+                return self.ppu.read_vram(address);
+            }
+            0xA000..=0xBFFF => {
+                return self.cartridge.read_ram(address);
+            }
+            0xC000..=0xDFFF => {
+                return self.wram[(address - WRAM_START) as usize];
+            }
+            0xE000..=0xFDFF => {
+                // echo ram mirrors the work ram
+                return self.wram[(address - ECHO_START) as usize];
+            }
+            0xFE00..=0xFE9F => {
+                return self.ppu.read_oam(address);
+            }
+            0xFEA0..=0xFEFF => {
+                // unusable region
+                return 0x00;
+            }
+            0xFF00..=0xFF7F => {
+                return self.read_io(address);
+            }
+            0xFF80..=0xFFFE => {
+                return self.hram[(address - HRAM_START) as usize];
+            }
+            INTERRUPT_ENABLE_REGISTER => {
+                return self.interrupt_enable;
+            }
+        }
+    }
 
+    fn write_mapped(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF => {
+                self.cartridge.write_rom(address, value);
+            }
+            0x8000..=0x9FFF => {
+                self.ppu.write_vram(address, value);
+            }
+            0xA000..=0xBFFF => {
+                self.cartridge.write_ram(address, value);
+            }
+            0xC000..=0xDFFF => {
+                self.wram[(address - WRAM_START) as usize] = value;
+            }
+            0xE000..=0xFDFF => {
+                self.wram[(address - ECHO_START) as usize] = value;
+            }
+            0xFE00..=0xFE9F => {
+                self.ppu.write_oam(address, value);
+            }
+            0xFEA0..=0xFEFF => {
+                // unusable region, writes are dropped
+            }
+            0xFF00..=0xFF7F => {
+                self.write_io(address, value);
+            }
+            0xFF80..=0xFFFE => {
+                self.hram[(address - HRAM_START) as usize] = value;
+            }
+            INTERRUPT_ENABLE_REGISTER => {
+                self.interrupt_enable = value;
+            }
+        }
+    }
+}
+  
+impl Memory {
+    pub fn initialize(file_name: String) -> io::Result<Memory> {
         let string_path: String = format!("./{}", file_name);
         let filepath: &Path = Path::new(&string_path);
+        let contents: Vec<u8> = fs::read(filepath)?;
+        return Memory::from_bytes(contents);
+    }
 
-        assert!(filepath.exists(), "{:#?}", filepath.display());
+    pub fn from_bytes(contents: Vec<u8>) -> io::Result<Memory> {
+        let cartridge: Cartridge = Cartridge::from_bytes(contents)?;
+        return Ok(Memory {
+            cgb_mode: cartridge.header.cgb_flag & 0x80 != 0,
+            cartridge,
+            timer: Timer::initialize(),
+            ppu: PPU::initialize(),
+            joypad: Joypad::initialize(),
+            apu: APU::initialize(),
+            serial: Serial::initialize(),
+            wram: [0; 0x2000],
+            io: [0; 0x80],
+            hram: [0; 0x7F],
+            interrupt_enable: 0,
+            double_speed: false,
+            speed_switch_armed: false,
+            speed_remainder: 0,
+            dma_register: 0xFF,
+            dma: None,
+            dma_starting: None,
+            dma_value: 0xFF,
+            timer_cycles_ahead: 0,
+            vgm_logger: None,
+        });
+    }
 
-        let contents: Vec<u8> = fs::read(
-            filepath,
-        ).expect(
-            &format!("path {} to file not found", filepath.display()),
-        );
+    pub fn print_range(&self, start: usize, len: usize) {
+        (start..start+len).for_each(|address| print!("{:>2x} ", self.read_byte(address as u16)));
+        println!();
+    }
 
-        let program_slice: &mut [u8] = &mut memory[0x0000 .. contents.len()];
-        program_slice.iter_mut().enumerate().for_each(|(index, slot)| {
-            *slot = contents[index];
-        });
+    fn dma_conflict(&self, address: u16) -> DmaConflict {
+        let Some(dma) = self.dma else { return DmaConflict::None; };
+        if (OAM_START..IO_START).contains(&address) { return DmaConflict::Oam; }
+        if shares_dma_bus(address, dma.source) { return DmaConflict::Bus; }
+        return DmaConflict::None;
+    }
 
-        return Memory { memory };
+    fn start_dma(&mut self, value: u8) {
+        self.dma_register = value;
+        self.dma_starting = Some(OamDma { source: (value as u16) << 8, index: 0 });
     }
 
-    pub fn print_range(&mut self, start: usize, len: usize) {
-        let slice = &mut self.memory[start..start+len];
-        slice.iter_mut().for_each(|byte|  print!("{:>2x} ", byte));
-        println!();
+    // one machine cycle of OAM DMA, runs at cpu speed
+    fn tick_dma(&mut self) {
+        if let Some(mut dma) = self.dma {
+            // sources past the work ram read the echo of it
+            let mut address: u16 = dma.source + dma.index;
+            if address >= ECHO_START { address -= ECHO_START - WRAM_START; }
+            self.dma_value = self.read_mapped(address);
+            self.ppu.write_oam(OAM_START + dma.index, self.dma_value);
+            dma.index += 1;
+            self.dma = if dma.index == DMA_LENGTH { None } else { Some(dma) };
+        }
+        if let Some(dma) = self.dma_starting.take() {
+            self.dma = Some(dma);
+        }
     }
 
-    pub fn read_byte(&self, address: u16) -> u8 {
-        return self.memory[address as usize]
+    fn poll_timer(&mut self) {
+        if self.timer.interrupt_requested {
+            self.timer.interrupt_requested = false;
+            self.request_interrupt(Interrupt::Timer);
+        }
     }
 
-    pub fn write_byte(&mut self, address: u16, value: u8) {
-        let index = address as usize;
-        match address {
-            DIVIDER_REGISTER => {
-                self.memory[index] = 0x00;
+    // advances the devices on the bus by the given machine cycles
+    pub fn tick(&mut self, cycles: usize) {
+        if self.dma.is_some() || self.dma_starting.is_some() {
+            for _ in 0..cycles {
+                self.tick_dma();
             }
+        }
 
-            0xC000..=0xDDFF => {
-                self.memory[index] = value;
-                self.memory[index + 0x2000] = value;
-            }
+        // the cpu's accesses have already taken the timer part of the way
+        let timer_cycles: usize = cycles.saturating_sub(self.timer_cycles_ahead);
+        self.timer_cycles_ahead -= cycles - timer_cycles;
+        self.timer.tick(timer_cycles);
+        self.poll_timer();
 
-            0xE000..=0xFDFF => {
-                self.memory[index] = value;
-                self.memory[index - 0x2000] = value;
+        // the internal serial clock comes off the system clock, so it speeds up in double speed
+        self.serial.tick(cycles);
+        self.poll_serial();
+
+        for _ in 0..self.timer.frame_sequencer_clocks {
+            self.apu.clock_frame_sequencer();
+        }
+        self.timer.frame_sequencer_clocks = 0;
+
+        let normal_cycles: usize = self.normal_speed_cycles(cycles);
+        self.ppu.tick(normal_cycles);
+        self.apu.tick(normal_cycles);
+        if let Some(logger) = &mut self.vgm_logger { logger.tick(normal_cycles); }
+        if self.ppu.vblank_requested {
+            self.ppu.vblank_requested = false;
+            self.request_interrupt(Interrupt::VBlank);
+        }
+        if self.ppu.stat_requested {
+            self.ppu.stat_requested = false;
+            self.request_interrupt(Interrupt::LCDSTAT);
+        }
+        self.cartridge.tick(normal_cycles);
+    }
+
+    // while the cpu is stopped the system clock is off, only what runs off its own oscillator keeps going
+    pub fn tick_stopped(&mut self, cycles: usize) {
+        // the STOP that got here already ran the timer through its own accesses
+        self.timer_cycles_ahead = self.timer_cycles_ahead.saturating_sub(cycles);
+        let normal_cycles: usize = self.normal_speed_cycles(cycles);
+        self.cartridge.tick(normal_cycles);
+    }
+
+    // transfers can also finish from outside a tick, when a cable answers or clocks the port
+    pub fn poll_serial(&mut self) {
+        if self.serial.interrupt_requested {
+            self.serial.interrupt_requested = false;
+            self.request_interrupt(Interrupt::Serial);
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_pressed(button, pressed) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.io[(INTERRUPT_REQUEST_REGISTER - IO_START) as usize] |= u8::from(interrupt);
+    }
+
+    // converts cpu machine cycles to single speed ones, for the devices that don't speed up in double speed mode
+    fn normal_speed_cycles(&mut self, cycles: usize) -> usize {
+        if !self.double_speed { return cycles; }
+        let total: usize = cycles + self.speed_remainder;
+        self.speed_remainder = total % 2;
+        return total / 2;
+    }
+
+    // performs the switch a STOP requests when KEY1 has been armed
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.timer.set_double_speed(self.double_speed);
+        self.speed_switch_armed = false;
+        self.speed_remainder = 0;
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        match address {
+            JOYPAD_REGISTER => {
+                return self.joypad.read_register();
+            }
+            SERIAL_DATA_REGISTER | SERIAL_CONTROL_REGISTER => {
+                return self.serial.read_register(address);
+            }
+            DIVIDER_REGISTER..=TIMER_CONTROL_REGISTER => {
+                return self.timer.read_register(address);
+            }
+            NR10..=WAVE_RAM_END => {
+                return self.apu.read_register(address);
+            }
+            LCD_CONTROL_REGISTER..=LCD_Y_COMPARE_REGISTER | BACKGROUND_PALETTE_REGISTER..=WINDOW_X_REGISTER => {
+                return self.ppu.read_register(address);
+            }
+            INTERRUPT_REQUEST_REGISTER => {
+                // only the five request bits exist, the rest read back as 1
+                return self.io[(address - IO_START) as usize] | 0xE0;
             }
+            DMA_REGISTER => {
+                return self.dma_register;
+            }
+            SPEED_SWITCH_REGISTER => {
+                if !self.cgb_mode { return 0xFF; }
+                return (self.double_speed as u8) << 7 | 0x7E | self.speed_switch_armed as u8;
+            }
+            _ => {
+                return self.io[(address - IO_START) as usize];
+            }
+        }
+    }
 
+    fn write_io(&mut self, address: u16, value: u8) {
+        let index = (address - IO_START) as usize;
+        match address {
+            JOYPAD_REGISTER => {
+                if self.joypad.write_register(value) {
+                    self.request_interrupt(Interrupt::Joypad);
+                }
+            }
+            SERIAL_DATA_REGISTER | SERIAL_CONTROL_REGISTER => {
+                self.serial.write_register(address, value);
+            }
+            DIVIDER_REGISTER..=TIMER_CONTROL_REGISTER => {
+                self.timer.write_register(address, value);
+            }
+            NR10..=WAVE_RAM_END => {
+                self.apu.write_register(address, value);
+                if let Some(logger) = &mut self.vgm_logger { logger.log_write(address, value); }
+            }
+            LCD_CONTROL_REGISTER..=LCD_Y_COMPARE_REGISTER | BACKGROUND_PALETTE_REGISTER..=WINDOW_X_REGISTER => {
+                self.ppu.write_register(address, value);
+            }
+            DMA_REGISTER => {
+                self.start_dma(value);
+            }
+            SPEED_SWITCH_REGISTER => {
+                if self.cgb_mode { self.speed_switch_armed = value & 1 == 1; }
+            }
             _ => {
-                self.memory[index] = value;
+                self.io[index] = value;
             }
         }
     }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.source);
+        state.write_u16(self.index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u16()? & 0xFF00;
+        self.index = state.read_u16()?;
+        if self.index >= DMA_LENGTH { return Err(StateError::Invalid("oam dma")); }
+        return Ok(());
+    }
+}
+
+fn save_dma(state: &mut StateWriter, dma: &Option<OamDma>) {
+    state.write_bool(dma.is_some());
+    dma.unwrap_or(OamDma { source: 0, index: 0 }).save_state(state);
+}
+
+fn load_dma(state: &mut StateReader) -> Result<Option<OamDma>, StateError> {
+    let running: bool = state.read_bool()?;
+    let mut dma: OamDma = OamDma { source: 0, index: 0 };
+    dma.load_state(state)?;
+    return Ok(if running { Some(dma) } else { None });
+}
+
+// the devices first, then everything the bus holds itself. the vgm log belongs to the host
+impl SaveState for Memory {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.cartridge.save_state(state);
+        self.timer.save_state(state);
+        self.ppu.save_state(state);
+        self.joypad.save_state(state);
+        self.apu.save_state(state);
+        self.serial.save_state(state);
+        state.write_bytes(&self.wram);
+        state.write_bytes(&self.io);
+        state.write_bytes(&self.hram);
+        state.write_u8(self.interrupt_enable);
+        state.write_bool(self.double_speed);
+        state.write_bool(self.speed_switch_armed);
+        state.write_usize(self.speed_remainder);
+        state.write_u8(self.dma_register);
+        save_dma(state, &self.dma);
+        save_dma(state, &self.dma_starting);
+        state.write_u8(self.dma_value);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cartridge.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.serial.load_state(state)?;
+        state.read_into(&mut self.wram)?;
+        state.read_into(&mut self.io)?;
+        state.read_into(&mut self.hram)?;
+        self.interrupt_enable = state.read_u8()?;
+        self.double_speed = state.read_bool()?;
+        self.speed_switch_armed = state.read_bool()?;
+        self.speed_remainder = state.read_usize()?;
+        self.dma_register = state.read_u8()?;
+        self.dma = load_dma(state)?;
+        self.dma_starting = load_dma(state)?;
+        self.dma_value = state.read_u8()?;
+        self.timer_cycles_ahead = 0;
+        if self.speed_remainder > 1 || (self.double_speed && !self.cgb_mode) {
+            return Err(StateError::Invalid("speed"));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a rom only cartridge with work ram at 0xC000 counting up from 0x10
+    fn dma_memory() -> Memory {
+        let mut memory: Memory = Memory::from_bytes(vec![0x00; 0x8000]).unwrap();
+        for index in 0..DMA_LENGTH {
+            memory.write_byte(WRAM_START + index, 0x10 + index as u8);
+        }
+        memory.write_byte(DMA_REGISTER, 0xC0);
+        return memory;
+    }
 
-    pub fn increment_div(&mut self, increments: u8) {
-        self.memory[DIVIDER_REGISTER as usize] = increments.wrapping_add(self.memory[DIVIDER_REGISTER as usize]);
+    #[test]
+    fn the_unused_if_bits_read_as_1() {
+        let mut memory: Memory = Memory::from_bytes(vec![0x00; 0x8000]).unwrap();
+        memory.write_byte(INTERRUPT_REQUEST_REGISTER, 0x00);
+        assert_eq!(memory.read_byte(INTERRUPT_REQUEST_REGISTER), 0xE0);
+        memory.request_interrupt(Interrupt::Timer);
+        assert_eq!(memory.read_byte(INTERRUPT_REQUEST_REGISTER), 0xE4);
     }
 
-    pub fn flag_timer_interrrupt(&mut self) {
-        self.memory[INTERRUPT_REQUEST_REGISTER as usize] = self.memory[INTERRUPT_REQUEST_REGISTER as usize] | 1<<TIMER_BIT;
+    #[test]
+    fn dma_spends_a_cycle_starting() {
+        let mut memory: Memory = dma_memory();
+        assert_eq!(memory.read_byte(OAM_START), 0x00);
+        memory.tick(1);
+        // running now, but nothing copied yet
+        assert_eq!(memory.ppu.read_oam(OAM_START), 0x00);
+        assert_eq!(memory.read_byte(OAM_START), 0xFF);
+        memory.tick(1);
+        assert_eq!(memory.ppu.read_oam(OAM_START), 0x10);
+        assert_eq!(memory.ppu.read_oam(OAM_START + 1), 0x00);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn dma_copies_160_bytes_in_160_cycles() {
+        let mut memory: Memory = dma_memory();
+        memory.tick(1 + DMA_LENGTH as usize - 1);
+        assert_eq!(memory.read_byte(OAM_START), 0xFF);
+        assert_eq!(memory.ppu.read_oam(OAM_START + DMA_LENGTH - 1), 0x00);
+        memory.tick(1);
+        for index in 0..DMA_LENGTH {
+            assert_eq!(memory.read_byte(OAM_START + index), 0x10 + index as u8);
+        }
+        assert_eq!(memory.read_byte(DMA_REGISTER), 0xC0);
+    }
+
+    #[test]
+    fn only_hram_is_reachable_during_dma() {
+        let mut memory: Memory = dma_memory();
+        memory.tick(1);
+        memory.write_byte(HRAM_START, 0x42);
+        assert_eq!(memory.read_byte(HRAM_START), 0x42);
+        assert_eq!(memory.read_byte(OAM_START), 0xFF);
+        memory.write_byte(WRAM_START + 0x100, 0x42);
+        memory.tick(DMA_LENGTH as usize);
+        // the write went nowhere
+        assert_eq!(memory.read_byte(WRAM_START + 0x100), 0x00);
+    }
+
+    #[test]
+    fn reads_on_the_dma_bus_see_the_byte_being_copied() {
+        let mut memory: Memory = dma_memory();
+        memory.tick(1 + 5);
+        // the fifth byte went over the bus last
+        assert_eq!(memory.read_byte(0x0000), 0x14);
+        assert_eq!(memory.read_byte(WRAM_START + 0x100), 0x14);
+        memory.tick(1);
+        assert_eq!(memory.read_byte(0x0000), 0x15);
+    }
+}
@@ -1,4 +1,4 @@
-mod registers;
+pub mod registers;
 pub mod memory;
 mod instruction;
 mod instruction_history;
@@ -9,24 +9,27 @@ use memory::*;
 use instruction::*;
 use instruction_history::InstructionHistory;
 use std::fmt;
+use super::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const DEBUG_INSTRUCTIONS_PER_LINE: usize = 3;
 const HISTORY_SIZE: usize = 18; // make divisible by DEBUG_INSTRUCTIONS_PER_LINE for good printing
 const PADDING_WIDTH: usize = 63;
 
-pub struct CPU {
+pub struct CPU<B: Bus> {
     pub registers: Registers,
     pub pc: u16,
-    sp: u16,
-    pub memory: Memory,
+    pub sp: u16,
+    pub memory: B,
     nop_count: usize,
     instruction_history: [InstructionHistory; HISTORY_SIZE],
-    pub master_interrupt_request: bool,
+    pub master_interrupt_enabled: bool, // IME
+    pub ei_pending: bool, // EI turns IME on only after the instruction that follows it
     pub halted: bool,
     pub stopped: bool,
+    halt_bug: bool, // the next opcode fetch does not advance pc
 }
 
-impl fmt::Display for CPU {
+impl<B: Bus> fmt::Display for CPU<B> {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for (i, chunk) in self.instruction_history.chunks(DEBUG_INSTRUCTIONS_PER_LINE).rev().enumerate() {
@@ -38,7 +41,7 @@ impl fmt::Display for CPU {
                     history_e,
                     (HISTORY_SIZE as f64).log10().floor() as usize + 1),
                 );
-                let padding_len: usize = ((j+1)*PADDING_WIDTH).checked_sub(write.chars().count()).unwrap_or(0);
+                let padding_len: usize = ((j+1)*PADDING_WIDTH).saturating_sub(write.chars().count());
                 write.push_str(&" ".repeat(padding_len))
             }
             writeln!(f, "{}", &write)?;
@@ -47,18 +50,20 @@ impl fmt::Display for CPU {
     }
 }
 
-impl CPU {
-    pub fn initialize(file_name: String) -> CPU {
+impl<B: Bus> CPU<B> {
+    pub fn initialize(memory: B) -> CPU<B> {
         return CPU {
             registers: Registers::initialize(),
             pc: 0x0100,
             sp: 0xFFFE,
-            memory: Memory::initialize(file_name),
+            memory,
             nop_count: 0,
             instruction_history: [InstructionHistory::new(); HISTORY_SIZE],
-            master_interrupt_request: false,
+            master_interrupt_enabled: false,
+            ei_pending: false,
             halted: false,
             stopped: false,
+            halt_bug: false,
         }
     }
 
@@ -67,7 +72,6 @@ impl CPU {
     }
 
     pub fn write_byte_debug(&mut self, address: u16, value: u8) {
-        let pre_value = self.memory.read_byte(address);
         self.memory.write_byte(address, value);
 
         // if address == TIMER_MODULO_REGISTER || address == TIMER_CONTROL_REGISTER {
@@ -94,15 +98,35 @@ impl CPU {
         // }
     }
 
+    // a read or write an instruction makes, each takes one of its machine cycles
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        let data: u8 = self.memory.read_byte(address);
+        self.memory.access_cycle();
+        return data;
+    }
+
+    fn write_cycle(&mut self, address: u16, value: u8) {
+        self.write_byte_debug(address, value);
+        self.memory.access_cycle();
+    }
+
     // returns the number of machine cycles taken by the step
     pub fn step(&mut self, step_count: usize) -> usize {
+        // an EI from the previous step takes effect once this instruction is done
+        let enable_interrupts: bool = self.ei_pending;
+
         //fetch
         let mut mem_cycles: usize = 0;
-        let mut instruction_byte: u8 = self.memory.read_byte(self.pc);
-        self.pc += 1;
+        let mut instruction_byte: u8 = self.read_cycle(self.pc);
+        if self.halt_bug {
+            // the byte after the HALT gets read twice
+            self.halt_bug = false;
+        } else {
+            self.pc += 1;
+        }
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
-            instruction_byte = self.memory.read_byte(self.pc); // pc has already been incremented
+            instruction_byte = self.read_cycle(self.pc); // pc has already been incremented
             self.pc += 1; // increment again to put in expected location
             mem_cycles += 1;
         }
@@ -134,6 +158,11 @@ impl CPU {
             panic!("Unkown instruction found for: {}", description)
         };
 
+        if enable_interrupts && self.ei_pending {
+            self.master_interrupt_enabled = true;
+            self.ei_pending = false;
+        }
+
         if self.pc >= 0xFFFD {
             println!("{}", self);
             println!("failed on step: {}", step_count);
@@ -149,6 +178,20 @@ impl CPU {
         return mem_cycles;
     }
 
+    // an enabled interrupt has been requested, regardless of IME
+    pub fn interrupt_pending(&self) -> bool {
+        return self.memory.read_byte(INTERRUPT_REQUEST_REGISTER) & self.memory.read_byte(INTERRUPT_ENABLE_REGISTER) & 0x1F != 0;
+    }
+
+    // the pc an interrupt should return to, an interrupt taken during the HALT bug returns to the HALT itself
+    pub fn interrupt_return_address(&mut self) -> u16 {
+        if self.halt_bug {
+            self.halt_bug = false;
+            return self.pc.wrapping_sub(1);
+        }
+        return self.pc;
+    }
+
     pub fn call (&mut self, new_location: u16) {
         self.push(self.pc);
         self.pc = new_location;
@@ -157,12 +200,30 @@ impl CPU {
     // returns the number of machine cycles taken by the instruction
     fn execute(&mut self, instruction: Instruction) -> usize {
       match instruction {
+        Instruction::Halt() => {
+            if self.interrupt_pending() {
+                // with IME set the interrupt is serviced right away, without it HALT falls through and trips the bug
+                if !self.master_interrupt_enabled { self.halt_bug = true; }
+            } else {
+                self.halted = true;
+            }
+            return 1;
+        }
+        Instruction::Stop() => {
+            let _ = self.get_n(); // STOP is followed by a padding byte
+            // DIV is reset on entering stop, whoever runs the cpu decides between sleeping and a speed switch
+            self.write_byte_debug(DIVIDER_REGISTER, 0x00);
+            self.stopped = true;
+            // the opcode and the padding byte, both go over the bus
+            return 2;
+        }
         Instruction::EI() => {
-            self.master_interrupt_request = true;
+            self.ei_pending = true;
             return 1;
         }
         Instruction::DI() => {
-            self.master_interrupt_request = false;
+            self.master_interrupt_enabled = false;
+            self.ei_pending = false;
             return 1;
         }
         Instruction::NOP() => { return 1; }
@@ -236,7 +297,8 @@ impl CPU {
         }
         Instruction::INCmem(target) => {
             let carry: u8 = self.registers.get_carry(); // need to preserve carry value as this op does not change it
-            let new_value: u8 = self.add(self.get_memory_target(target), 1, false);
+            let value: u8 = self.get_memory_target(target);
+            let new_value: u8 = self.add(value, 1, false);
             if carry == 1 {self.registers.flag_carry();} else {self.registers.clear_carry();}
             self.set_memory_target(target, new_value);
             return 3;
@@ -262,7 +324,8 @@ impl CPU {
         }
         Instruction::DECmem(target) => {
             let carry: u8 = self.registers.get_carry(); // need to preserve carry value as this op does not change it
-            let new_value: u8 = self.sub(self.get_memory_target(target), 1, false);
+            let value: u8 = self.get_memory_target(target);
+            let new_value: u8 = self.sub(value, 1, false);
             if carry == 1 {self.registers.flag_carry();} else {self.registers.clear_carry();}
             self.set_memory_target(target, new_value);
             return 3;
@@ -284,7 +347,8 @@ impl CPU {
             return 1;
         }
         Instruction::ANDmem(target) => {
-            self.registers.a = self.logical_and(self.registers.a, self.get_memory_target(target));
+            let value: u8 = self.get_memory_target(target);
+            self.registers.a = self.logical_and(self.registers.a, value);
             return 2;
         }
         Instruction::ANDn() => {
@@ -297,7 +361,8 @@ impl CPU {
             return 1;
         }
         Instruction::XORmem(target) => {
-            self.registers.a = self.logical_xor(self.registers.a, self.get_memory_target(target));
+            let value: u8 = self.get_memory_target(target);
+            self.registers.a = self.logical_xor(self.registers.a, value);
             return 2;
         }
         Instruction::XORn() => {
@@ -310,7 +375,8 @@ impl CPU {
             return 1;
         }
         Instruction::ORmem(target) => {
-            self.registers.a = self.logical_or(self.registers.a, self.get_memory_target(target));
+            let value: u8 = self.get_memory_target(target);
+            self.registers.a = self.logical_or(self.registers.a, value);
             return 2;
         }
         Instruction::ORn() => {
@@ -412,11 +478,12 @@ impl CPU {
         }
         Instruction::RETI() => {
             self.pc = self.pop();
-            self.master_interrupt_request = true;
+            // unlike EI this takes effect straight away
+            self.master_interrupt_enabled = true;
             return 4;
         }
         Instruction::CallI(target) => {
-            self.call(target as u16);
+            self.call(u16::from(target));
             return 4;
         }
         Instruction::LoadRR(destination, source) => {
@@ -447,38 +514,38 @@ impl CPU {
         }
         Instruction::LoadRNN(destination) => {
             let nn: u16 = self.get_nn();
-            let data: u8 = self.memory.read_byte(nn);
+            let data: u8 = self.read_cycle(nn);
             self.set_register_target(destination, data);
             return 4;
         }
         Instruction::LoadNNR(source) => {
             let nn: u16 = self.get_nn();
             let data: u8 = self.get_register_target(source);
-            self.write_byte_debug(nn, data);
+            self.write_cycle(nn, data);
             return 4;
         }
         Instruction::LoadRHighR(destination, offset) => {
             let offset: u8 = self.get_register_target(offset);
-            let data: u8 = self.memory.read_byte(0xFF00_u16 + offset as u16);
+            let data: u8 = self.read_cycle(0xFF00_u16 + offset as u16);
             self.set_register_target(destination, data);
             return 2;
         }
         Instruction::LoadHighRR(offset, source) => {
             let offset: u8 = self.get_register_target(offset);
             let data: u8 = self.get_register_target(source);
-            self.write_byte_debug(0xFF00_u16 + offset as u16, data);
+            self.write_cycle(0xFF00_u16 + offset as u16, data);
             return 2;
         }
         Instruction::LoadRHighN(destination) => {
             let offset: u8 = self.get_n();
-            let data: u8 = self.memory.read_byte(0xFF00_u16 + offset as u16);
+            let data: u8 = self.read_cycle(0xFF00_u16 + offset as u16);
             self.set_register_target(destination, data);
             return 3;
         }
         Instruction::LoadHighNR(source) => {
             let offset: u8 = self.get_n();
             let data: u8 = self.get_register_target(source);
-            self.write_byte_debug(0xFF00_u16 + offset as u16, data);
+            self.write_cycle(0xFF00_u16 + offset as u16, data);
             return 3;
         }
         Instruction::LoadRRNN(destination) => {
@@ -488,8 +555,8 @@ impl CPU {
         }
         Instruction::LoadNNSP() => {
             let destination = self.get_nn();
-            self.write_byte_debug(destination, (self.sp & 0x00FF) as u8);
-            self.write_byte_debug(destination + 1, ((self.sp & 0xFF00) >> 8) as u8);
+            self.write_cycle(destination, (self.sp & 0x00FF) as u8);
+            self.write_cycle(destination + 1, ((self.sp & 0xFF00) >> 8) as u8);
             return 5;
         }
         Instruction::LoadSPNN() => {
@@ -805,19 +872,19 @@ impl CPU {
     }
 
     // get memory byte from location specified by the double register from enum
-    fn get_memory_target(&self, target: DoubleRegisterTarget) -> u8 {
+    fn get_memory_target(&mut self, target: DoubleRegisterTarget) -> u8 {
         match target {
             DoubleRegisterTarget::AF => {
-                return self.memory.read_byte(self.registers.get_af());
+                return self.read_cycle(self.registers.get_af());
             }
             DoubleRegisterTarget::BC => {
-                return self.memory.read_byte(self.registers.get_bc());
+                return self.read_cycle(self.registers.get_bc());
             }
             DoubleRegisterTarget::DE => {
-                return self.memory.read_byte(self.registers.get_de());
+                return self.read_cycle(self.registers.get_de());
             }
             DoubleRegisterTarget::HL => {
-                return self.memory.read_byte(self.registers.get_hl());
+                return self.read_cycle(self.registers.get_hl());
             }
         }
     }
@@ -826,32 +893,32 @@ impl CPU {
     fn set_memory_target(&mut self, target: DoubleRegisterTarget, value: u8) {
         match target {
             DoubleRegisterTarget::AF => {
-                self.write_byte_debug(self.registers.get_af(), value);
+                self.write_cycle(self.registers.get_af(), value);
             }
             DoubleRegisterTarget::BC => {
-                self.write_byte_debug(self.registers.get_bc(), value);
+                self.write_cycle(self.registers.get_bc(), value);
             }
             DoubleRegisterTarget::DE => {
-                self.write_byte_debug(self.registers.get_de(), value);
+                self.write_cycle(self.registers.get_de(), value);
             }
             DoubleRegisterTarget::HL => {
-                self.write_byte_debug(self.registers.get_hl(), value);
+                self.write_cycle(self.registers.get_hl(), value);
             }
         }
     }
 
     // get the next byte after PC (increments PC)
     fn get_n(&mut self) -> u8 {
-        let data: u8 = self.memory.read_byte(self.pc);
+        let data: u8 = self.read_cycle(self.pc);
         self.pc += 1;
         return data;
     }
 
     // get the next two bytes after PC (increments PC twice) and return as 16 bit little endian number
     fn get_nn(&mut self) -> u16 {
-        let lsb: u8 = self.memory.read_byte(self.pc);
+        let lsb: u8 = self.read_cycle(self.pc);
         self.pc += 1;
-        let msb: u8 = self.memory.read_byte(self.pc);
+        let msb: u8 = self.read_cycle(self.pc);
         self.pc += 1;
         let data: u16 = (msb as u16) << 8 | lsb as u16;
         return data;
@@ -934,16 +1001,16 @@ impl CPU {
     fn check_conditional(&self, conditional: Conditional) -> bool {
         match conditional {
             Conditional::ZeroFlag => {
-                if self.registers.get_zero() == 1 {return true;} else {return false;}
+                return self.registers.get_zero() == 1;
             }
             Conditional::NotZeroFlag => {
-                if self.registers.get_zero() == 0 {return true;} else {return false;}
+                return self.registers.get_zero() == 0;
             }
             Conditional::CarryFlag => {
-                if self.registers.get_carry() == 1 {return true;} else {return false;}
+                return self.registers.get_carry() == 1;
             }
             Conditional::NotCarryFlag => {
-                if self.registers.get_carry() == 0 {return true;} else {return false;}
+                return self.registers.get_carry() == 0;
             }
             Conditional::Unconditional => {
                 return true;
@@ -954,18 +1021,18 @@ impl CPU {
     // push to the stack
     fn push(&mut self, value: u16) {
         self.sp = self.sp.wrapping_sub(1);
-        self.write_byte_debug(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.write_cycle(self.sp, ((value & 0xFF00) >> 8) as u8);
     
         self.sp = self.sp.wrapping_sub(1);
-        self.write_byte_debug(self.sp, (value & 0xFF) as u8);
+        self.write_cycle(self.sp, (value & 0xFF) as u8);
     }
 
     // pop from the stack
     fn pop(&mut self) -> u16 {
-        let lsb = self.memory.read_byte(self.sp) as u16;
+        let lsb = self.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
     
-        let msb = self.memory.read_byte(self.sp) as u16;
+        let msb = self.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
     
         return (msb << 8) | lsb;
@@ -990,4 +1057,33 @@ impl CPU {
     }
 }
   
-  
\ No newline at end of file
+  
+// the instruction history is only for debug printouts and starts over after a load
+impl<B: Bus + SaveState> SaveState for CPU<B> {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        state.write_u16(self.pc);
+        state.write_u16(self.sp);
+        state.write_bool(self.master_interrupt_enabled);
+        state.write_bool(self.ei_pending);
+        state.write_bool(self.halted);
+        state.write_bool(self.stopped);
+        state.write_bool(self.halt_bug);
+        self.memory.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(state)?;
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u16()?;
+        self.master_interrupt_enabled = state.read_bool()?;
+        self.ei_pending = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.stopped = state.read_bool()?;
+        self.halt_bug = state.read_bool()?;
+        self.memory.load_state(state)?;
+        self.nop_count = 0;
+        self.instruction_history = [InstructionHistory::new(); HISTORY_SIZE];
+        return Ok(());
+    }
+}
@@ -1,6 +1,7 @@
 mod flags_register;
 
 use flags_register::FlagsRegister;
+use super::super::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Registers {
     pub a: u8,
@@ -105,4 +106,21 @@ impl Registers {
     pub fn clear_half_carry(&mut self) {
         self.f.half_carry = false;
     }
-  }
\ No newline at end of file
+  }
+
+impl SaveState for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.get_af());
+        state.write_u16(self.get_bc());
+        state.write_u16(self.get_de());
+        state.write_u16(self.get_hl());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.set_af(state.read_u16()?);
+        self.set_bc(state.read_u16()?);
+        self.set_de(state.read_u16()?);
+        self.set_hl(state.read_u16()?);
+        return Ok(());
+    }
+}
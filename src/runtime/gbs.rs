@@ -0,0 +1,282 @@
+use super::{Runtime, CYCLES_PER_SECOND};
+use super::cartridge::header::{CARTRIDGE_TYPE_ADDRESS, ROM_SIZE_ADDRESS, RAM_SIZE_ADDRESS, TITLE_START, TITLE_END, ROM_BANK_SIZE};
+use super::cpu::memory::{INTERRUPT_ENABLE_REGISTER, INTERRUPT_REQUEST_REGISTER, TIMER_REGISTER, TIMER_MODULO_REGISTER, TIMER_CONTROL_REGISTER, TIMER_BIT, VBLANK_BIT};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const GBS_HEADER_SIZE: usize = 0x70;
+// the rip's code has to load above the vectors and the cartridge header we put in front of it
+const MINIMUM_LOAD_ADDRESS: u16 = 0x0400;
+
+// the player's own code, placed in the vectors below the rip
+const VBLANK_VECTOR: usize = 0x40;
+const TIMER_VECTOR: usize = 0x50;
+const IDLE_ADDRESS: u16 = 0x68;
+const OPCODE_JP: u8 = 0xC3;
+const OPCODE_CALL: u8 = 0xCD;
+const OPCODE_RETI: u8 = 0xD9;
+const OPCODE_EI: u8 = 0xFB;
+const OPCODE_HALT: u8 = 0x76;
+const OPCODE_JR: u8 = 0x18;
+
+// mbc1 with 32KiB of ram, the banking rips expect is writing the bank to 0x2000
+const CARTRIDGE_TYPE_MBC1_RAM: u8 = 0x02;
+const RAM_SIZE_32K: u8 = 0x03;
+const RAM_ENABLE_ADDRESS: u16 = 0x0000;
+
+// TAC bit 2, PLAY runs off the timer instead of vblank
+const TIMER_ENABLE: u8 = 0x04;
+
+// the 0x70 byte header at the start of a .gbs file
+pub struct GbsHeader {
+    pub song_count: u8,
+    pub first_song: u8, // 1 based
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(bytes: &[u8]) -> io::Result<GbsHeader> {
+        if bytes.len() < GBS_HEADER_SIZE || &bytes[0..3] != b"GBS" {
+            return Err(invalid_data("not a gbs file".to_owned()));
+        }
+        if bytes[3] != 1 {
+            return Err(invalid_data(format!("unsupported gbs version {}", bytes[3])));
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| -> String {
+            return bytes[offset..offset + 32].iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect();
+        };
+        return Ok(GbsHeader {
+            song_count: bytes[0x04],
+            first_song: bytes[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        });
+    }
+
+    // vblank at 59.7Hz, or the timer's overflow rate
+    pub fn uses_timer(&self) -> bool {
+        return self.timer_control & TIMER_ENABLE != 0;
+    }
+}
+
+// plays the tracks of a .gbs rip. the code is mapped into a cartridge as it would be in the game,
+// INIT is called for the track and then PLAY from the vblank or timer interrupt
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    rom: Vec<u8>,
+    runtime: Runtime,
+    sample_rate: usize,
+}
+
+impl GbsPlayer {
+    pub fn load(path: &Path) -> io::Result<GbsPlayer> {
+        return GbsPlayer::from_bytes(&fs::read(path)?);
+    }
+
+    // rips that can't be played are InvalidData errors
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<GbsPlayer> {
+        let header: GbsHeader = GbsHeader::parse(bytes)?;
+        if header.load_address < MINIMUM_LOAD_ADDRESS {
+            return Err(invalid_data(format!("gbs load address 0x{:04x} is below 0x{:04x}", header.load_address, MINIMUM_LOAD_ADDRESS)));
+        }
+        if header.song_count == 0 {
+            return Err(invalid_data("gbs has no tracks".to_owned()));
+        }
+        let rom: Vec<u8> = build_rom(&header, &bytes[GBS_HEADER_SIZE..]);
+        let runtime: Runtime = Runtime::from_rom_bytes(rom.clone())?;
+        let sample_rate: usize = runtime.audio_sample_rate();
+        // a first song past the end starts on the last one
+        let first: usize = (header.first_song.max(1) as usize - 1).min(header.song_count as usize - 1);
+        let mut player: GbsPlayer = GbsPlayer { header, rom, runtime, sample_rate };
+        player.start_track(first)?;
+        return Ok(player);
+    }
+
+    // the machine playing the track, for running it and getting at the audio
+    pub fn runtime(&mut self) -> &mut Runtime {
+        return &mut self.runtime;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
+        self.runtime.set_audio_sample_rate(sample_rate);
+    }
+
+    // restarts the machine and calls INIT with the 0 based track in A. any audio recording is finished.
+    // a track past the end is an InvalidInput error and leaves the current one playing
+    pub fn start_track(&mut self, track: usize) -> io::Result<()> {
+        if track >= self.header.song_count as usize {
+            let message: String = format!("track {} out of range, there are {}", track.saturating_add(1), self.header.song_count);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        let _ = self.runtime.stop_audio_recording();
+        self.runtime = Runtime::from_rom_bytes(self.rom.clone())?;
+        self.runtime.set_audio_sample_rate(self.sample_rate);
+
+        let runtime: &mut Runtime = &mut self.runtime;
+        runtime.write_byte(RAM_ENABLE_ADDRESS, 0x0A);
+        runtime.write_byte(TIMER_MODULO_REGISTER, self.header.timer_modulo);
+        // starting from the modulo keeps the first PLAY a full period away, not the whole 256 counts
+        runtime.write_byte(TIMER_REGISTER, self.header.timer_modulo);
+        // bit 7 asks for cgb double speed, there's no cgb to run it on
+        runtime.write_byte(TIMER_CONTROL_REGISTER, self.header.timer_control & 0x07);
+        let source: u8 = if self.header.uses_timer() { TIMER_BIT } else { VBLANK_BIT };
+        runtime.write_byte(INTERRUPT_ENABLE_REGISTER, 1 << source);
+        runtime.write_byte(INTERRUPT_REQUEST_REGISTER, 0x00);
+
+        // INIT returns into the idle loop, which turns interrupts on and waits for them
+        runtime.cpu.master_interrupt_enabled = false;
+        runtime.cpu.sp = self.header.stack_pointer;
+        runtime.cpu.registers.a = track as u8;
+        runtime.cpu.pc = IDLE_ADDRESS;
+        runtime.cpu.call(self.header.init_address);
+        return Ok(());
+    }
+
+    // plays the track for the given emulated seconds, returns the cycles run
+    pub fn play_for(&mut self, seconds: usize) -> usize {
+        return self.runtime.run_cycles(seconds * CYCLES_PER_SECOND);
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+// a cartridge image with the code at its load address, the rst vectors pointing into it and the
+// player's interrupt handlers and idle loop in the space the rip doesn't use
+fn build_rom(header: &GbsHeader, code: &[u8]) -> Vec<u8> {
+    let end: usize = header.load_address as usize + code.len();
+    let banks: usize = end.div_ceil(ROM_BANK_SIZE).max(2).next_power_of_two();
+    let mut rom: Vec<u8> = vec![0xFF; banks * ROM_BANK_SIZE];
+    rom[header.load_address as usize..end].copy_from_slice(code);
+
+    // rst n lands on load address + n
+    for vector in (0x00..VBLANK_VECTOR).step_by(8) {
+        let target: u16 = header.load_address + vector as u16;
+        rom[vector..vector + 3].copy_from_slice(&[OPCODE_JP, target as u8, (target >> 8) as u8]);
+    }
+    let play: [u8; 4] = [OPCODE_CALL, header.play_address as u8, (header.play_address >> 8) as u8, OPCODE_RETI];
+    for vector in (VBLANK_VECTOR..IDLE_ADDRESS as usize).step_by(8) {
+        rom[vector] = OPCODE_RETI;
+    }
+    let vector: usize = if header.uses_timer() { TIMER_VECTOR } else { VBLANK_VECTOR };
+    rom[vector..vector + 4].copy_from_slice(&play);
+    let idle: usize = IDLE_ADDRESS as usize;
+    // EI, then HALT forever, JR jumps back to the HALT
+    rom[idle..idle + 4].copy_from_slice(&[OPCODE_EI, OPCODE_HALT, OPCODE_JR, 0xFD]);
+
+    rom[TITLE_START..TITLE_END].fill(0);
+    for (index, byte) in header.title.bytes().take(TITLE_END - TITLE_START).enumerate() {
+        rom[TITLE_START + index] = byte;
+    }
+    rom[CARTRIDGE_TYPE_ADDRESS] = CARTRIDGE_TYPE_MBC1_RAM;
+    rom[ROM_SIZE_ADDRESS] = banks.trailing_zeros() as u8 - 1;
+    rom[RAM_SIZE_ADDRESS] = RAM_SIZE_32K;
+    return rom;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // INIT keeps the track in 0xFF80, PLAY counts its calls in 0xFF81
+    const CODE: [u8; 9] = [0xE0, 0x80, 0xC9, 0xF0, 0x81, 0x3C, 0xE0, 0x81, 0xC9];
+
+    fn gbs(song_count: u8, load_address: u16, timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![0x00; GBS_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(b"GBS\x01");
+        bytes[0x04] = song_count;
+        bytes[0x05] = 1;
+        bytes[0x06..0x08].copy_from_slice(&load_address.to_le_bytes());
+        bytes[0x08..0x0A].copy_from_slice(&load_address.to_le_bytes());
+        bytes[0x0A..0x0C].copy_from_slice(&(load_address + 3).to_le_bytes());
+        bytes[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes());
+        bytes[0x0E] = timer_modulo;
+        bytes[0x0F] = timer_control;
+        bytes[0x10..0x15].copy_from_slice(b"Songs");
+        bytes.extend_from_slice(&CODE);
+        return bytes;
+    }
+
+    fn error_kind(bytes: &[u8]) -> io::ErrorKind {
+        return GbsPlayer::from_bytes(bytes).err().unwrap().kind();
+    }
+
+    #[test]
+    fn unplayable_rips_are_invalid_data() {
+        let mut bad_magic: Vec<u8> = gbs(3, 0x0400, 0, 0);
+        bad_magic[2] = b'X';
+        assert_eq!(error_kind(&bad_magic), io::ErrorKind::InvalidData);
+        let mut version_2: Vec<u8> = gbs(3, 0x0400, 0, 0);
+        version_2[3] = 2;
+        assert_eq!(error_kind(&version_2), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind(&gbs(3, 0x0400, 0, 0)[..0x40]), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind(&gbs(3, 0x03FF, 0, 0)), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind(&gbs(0, 0x0400, 0, 0)), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tracks_past_the_end_are_turned_away() {
+        let mut player: GbsPlayer = GbsPlayer::from_bytes(&gbs(3, 0x0400, 0, 0)).unwrap();
+        assert_eq!(player.header.title, "Songs");
+        assert_eq!(player.start_track(3).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        player.start_track(2).unwrap();
+        player.runtime().run_cycles(100);
+        assert_eq!(player.runtime().read_byte(0xFF80), 2);
+    }
+
+    #[test]
+    fn rsts_land_on_the_load_address() {
+        let header: GbsHeader = GbsHeader::parse(&gbs(1, 0x1234, 0, 0)).unwrap();
+        let rom: Vec<u8> = build_rom(&header, &CODE);
+        for vector in (0x00..0x40).step_by(8) {
+            let target: u16 = 0x1234 + vector as u16;
+            assert_eq!(rom[vector..vector + 3], [OPCODE_JP, target as u8, (target >> 8) as u8]);
+        }
+        assert_eq!(rom[0x1234..0x1234 + CODE.len()], CODE);
+    }
+
+    #[test]
+    fn tac_picks_the_play_interrupt() {
+        let vblank: GbsHeader = GbsHeader::parse(&gbs(1, 0x0400, 0, 0)).unwrap();
+        let rom: Vec<u8> = build_rom(&vblank, &CODE);
+        assert_eq!(rom[VBLANK_VECTOR..VBLANK_VECTOR + 4], [OPCODE_CALL, 0x03, 0x04, OPCODE_RETI]);
+        assert_eq!(rom[TIMER_VECTOR], OPCODE_RETI);
+
+        let timer: GbsHeader = GbsHeader::parse(&gbs(1, 0x0400, 0, 0x04)).unwrap();
+        let rom: Vec<u8> = build_rom(&timer, &CODE);
+        assert_eq!(rom[TIMER_VECTOR..TIMER_VECTOR + 4], [OPCODE_CALL, 0x03, 0x04, OPCODE_RETI]);
+        assert_eq!(rom[VBLANK_VECTOR], OPCODE_RETI);
+    }
+
+    #[test]
+    fn play_runs_at_the_rate_of_its_interrupt() {
+        // 59.7 frames a second
+        let mut player: GbsPlayer = GbsPlayer::from_bytes(&gbs(1, 0x0400, 0, 0)).unwrap();
+        player.play_for(1);
+        assert!((59..=60).contains(&player.runtime().read_byte(0xFF81)));
+
+        // 4096Hz over 256 counts, the 16th lands right on the end of the second
+        let mut player: GbsPlayer = GbsPlayer::from_bytes(&gbs(1, 0x0400, 0x00, 0x04)).unwrap();
+        player.play_for(1);
+        assert!((15..=16).contains(&player.runtime().read_byte(0xFF81)));
+    }
+}
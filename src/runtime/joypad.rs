@@ -0,0 +1,135 @@
+use super::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// P1/JOYP at 0xFF00, the buttons sit on a 2x4 matrix and bits 4 and 5 pick which row is read
+const SELECT_DIRECTIONS: u8 = 1 << 4; // P14
+const SELECT_ACTIONS: u8 = 1 << 5; // P15
+const SELECT_MASK: u8 = SELECT_DIRECTIONS | SELECT_ACTIONS;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    // bit in the low nibble of P1 the button pulls low
+    fn line(&self) -> u8 {
+        match self {
+            Button::A | Button::Right => { return 1 << 0; }
+            Button::B | Button::Left => { return 1 << 1; }
+            Button::Select | Button::Up => { return 1 << 2; }
+            Button::Start | Button::Down => { return 1 << 3; }
+        }
+    }
+
+    fn is_direction(&self) -> bool {
+        return matches!(self, Button::Up | Button::Down | Button::Left | Button::Right);
+    }
+}
+
+pub struct Joypad {
+    select: u8, // bits 4 and 5 as written, a 0 selects the row
+    directions: u8, // pressed buttons as set bits, in P1 line order
+    actions: u8,
+}
+
+impl Joypad {
+    pub fn initialize() -> Joypad {
+        return Joypad {
+            select: SELECT_MASK,
+            directions: 0,
+            actions: 0,
+        };
+    }
+
+    // the P10-P13 lines currently pulled low, as set bits
+    pub fn low_lines(&self) -> u8 {
+        let mut lines: u8 = 0;
+        if self.select & SELECT_DIRECTIONS == 0 { lines |= self.directions; }
+        if self.select & SELECT_ACTIONS == 0 { lines |= self.actions; }
+        return lines;
+    }
+
+    pub fn read_register(&self) -> u8 {
+        // active low, the top two bits aren't wired
+        return 0xC0 | self.select | (!self.low_lines() & 0x0F);
+    }
+
+    // these return whether a line went from high to low, which requests the joypad interrupt
+    pub fn write_register(&mut self, value: u8) -> bool {
+        let before: u8 = self.low_lines();
+        self.select = value & SELECT_MASK;
+        return self.low_lines() & !before != 0;
+    }
+
+    pub fn set_pressed(&mut self, button: Button, pressed: bool) -> bool {
+        let before: u8 = self.low_lines();
+        let row: &mut u8 = if button.is_direction() { &mut self.directions } else { &mut self.actions };
+        if pressed {
+            *row |= button.line();
+        } else {
+            *row &= !button.line();
+        }
+        return self.low_lines() & !before != 0;
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.select);
+        state.write_u8(self.directions);
+        state.write_u8(self.actions);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.select = state.read_u8()? & SELECT_MASK;
+        self.directions = state.read_u8()? & 0x0F;
+        self.actions = state.read_u8()? & 0x0F;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_select_bits_pick_the_row_that_is_read() {
+        let mut joypad: Joypad = Joypad::initialize();
+        joypad.set_pressed(Button::A, true);
+        joypad.set_pressed(Button::Down, true);
+        // nothing selected, every line high
+        assert_eq!(joypad.read_register(), 0xFF);
+        joypad.write_register(0x20);
+        assert_eq!(joypad.read_register(), 0xE7);
+        joypad.write_register(0x10);
+        assert_eq!(joypad.read_register(), 0xDE);
+        // both rows pull on the same lines
+        joypad.write_register(0x00);
+        assert_eq!(joypad.read_register(), 0xC6);
+    }
+
+    #[test]
+    fn only_a_line_going_low_requests_the_interrupt() {
+        let mut joypad: Joypad = Joypad::initialize();
+        // not selected, the line stays high
+        assert!(!joypad.set_pressed(Button::Start, true));
+        assert!(joypad.write_register(0x10));
+        assert!(!joypad.write_register(0x10));
+        // the directions aren't selected
+        assert!(!joypad.set_pressed(Button::Down, true));
+        assert!(!joypad.set_pressed(Button::Start, false));
+        assert!(!joypad.set_pressed(Button::Down, false));
+        assert!(joypad.set_pressed(Button::Select, true));
+        assert!(!joypad.write_register(0x30));
+        // Up shares the line Select already holds low
+        joypad.write_register(0x00);
+        assert!(!joypad.set_pressed(Button::Up, true));
+    }
+}
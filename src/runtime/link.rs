@@ -0,0 +1,137 @@
+use super::Runtime;
+
+// two game boys joined by a link cable, run in lock step in one process. whichever machine is
+// behind runs the next instruction, so they're never further apart than one instruction (at most
+// 6 machine cycles). a clock edge from a machine on the internal clock holds its serial port until
+// the other machine has caught up to it, then the bits cross over, so transfers are bit exact
+// and runs are deterministic
+pub struct LinkCable {
+    // the machines can be used directly between steps, for input and reading state, but stepping
+    // them outside the cable lets them drift apart
+    pub first: Runtime,
+    pub second: Runtime,
+    first_start: usize, // each machine's cycle count when the cable went in
+    second_start: usize,
+}
+
+impl LinkCable {
+    pub fn connect(mut first: Runtime, mut second: Runtime) -> LinkCable {
+        first.cpu.memory.serial.set_connected(true);
+        second.cpu.memory.serial.set_connected(true);
+        return LinkCable {
+            first_start: first.cycles(),
+            second_start: second.cycles(),
+            first,
+            second,
+        };
+    }
+
+    pub fn disconnect(mut self) -> (Runtime, Runtime) {
+        self.first.cpu.memory.serial.set_connected(false);
+        self.second.cpu.memory.serial.set_connected(false);
+        return (self.first, self.second);
+    }
+
+    // machine cycles the first machine is ahead of the second since they were connected
+    pub fn skew(&self) -> isize {
+        return self.first_cycles() as isize - self.second_cycles() as isize;
+    }
+
+    fn first_cycles(&self) -> usize {
+        return self.first.cycles() - self.first_start;
+    }
+
+    fn second_cycles(&self) -> usize {
+        return self.second.cycles() - self.second_start;
+    }
+
+    // steps the machine that's behind, returns the machine cycles the pair moved forward
+    pub fn step(&mut self) -> usize {
+        let before: usize = self.first_cycles().min(self.second_cycles());
+        if self.first_cycles() <= self.second_cycles() {
+            self.first.step();
+        } else {
+            self.second.step();
+        }
+        self.exchange(true);
+        self.exchange(false);
+        self.first.cpu.memory.poll_serial();
+        self.second.cpu.memory.poll_serial();
+        return self.first_cycles().min(self.second_cycles()) - before;
+    }
+
+    // steps until both machines have run at least the given cycles, returns the cycles the pair moved
+    pub fn run_cycles(&mut self, cycles: usize) -> usize {
+        let start: usize = self.first_cycles().min(self.second_cycles());
+        let mut moved: usize = 0;
+        while moved < cycles {
+            self.step();
+            moved = self.first_cycles().min(self.second_cycles()) - start;
+        }
+        return moved;
+    }
+
+    // runs until the first machine finishes a frame, or a frame's worth of cycles if its lcd is off
+    pub fn run_frame(&mut self) -> usize {
+        let frame: usize = self.first.frame_count();
+        let start: usize = self.first_cycles();
+        let limit: usize = self.first.frame_cycles();
+        while self.first.frame_count() == frame && self.first_cycles() - start < limit {
+            self.step();
+        }
+        return self.first_cycles() - start;
+    }
+
+    // hands a waiting clock edge from one machine across to the other
+    fn exchange(&mut self, first_is_master: bool) {
+        let (master, master_start, slave, slave_start) = if first_is_master {
+            (&mut self.first, self.first_start, &mut self.second, self.second_start)
+        } else {
+            (&mut self.second, self.second_start, &mut self.first, self.first_start)
+        };
+        let Some(outgoing) = master.cpu.memory.serial.outgoing_bit() else { return; };
+
+        // the edge happened at the master's time, the slave has to get there first
+        while slave.cycles() - slave_start < master.cycles() - master_start && slave.cpu.memory.serial.outgoing_bit().is_none() {
+            slave.step();
+        }
+        if let Some(slave_outgoing) = slave.cpu.memory.serial.outgoing_bit() {
+            // both ends on the internal clock, each takes the other's bit
+            slave.cpu.memory.serial.complete_bit(outgoing);
+            master.cpu.memory.serial.complete_bit(slave_outgoing);
+            return;
+        }
+        // a slave that isn't mid transfer doesn't shift, and the line stays high
+        let incoming: bool = slave.cpu.memory.serial.external_clock(outgoing).unwrap_or(true);
+        master.cpu.memory.serial.complete_bit(incoming);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cpu::memory::INTERRUPT_REQUEST_REGISTER;
+    use super::super::serial::SERIAL_DATA_REGISTER;
+
+    // LD A,data / LDH (0x01),A / LD A,control / LDH (0x02),A / JR -2
+    fn transfer_rom(data: u8, control: u8) -> Runtime {
+        let mut rom: Vec<u8> = vec![0x00; 0x8000];
+        rom[0x0100..0x010A].copy_from_slice(&[0x3E, data, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02, 0x18, 0xFE]);
+        return Runtime::from_rom_bytes(rom).unwrap();
+    }
+
+    #[test]
+    fn a_master_and_a_slave_swap_bytes() {
+        let mut cable: LinkCable = LinkCable::connect(transfer_rom(0x12, 0x81), transfer_rom(0x34, 0x80));
+        let mut moved: usize = 0;
+        while moved < 2000 {
+            moved += cable.step();
+            assert!(cable.skew().abs() <= 6);
+        }
+        let (first, second) = cable.disconnect();
+        assert_eq!(first.read_byte(SERIAL_DATA_REGISTER), 0x34);
+        assert_eq!(second.read_byte(SERIAL_DATA_REGISTER), 0x12);
+        assert_ne!(first.read_byte(INTERRUPT_REQUEST_REGISTER) & 0x08, 0);
+        assert_ne!(second.read_byte(INTERRUPT_REQUEST_REGISTER) & 0x08, 0);
+    }
+}
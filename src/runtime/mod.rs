@@ -1,106 +1,407 @@
 pub mod cpu;
+pub mod cartridge;
 pub mod timer_control;
+pub mod timer;
 pub mod interrupt;
+pub mod ppu;
+pub mod joypad;
+pub mod apu;
+pub mod serial;
+pub mod link;
+pub mod tcp_link;
+pub mod printer;
+pub mod test_runner;
+pub mod gbs;
+pub mod save_state;
 
 use cpu::CPU;
-use timer_control::*;
+use cpu::memory::{Bus, Memory};
+use cpu::registers::Registers;
+use cartridge::header::CartridgeHeader;
+use cartridge::rtc::RtcClock;
+use ppu::Renderer;
+use joypad::Button;
+use serial::SerialPeripheral;
+use apu::{VgmLogger, VgmTags};
+use save_state::{SaveState, StateError, StateReader, StateWriter};
 use interrupt::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use self::cpu::memory::{TIMER_MODULO_REGISTER, TIMER_REGISTER, TIMER_CONTROL_REGISTER, INTERRUPT_REQUEST_REGISTER, INTERRUPT_ENABLE_REGISTER};
+use self::cpu::memory::{INTERRUPT_REQUEST_REGISTER, INTERRUPT_ENABLE_REGISTER};
 
 
-const DIV_SPEED: TimerSpeed = TimerSpeed::Clock256;
+// machine cycles in one second of emulated time
+pub const CYCLES_PER_SECOND: usize = 1_048_576;
+// machine cycles in one frame at normal speed, 154 lines of 456 dots
+pub const CYCLES_PER_FRAME: usize = 17_556;
 
 pub struct Runtime {
-    cpu: CPU,
+    cpu: CPU<Memory>,
     step_counter: usize,
-    master_interrupt_enabled: bool,
-    delay_timer_reset: bool,
-    old_tma: u8,
-    debug_flag: bool,
+    rom_path: Option<PathBuf>,
+    autosave_interval: Option<usize>, // machine cycles between flushes of dirty save ram
+    last_autosave: usize,
+    serial_peripheral: Option<Box<dyn SerialPeripheral>>,
 }
 
 impl Runtime {
 
-    pub fn initialize(file_name: String) -> Runtime {
+    // fails if the rom can't be read or its cartridge isn't supported
+    pub fn initialize(file_name: String) -> io::Result<Runtime> {
+        let rom_path: PathBuf = PathBuf::from(format!("./{}", file_name));
+        let mut runtime: Runtime = Runtime::from_memory(Memory::initialize(file_name)?);
+        runtime.rom_path = Some(rom_path);
+        return Ok(runtime);
+    }
+
+    // unsupported cartridges are InvalidData errors
+    pub fn from_rom_bytes(rom: Vec<u8>) -> io::Result<Runtime> {
+        return Ok(Runtime::from_memory(Memory::from_bytes(rom)?));
+    }
+
+    fn from_memory(memory: Memory) -> Runtime {
         return Runtime {
-            cpu: CPU::initialize(file_name),
+            cpu: CPU::initialize(memory),
             step_counter: 0,
-            master_interrupt_enabled: false,
-            delay_timer_reset: false,
-            old_tma: 0,
-            debug_flag: false,
+            rom_path: None,
+            autosave_interval: None,
+            last_autosave: 0,
+            serial_peripheral: None,
         }
     }
 
     pub fn run(&mut self) {
         loop {
-            // TODO: Get rid of the old_tma pattern
-            self.old_tma = self.cpu.memory.read_byte(TIMER_MODULO_REGISTER);
-            let steps: usize = self.step_debug();
-            self.handle_timers(steps);
-            self.step_counter += steps;
+            self.step();
         }
     }
 
-    fn handle_timers(&mut self, steps: usize) {
-        // if self.delay_timer_reset && self.cpu.memory.read_byte(TIMER_REGISTER) != 0x00 {
-        //     println!("This is the reset/write timer edge case.");
-        // }
-        
-        if !self.cpu.stopped {
-            let div_increments: u8 = calc_increments(DIV_SPEED, self.step_counter, steps);
-            self.cpu.memory.increment_div(div_increments);
+    // executes a single instruction (and any interrupt dispatch), returns the machine cycles taken
+    pub fn step(&mut self) -> usize {
+        let steps: usize = self.step_debug();
+        if self.cpu.stopped {
+            self.cpu.memory.tick_stopped(steps);
+        } else {
+            self.cpu.memory.tick(steps);
+        }
+        if let Some(peripheral) = &mut self.serial_peripheral {
+            peripheral.update(&mut self.cpu.memory.serial, steps);
+            self.cpu.memory.poll_serial();
         }
+        self.step_counter += steps;
+        self.handle_autosave();
+        return steps;
+    }
 
-        let mut tma: u8 = self.cpu.memory.read_byte(TIMER_MODULO_REGISTER);
-        if self.old_tma != tma {
-            println!("OLD_TMA RELEVANT");
-            tma = self.old_tma;
+    // steps until at least the given number of machine cycles have passed, returns the cycles actually run
+    pub fn run_cycles(&mut self, cycles: usize) -> usize {
+        let start: usize = self.step_counter;
+        while self.step_counter - start < cycles {
+            self.step();
         }
-        let mut tima: u8 = if self.delay_timer_reset {
-            self.cpu.memory.flag_timer_interrrupt();
-            self.delay_timer_reset = false;
-            tma 
-        } else {
-            self.cpu.memory.read_byte(TIMER_REGISTER)
+        return self.step_counter - start;
+    }
+
+    // steps until the condition holds (checked before every step), returns the cycles run
+    pub fn run_until<F: FnMut(&Runtime) -> bool>(&mut self, mut condition: F) -> usize {
+        let start: usize = self.step_counter;
+        while !condition(self) {
+            self.step();
+        }
+        return self.step_counter - start;
+    }
+
+    // total machine cycles run since initialization
+    pub fn cycles(&self) -> usize {
+        return self.step_counter;
+    }
+
+    pub fn cartridge_header(&self) -> &CartridgeHeader {
+        return &self.cpu.memory.cartridge.header;
+    }
+
+    // picks whether an MBC3 clock follows emulated time or the host's wall clock
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.cpu.memory.cartridge.set_rtc_clock(clock);
+    }
+
+    pub fn set_rumble_callback<F: FnMut(bool) + 'static>(&mut self, callback: F) {
+        self.cpu.memory.cartridge.set_rumble_callback(Box::new(callback));
+    }
+
+    // the .sav file next to the rom, if the cartridge has a battery and came from a file
+    pub fn save_path(&self) -> Option<PathBuf> {
+        if !self.cpu.memory.cartridge.has_battery() { return None; }
+        return self.rom_path.as_ref().map(|path| path.with_extension("sav"));
+    }
+
+    // returns whether a save was found
+    pub fn load_battery_save(&mut self) -> io::Result<bool> {
+        match self.save_path() {
+            Some(path) => { return self.cpu.memory.cartridge.load_battery(&path); }
+            None => { return Ok(false); }
+        }
+    }
+
+    pub fn flush_battery_save(&mut self) -> io::Result<()> {
+        match self.save_path() {
+            Some(path) => { return self.cpu.memory.cartridge.save_battery(&path); }
+            None => { return Ok(()); }
+        }
+    }
+
+    // flush the save ram every so many machine cycles if it was written to, None turns this off
+    pub fn set_autosave_interval(&mut self, cycles: Option<usize>) {
+        self.autosave_interval = cycles;
+        self.last_autosave = self.step_counter;
+    }
+
+    fn handle_autosave(&mut self) {
+        let Some(interval) = self.autosave_interval else { return; };
+        if self.step_counter - self.last_autosave < interval { return; }
+        self.last_autosave = self.step_counter;
+        if !self.cpu.memory.cartridge.ram_dirty() { return; }
+        if let Err(error) = self.flush_battery_save() {
+            eprintln!("failed to write save file: {}", error);
+        }
+    }
+
+    // a snapshot of the whole machine, for loading back into a runtime on the same cartridge.
+    // what's plugged in, callbacks, recordings and the renderer choice aren't part of it
+    pub fn save_state(&self) -> Vec<u8> {
+        return save_state::encode(self.cartridge_header(), &self.machine_state());
+    }
+
+    // on an error the machine is left as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let machine: &[u8] = save_state::decode(self.cartridge_header(), data)?;
+        let backup: Vec<u8> = self.machine_state();
+        let ram_dirty: bool = self.cpu.memory.cartridge.ram_dirty();
+        if let Err(error) = self.load_machine_state(machine) {
+            self.load_machine_state(&backup).expect("failed to restore the machine after a bad save state");
+            self.cpu.memory.cartridge.restore_ram_dirty(ram_dirty);
+            return Err(error);
+        }
+        self.cpu.memory.cartridge.state_loaded();
+        return Ok(());
+    }
+
+    pub fn save_state_to(&self, path: &Path) -> io::Result<()> {
+        return fs::write(path, self.save_state());
+    }
+
+    pub fn load_state_from(&mut self, path: &Path) -> Result<(), StateError> {
+        let data: Vec<u8> = fs::read(path)?;
+        return self.load_state(&data);
+    }
+
+    fn machine_state(&self) -> Vec<u8> {
+        let mut state: StateWriter = StateWriter::initialize();
+        state.write_usize(self.step_counter);
+        self.cpu.save_state(&mut state);
+        return state.into_bytes();
+    }
+
+    fn load_machine_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state: StateReader = StateReader::initialize(data);
+        self.step_counter = state.read_usize()?;
+        self.cpu.load_state(&mut state)?;
+        if !state.is_finished() { return Err(StateError::Corrupt); }
+        self.last_autosave = self.step_counter;
+        return Ok(());
+    }
+
+    // runs until the ppu finishes the next frame, returns the cycles run. with the lcd off no frame
+    // ever finishes, so it stops after a frame's worth of cycles either way
+    pub fn run_frame(&mut self) -> usize {
+        let frame: usize = self.frame_count();
+        let start: usize = self.step_counter;
+        let limit: usize = self.frame_cycles();
+        return self.run_until(|runtime| runtime.frame_count() != frame || runtime.step_counter - start >= limit);
+    }
+
+    // a frame in cpu machine cycles, twice as many in double speed
+    pub fn frame_cycles(&self) -> usize {
+        if self.double_speed() { return 2 * CYCLES_PER_FRAME; }
+        return CYCLES_PER_FRAME;
+    }
+
+    // the last frame drawn, SCREEN_WIDTH * SCREEN_HEIGHT shades from 0 (white) to 3 (black)
+    pub fn framebuffer(&self) -> &[u8] {
+        return self.cpu.memory.ppu.framebuffer();
+    }
+
+    // switches how the ppu draws, takes effect from the next line
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.cpu.memory.ppu.set_renderer(renderer);
+    }
+
+    pub fn frame_count(&self) -> usize {
+        return self.cpu.memory.ppu.frame_count();
+    }
+
+    // audio generated since the last drain, interleaved left and right at the audio sample rate
+    pub fn drain_audio(&mut self) -> Vec<i16> {
+        return self.cpu.memory.apu.drain_samples();
+    }
+
+    // like drain_audio but into a caller's buffer, returns the number of samples written
+    pub fn drain_audio_into(&mut self, out: &mut [i16]) -> usize {
+        return self.cpu.memory.apu.drain_samples_into(out);
+    }
+
+    pub fn audio_samples_available(&self) -> usize {
+        return self.cpu.memory.apu.samples_available();
+    }
+
+    pub fn audio_sample_rate(&self) -> usize {
+        return self.cpu.memory.apu.sample_rate();
+    }
+
+    // output samples per second, per side, DEFAULT_SAMPLE_RATE until changed
+    pub fn set_audio_sample_rate(&mut self, sample_rate: usize) {
+        self.cpu.memory.apu.set_sample_rate(sample_rate);
+    }
+
+    // writes everything the apu plays from now on to a 16 bit stereo .wav, independent of draining.
+    // with stems each channel also gets a mono <name>.ch1.wav to <name>.ch4.wav next to it
+    pub fn start_audio_recording(&mut self, path: &Path, stems: bool) -> io::Result<()> {
+        return self.cpu.memory.apu.start_recording(path, stems);
+    }
+
+    pub fn is_recording_audio(&self) -> bool {
+        return self.cpu.memory.apu.is_recording();
+    }
+
+    // the files are only complete once this is called, although a dropped runtime finishes them too
+    pub fn stop_audio_recording(&mut self) -> io::Result<()> {
+        return self.cpu.memory.apu.stop_recording();
+    }
+
+    // logs every sound register write from now on to a .vgm, playable in standard players.
+    // the apu's current settings are written first so a log can start mid game
+    pub fn start_vgm_log(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_vgm_log()?;
+        let tags: VgmTags = VgmTags {
+            track: String::new(),
+            game: self.cartridge_header().title.clone(),
+            system: "Nintendo Game Boy".to_owned(),
+            author: String::new(),
+            date: String::new(),
+            ripper: String::new(),
+            notes: "Logged by DMG-e".to_owned(),
         };
-        let tima_control: TimerControl = TimerControl::from(self.cpu.memory.read_byte(TIMER_CONTROL_REGISTER));
-        if tima_control.enabled {
-            let tima_increments: u8 = calc_increments(tima_control.speed, self.step_counter, steps);
-            let (mut new_tima, overflow) = tima.overflowing_add(tima_increments);
-            if overflow {
-                if new_tima == 0x00 {
-                    self.delay_timer_reset = true;
-                } else {
-                    new_tima = new_tima.wrapping_add(tma);
-                    self.cpu.memory.flag_timer_interrrupt();
-                }
-            }
-            tima = new_tima;
-            self.cpu.write_byte_debug(TIMER_REGISTER, tima);
+        let mut logger: VgmLogger = VgmLogger::create(path, tags)?;
+        for (address, value) in self.cpu.memory.apu.state_writes() {
+            logger.log_write(address, value);
         }
+        self.cpu.memory.vgm_logger = Some(logger);
+        return Ok(());
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        return self.cpu.memory.vgm_logger.is_some();
+    }
+
+    // writes the .vgm out, nothing is on disk until this is called or the runtime is dropped
+    pub fn stop_vgm_log(&mut self) -> io::Result<()> {
+        match self.cpu.memory.vgm_logger.take() {
+            Some(mut logger) => { return logger.finish(); }
+            None => { return Ok(()); }
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.cpu.memory.set_button(button, true);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.cpu.memory.set_button(button, false);
+    }
+
+    pub fn double_speed(&self) -> bool {
+        return self.cpu.memory.double_speed;
     }
 
+    // the callback gets every byte the game sends over the serial port, as each transfer finishes
+    pub fn set_serial_callback<F: FnMut(u8) + 'static>(&mut self, callback: F) {
+        self.cpu.memory.serial.set_callback(Box::new(callback));
+    }
+
+    // plugs something into the serial port in place of the open line
+    pub fn connect_serial(&mut self, peripheral: Box<dyn SerialPeripheral>) {
+        self.cpu.memory.serial.set_connected(true);
+        self.serial_peripheral = Some(peripheral);
+    }
+
+    pub fn disconnect_serial(&mut self) -> Option<Box<dyn SerialPeripheral>> {
+        self.cpu.memory.serial.set_connected(false);
+        return self.serial_peripheral.take();
+    }
+
+    pub fn registers(&self) -> &Registers {
+        return &self.cpu.registers;
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        return &mut self.cpu.registers;
+    }
+
+    pub fn pc(&self) -> u16 {
+        return self.cpu.pc;
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.cpu.pc = pc;
+    }
+
+    pub fn sp(&self) -> u16 {
+        return self.cpu.sp;
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.cpu.sp = sp;
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        return self.cpu.memory.read_byte(address);
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.cpu.write_byte_debug(address, value);
+    }
+
+    // dispatches the highest priority pending interrupt if IME allows it, returns the machine cycles taken
     fn handle_interrupts(&mut self) -> usize {
-        if !self.master_interrupt_enabled { return 0; }
+        if !self.cpu.master_interrupt_enabled || !self.cpu.interrupt_pending() { return 0; }
+        self.cpu.master_interrupt_enabled = false;
+        self.cpu.ei_pending = false;
+
+        // two idle cycles, two cycles pushing pc and one to jump
+        let return_address: u16 = self.cpu.interrupt_return_address();
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        self.cpu.write_byte_debug(self.cpu.sp, (return_address >> 8) as u8);
+
+        // the high byte push can land on IE (sp at 0x0000), so the interrupt is only picked now
         let interrupt: Interrupt = self.check_interrupts();
-        
+
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        self.cpu.write_byte_debug(self.cpu.sp, (return_address & 0xFF) as u8);
+
         match interrupt {
             Interrupt::None => {
-                return 0;
+                // the interrupt was cancelled by the push, the cpu jumps to 0x0000 instead
+                self.cpu.pc = 0x0000;
             }
             _ => {
-                // println!("Interrupted!");
-                self.cpu.call(u16::from(interrupt));
-                self.master_interrupt_enabled = false;
-                self.cpu.master_interrupt_request = false;
-                let interrupt_requests = self.cpu.memory.read_byte(INTERRUPT_REQUEST_REGISTER);
-                self.cpu.write_byte_debug(INTERRUPT_REQUEST_REGISTER, interrupt_requests - u8::from(interrupt));
-                self.debug_flag = true;
-                return 5;
+                self.cpu.pc = u16::from(interrupt);
+                let interrupt_requests: u8 = self.cpu.memory.read_byte(INTERRUPT_REQUEST_REGISTER);
+                self.cpu.write_byte_debug(INTERRUPT_REQUEST_REGISTER, interrupt_requests & !u8::from(interrupt));
             }
         }
+        return 5;
     }
 
     fn check_interrupts(&self) -> Interrupt {
@@ -111,78 +412,307 @@ impl Runtime {
     }
 
     fn step_debug(&mut self) -> usize {
-        // let tma = self.cpu.memory.read_byte(TIMER_MODULO_REGISTER);
-        // let tcr = self.cpu.memory.read_byte(TIMER_CONTROL_REGISTER);
-        // let ifff = self.cpu.memory.read_byte(INTERRUPT_REQUEST_REGISTER);
-        // let ieee = self.cpu.memory.read_byte(INTERRUPT_ENABLE_REGISTER);
-
-        let mut steps: usize = self.cpu.step(self.step_counter);
-        steps += self.handle_interrupts();
-        self.master_interrupt_enabled = self.cpu.master_interrupt_request;
-
-        // if tma != self.cpu.memory.read_byte(TIMER_MODULO_REGISTER) { self.debug_flag = true; }
-        // if tcr != self.cpu.memory.read_byte(TIMER_CONTROL_REGISTER) { self.debug_flag = true; }
-        // if ifff != self.cpu.memory.read_byte(INTERRUPT_REQUEST_REGISTER) { self.debug_flag = true; }
-        // if ieee != self.cpu.memory.read_byte(INTERRUPT_ENABLE_REGISTER) { self.debug_flag = true; }
-
-        if self.cpu.memory.read_byte(0xFF02) == 0x81 {
-            // print!("{:x} ", self.cpu.memory.read_byte(0xFF01));
-            print!("{}", std::str::from_utf8(&[self.cpu.memory.read_byte(0xFF01)]).unwrap());
-            self.cpu.write_byte_debug(0xFF02, 0x00);
-        }
-        
-        if self.debug_flag {
-            // println!("~~~~~~~~~~~~~~~~~~~~~~~~~DEBUG PRINTOUT~~~~~~~~~~~~~~~~~~~~~~~~~");
-            // println!("{}", self.cpu.registers.a);
-            // println!("{}", self.cpu.registers.get_bc());
-            // println!("{}", self.cpu.memory.read_byte(TIMER_MODULO_REGISTER));
-            // println!("{}", self.cpu.memory.read_byte(TIMER_CONTROL_REGISTER));
-            // self.cpu.memory.print_range(INTERRUPT_REQUEST_REGISTER as usize, 1);
-            // self.cpu.memory.print_range(INTERRUPT_ENABLE_REGISTER as usize, 1);
-
-            // steps += self.cpu.step(self.step_counter);
-            // steps += self.handle_interrupts();
-            // self.master_interrupt_enabled = self.cpu.master_interrupt_request;
-            
-            // self.cpu.print_self();
-            // println!("{}", self.cpu.registers.a);
-            // println!("{}", self.step_counter);
-            
-            // self.cpu.memory.print_range(INTERRUPT_REQUEST_REGISTER as usize, 1);
-            // self.cpu.memory.print_range(INTERRUPT_ENABLE_REGISTER as usize, 1);
-            // println!("{}", self.master_interrupt_enabled);
-            // println!("{}", self.cpu.master_interrupt_request);
-            self.debug_flag = !self.debug_flag;
-        }
-
-        // if self.cpu.master_interrupt_request {println!("master interrupt requested");}
-        // if self.master_interrupt_enabled {println!("master interrupt enabled"); self.debug_flag = true;}
-
-        // if self.cpu.pc >= 0xC000 {
-        //     flag = true;
-        // }
-
-        // if self.cpu.pc < 0xC000 && flag {
-        //     flag = false;
-        //     self.step_debug();
-        //     self.cpu.print_self();
-        //     println!("{:x}", self.cpu.pc);
-        // }
-
-        // if self.step_counter % 434505 < 200 && self.step_counter % 20 < 2 && self.cpu.pc >= 0xC000 {
-        //     self.debug_flag = true;
-        // }
-
-        // if self.cpu.pc == 0xc62c || self.cpu.pc == 0xc631 {
-        //     self.cpu.memory.print_range(0xd801 - 50, 100);
-        //     self.cpu.memory.print_range(0xcb23 - 10, 20);
-        //     self.cpu.memory.print_range(0xc62c - 10, 20);
-        //     self.cpu.memory.print_range(0xdd00, 4);
-        //     self.cpu.memory.print_range(0xc671 - 10, 20);
-        //     self.cpu.memory.print_range(0x0000, 40);
-        //     println!("^^ output ^^");
-        // }
+        let mut steps: usize = 0;
+        if self.cpu.halted {
+            // the cpu idles a cycle at a time while everything else keeps running
+            if !self.cpu.interrupt_pending() { return 1; }
+            // any requested and enabled interrupt wakes the cpu, IME only decides if it gets serviced
+            self.cpu.halted = false;
+            steps += 1;
+        }
+
+        if self.cpu.stopped {
+            // only a selected button going low brings the clock back
+            if self.cpu.memory.joypad.low_lines() == 0 { return 1; }
+            self.cpu.stopped = false;
+        }
+
+        // interrupts are checked between instructions, a dispatch takes the place of the next one
+        let dispatch_steps: usize = self.handle_interrupts();
+        if dispatch_steps > 0 { return steps + dispatch_steps; }
+
+        steps += self.cpu.step(self.step_counter);
+        if self.cpu.stopped && self.cpu.memory.speed_switch_armed {
+            // an armed KEY1 turns STOP into a speed switch instead of a sleep
+            self.cpu.memory.switch_speed();
+            self.cpu.stopped = false;
+        }
 
         return steps;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // a rom only cartridge running the given code from 0x0100
+    fn rom_with_code(code: &[u8]) -> Vec<u8> {
+        let mut rom: Vec<u8> = vec![0x00; 0x8000];
+        rom[0x0100..0x0100 + code.len()].copy_from_slice(code);
+        return rom;
+    }
+
+    #[test]
+    fn run_frame_returns_with_the_lcd_off() {
+        // LD A,0 / LDH (0x40),A / JR -2
+        let mut runtime: Runtime = Runtime::from_rom_bytes(rom_with_code(&[0x3E, 0x00, 0xE0, 0x40, 0x18, 0xFE])).unwrap();
+        runtime.run_cycles(10);
+        assert!(!runtime.cpu.memory.ppu.lcd_enabled());
+        let frame: usize = runtime.frame_count();
+        let cycles: usize = runtime.run_frame();
+        assert_eq!(runtime.frame_count(), frame);
+        assert!((CYCLES_PER_FRAME..CYCLES_PER_FRAME + 8).contains(&cycles));
+    }
+
+    #[test]
+    fn run_frame_stops_at_the_next_frame() {
+        // JR -2
+        let mut runtime: Runtime = Runtime::from_rom_bytes(rom_with_code(&[0x18, 0xFE])).unwrap();
+        runtime.run_frame();
+        let frame: usize = runtime.frame_count();
+        let cycles: usize = runtime.run_frame();
+        assert_eq!(runtime.frame_count(), frame + 1);
+        assert!(cycles.abs_diff(CYCLES_PER_FRAME) < 8);
+    }
+
+    // TIMA read back after resetting DIV, clearing TIMA and waiting the given NOPs
+    fn tima_after_nops(nops: usize) -> u8 {
+        // LD A,5 / LDH (0x07),A / LD C,0x04 / LD (C),A / XOR A / LDH (0x05),A
+        let mut code: Vec<u8> = vec![0x3E, 0x05, 0xE0, 0x07, 0x0E, 0x04, 0xE2, 0xAF, 0xE0, 0x05];
+        code.resize(code.len() + nops, 0x00);
+        // LDH A,(0x05) / JR -2
+        code.extend_from_slice(&[0xF0, 0x05, 0x18, 0xFE]);
+        let end: u16 = 0x0100 + code.len() as u16 - 2;
+        let mut runtime: Runtime = Runtime::from_rom_bytes(rom_with_code(&code)).unwrap();
+        runtime.run_until(|runtime| runtime.pc() == end);
+        return runtime.registers().a;
+    }
+
+    #[test]
+    fn timer_registers_are_accessed_on_their_own_cycle() {
+        // with DIV reset by LD (C),A the TIMA write in the last cycle of LDH lands just after an increment,
+        // the read in the last cycle of the other LDH comes 3 + nops cycles after it
+        assert_eq!(tima_after_nops(0), 0);
+        assert_eq!(tima_after_nops(1), 1);
+        assert_eq!(tima_after_nops(4), 1);
+        assert_eq!(tima_after_nops(5), 2);
+    }
+
+    #[test]
+    fn the_timer_restarts_in_step_after_stop() {
+        // LD A,5 / LDH (0x07),A / XOR A / LDH (0x00),A / STOP
+        let mut code: Vec<u8> = vec![0x3E, 0x05, 0xE0, 0x07, 0xAF, 0xE0, 0x00, 0x10, 0x00];
+        let woken: u16 = 0x0100 + code.len() as u16;
+        // LDH A,(0x04) / LD B,A / XOR A / LDH (0x05),A / INC DE / LDH A,(0x05) / JR -2
+        code.extend_from_slice(&[0xF0, 0x04, 0x47, 0xAF, 0xE0, 0x05, 0x13, 0xF0, 0x05, 0x18, 0xFE]);
+        let end: u16 = 0x0100 + code.len() as u16 - 2;
+        let mut runtime: Runtime = Runtime::from_rom_bytes(rom_with_code(&code)).unwrap();
+        runtime.run_until(|runtime| runtime.pc() == woken);
+        runtime.run_cycles(1000);
+        assert_eq!(runtime.pc(), woken);
+        runtime.press(Button::Start);
+        runtime.run_until(|runtime| runtime.pc() == end);
+        // STOP left DIV at 0, then TIMA is cleared 7 cycles after it and read 5 cycles later
+        assert_eq!(runtime.registers().b, 0);
+        assert_eq!(runtime.registers().a, 2);
+    }
+
+    // DI, then the timer interrupt enabled and requested
+    const TIMER_PENDING: [u8; 7] = [0xF3, 0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F];
+
+    // the code at 0x0100 with JR -2 at each rst and interrupt vector
+    fn vectored_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom: Vec<u8> = rom_with_code(code);
+        for vector in (0x00..=0x60).step_by(8) {
+            rom[vector..vector + 2].copy_from_slice(&[0x18, 0xFE]);
+        }
+        return rom;
+    }
+
+    fn run_rom_to(rom: Vec<u8>, address: u16) -> Runtime {
+        let mut runtime: Runtime = Runtime::from_rom_bytes(rom).unwrap();
+        runtime.run_until(|runtime| runtime.pc() == address);
+        return runtime;
+    }
+
+    // runs the code until pc reaches the given address
+    fn run_to(code: &[u8], address: u16) -> Runtime {
+        return run_rom_to(vectored_rom(code), address);
+    }
+
+    // the 16 bit value at the top of the stack
+    fn top_of_stack(runtime: &Runtime) -> u16 {
+        return u16::from_le_bytes([runtime.read_byte(runtime.sp()), runtime.read_byte(runtime.sp() + 1)]);
+    }
+
+    #[test]
+    fn halt_with_an_interrupt_pending_and_ime_off_runs_the_next_byte_twice() {
+        // HALT / INC A / JR -2
+        let mut code: Vec<u8> = TIMER_PENDING.to_vec();
+        code.extend_from_slice(&[0x76, 0x3C, 0x18, 0xFE]);
+        let runtime: Runtime = run_to(&code, 0x0100 + code.len() as u16 - 2);
+        assert_eq!(runtime.registers().a, 0x06);
+        assert!(!runtime.cpu.halted);
+    }
+
+    #[test]
+    fn halt_with_ime_off_wakes_without_a_dispatch() {
+        // DI / LD A,4 / LDH (0xFF),A / XOR A / LDH (0x0F),A / LD A,5 / LDH (0x07),A / HALT / LD B,0x42 / JR -2
+        let code: [u8; 17] = [0xF3, 0x3E, 0x04, 0xE0, 0xFF, 0xAF, 0xE0, 0x0F, 0x3E, 0x05, 0xE0, 0x07, 0x76, 0x06, 0x42, 0x18, 0xFE];
+        let mut runtime: Runtime = run_to(&code, 0x010C);
+        assert!(!runtime.cpu.halted);
+        runtime.step();
+        assert!(runtime.cpu.halted);
+        // TIMA takes 256 increments of 4 cycles to overflow
+        let cycles: usize = runtime.run_until(|runtime| runtime.pc() == 0x010F);
+        assert!(cycles > 1000);
+        assert_eq!(runtime.registers().b, 0x42);
+        assert_eq!(runtime.sp(), 0xFFFE);
+        assert_ne!(runtime.read_byte(INTERRUPT_REQUEST_REGISTER) & 0x04, 0);
+    }
+
+    #[test]
+    fn an_rst_after_the_halt_bug_returns_to_itself() {
+        // HALT / RST 0x38
+        let mut code: Vec<u8> = TIMER_PENDING.to_vec();
+        code.extend_from_slice(&[0x76, 0xFF]);
+        let runtime: Runtime = run_to(&code, 0x0038);
+        assert_eq!(runtime.sp(), 0xFFFC);
+        assert_eq!(top_of_stack(&runtime), 0x0100 + TIMER_PENDING.len() as u16 + 1);
+    }
+
+    #[test]
+    fn an_interrupt_taken_during_the_halt_bug_returns_to_the_halt() {
+        // EI / HALT / NOP, IME comes on after the HALT has already seen it off
+        let mut code: Vec<u8> = TIMER_PENDING.to_vec();
+        code.extend_from_slice(&[0xFB, 0x76, 0x00]);
+        let runtime: Runtime = run_to(&code, 0x0050);
+        assert_eq!(top_of_stack(&runtime), 0x0100 + TIMER_PENDING.len() as u16 + 1);
+        assert_eq!(runtime.read_byte(INTERRUPT_REQUEST_REGISTER) & 0x04, 0);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI / INC A / INC A / JR -2
+        let mut code: Vec<u8> = TIMER_PENDING.to_vec();
+        code.extend_from_slice(&[0xFB, 0x3C, 0x3C, 0x18, 0xFE]);
+        let runtime: Runtime = run_to(&code, 0x0050);
+        assert_eq!(runtime.registers().a, 0x05);
+        assert_eq!(top_of_stack(&runtime), 0x0100 + TIMER_PENDING.len() as u16 + 2);
+    }
+
+    #[test]
+    fn ei_then_di_never_lets_an_interrupt_in() {
+        // EI / DI / LD B,0x42 / JR -2
+        let mut code: Vec<u8> = TIMER_PENDING.to_vec();
+        code.extend_from_slice(&[0xFB, 0xF3, 0x06, 0x42, 0x18, 0xFE]);
+        let mut runtime: Runtime = Runtime::from_rom_bytes(vectored_rom(&code)).unwrap();
+        runtime.run_cycles(100);
+        assert_eq!(runtime.pc(), 0x0100 + code.len() as u16 - 2);
+        assert_eq!(runtime.registers().b, 0x42);
+        assert_eq!(runtime.sp(), 0xFFFE);
+        assert_ne!(runtime.read_byte(INTERRUPT_REQUEST_REGISTER) & 0x04, 0);
+    }
+
+    #[test]
+    fn reti_enables_interrupts_straight_away() {
+        // CALL 0x0200 / INC A / JR -2, with RETI at 0x0200
+        let mut code: Vec<u8> = TIMER_PENDING.to_vec();
+        code.extend_from_slice(&[0xCD, 0x00, 0x02, 0x3C, 0x18, 0xFE]);
+        let mut rom: Vec<u8> = vectored_rom(&code);
+        rom[0x0200] = 0xD9;
+        let runtime: Runtime = run_rom_to(rom, 0x0050);
+        // the interrupt comes in before the INC A
+        assert_eq!(runtime.registers().a, 0x04);
+        assert_eq!(top_of_stack(&runtime), 0x0100 + TIMER_PENDING.len() as u16 + 3);
+    }
+
+    #[test]
+    fn pushing_over_ie_can_cancel_the_interrupt() {
+        // LD SP,0x0000 / EI / NOP / JR -2
+        let mut code: Vec<u8> = vec![0x31, 0x00, 0x00];
+        code.extend_from_slice(&TIMER_PENDING);
+        code.extend_from_slice(&[0xFB, 0x00, 0x18, 0xFE]);
+        let runtime: Runtime = run_to(&code, 0x0000);
+        // the high byte of 0x010C lands on IE, which leaves the timer disabled, so the cpu ends up at 0x0000
+        assert_eq!(runtime.read_byte(INTERRUPT_ENABLE_REGISTER), 0x01);
+        assert_eq!(runtime.read_byte(0xFFFE), 0x0C);
+        assert_eq!(runtime.sp(), 0xFFFE);
+        assert_ne!(runtime.read_byte(INTERRUPT_REQUEST_REGISTER) & 0x04, 0);
+    }
+
+    // JR -2 on an MBC5+RUMBLE cartridge
+    fn rumble_runtime() -> Runtime {
+        let mut rom: Vec<u8> = rom_with_code(&[0x18, 0xFE]);
+        rom[cartridge::header::CARTRIDGE_TYPE_ADDRESS] = 0x1C;
+        return Runtime::from_rom_bytes(rom).unwrap();
+    }
+
+    #[test]
+    fn a_loaded_state_saves_the_same_bytes() {
+        let mut runtime: Runtime = rumble_runtime();
+        runtime.run_cycles(CYCLES_PER_FRAME + 100);
+        let state: Vec<u8> = runtime.save_state();
+
+        let mut loaded: Runtime = rumble_runtime();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+        // and both run on the same
+        runtime.run_cycles(1000);
+        loaded.run_cycles(1000);
+        assert_eq!(loaded.save_state(), runtime.save_state());
+    }
+
+    #[test]
+    fn states_for_another_cartridge_are_rejected() {
+        let state: Vec<u8> = rumble_runtime().save_state();
+        let mut runtime: Runtime = Runtime::from_rom_bytes(rom_with_code(&[0x18, 0xFE])).unwrap();
+        assert!(matches!(runtime.load_state(&state), Err(StateError::WrongCartridge(_))));
+    }
+
+    #[test]
+    fn a_failed_load_leaves_the_machine_and_the_motor_alone() {
+        let mut source: Runtime = rumble_runtime();
+        source.write_byte(0x4000, 0x08);
+        source.run_cycles(100);
+        // cut short after the cartridge, with a checksum that matches so it gets as far as loading
+        let mut machine: Vec<u8> = source.machine_state();
+        machine.pop();
+        let bad: Vec<u8> = save_state::encode(source.cartridge_header(), &machine);
+
+        let mut runtime: Runtime = rumble_runtime();
+        let rumbles: Rc<RefCell<Vec<bool>>> = Default::default();
+        let heard: Rc<RefCell<Vec<bool>>> = rumbles.clone();
+        runtime.set_rumble_callback(move |rumble| heard.borrow_mut().push(rumble));
+        runtime.run_cycles(50);
+        let before: Vec<u8> = runtime.save_state();
+        assert!(matches!(runtime.load_state(&bad), Err(StateError::Corrupt)));
+        assert_eq!(runtime.save_state(), before);
+        assert!(rumbles.borrow().is_empty());
+
+        runtime.load_state(&source.save_state()).unwrap();
+        assert_eq!(*rumbles.borrow(), vec![true]);
+    }
+
+    #[test]
+    fn a_failed_load_leaves_the_save_clean() {
+        // JR -2 on MBC1+RAM+BATTERY
+        let mut rom: Vec<u8> = rom_with_code(&[0x18, 0xFE]);
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let mut source: Runtime = Runtime::from_rom_bytes(rom.clone()).unwrap();
+        source.write_byte(0x0000, 0x0A);
+        source.write_byte(0xA000, 0x42);
+        let mut machine: Vec<u8> = source.machine_state();
+        machine.pop();
+        let bad: Vec<u8> = save_state::encode(source.cartridge_header(), &machine);
+
+        let mut runtime: Runtime = Runtime::from_rom_bytes(rom).unwrap();
+        assert!(runtime.load_state(&bad).is_err());
+        assert!(!runtime.cpu.memory.cartridge.ram_dirty());
+        runtime.load_state(&source.save_state()).unwrap();
+        assert!(runtime.cpu.memory.cartridge.ram_dirty());
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use super::*;
+
+// each fetcher step takes 2 dots, an object fetch takes 6
+const FETCH_STEP_DOTS: u8 = 2;
+const OBJECT_FETCH_DOTS: u8 = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Clone, Copy)]
+struct ObjectPixel {
+    colour: u8,
+    attributes: u8,
+}
+
+// the state of mode 3 when drawing a pixel at a time, so mid line writes land where they would on hardware
+pub(super) struct PixelFifo {
+    background: VecDeque<u8>,
+    objects: VecDeque<ObjectPixel>,
+    step: FetchStep,
+    step_dots: u8,
+    tile_x: u8, // tiles fetched so far on this line, counted from the left of the background or the window
+    tile_index: u8,
+    data_low: u8,
+    data_high: u8,
+    first_fetch: bool, // the first tile of a line is fetched twice
+    window: bool, // fetching from the window map
+    x: usize, // pixels sent to the screen
+    discard: u8, // pixels still to throw away for SCX fine scrolling or a window left of the screen
+    sprites: Vec<Sprite>, // objects on this line not fetched yet, in priority order
+    object_dots: u8, // dots left in the current object fetch
+    object: Option<Sprite>,
+}
+
+impl PixelFifo {
+    pub(super) fn initialize() -> PixelFifo {
+        return PixelFifo {
+            background: VecDeque::with_capacity(16),
+            objects: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            tile_x: 0,
+            tile_index: 0,
+            data_low: 0,
+            data_high: 0,
+            first_fetch: true,
+            window: false,
+            x: 0,
+            discard: 0,
+            sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            object_dots: 0,
+            object: None,
+        };
+    }
+
+    // true once all 160 pixels are out, that's the end of mode 3
+    pub(super) fn finished(&self) -> bool {
+        return self.x == SCREEN_WIDTH;
+    }
+
+    pub(super) fn window_drawn(&self) -> bool {
+        return self.window;
+    }
+
+    fn restart_fetch(&mut self) {
+        self.step = FetchStep::Tile;
+        self.step_dots = 0;
+    }
+}
+
+impl SaveState for PixelFifo {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.background.iter().copied().collect::<Vec<u8>>());
+        state.write_usize(self.objects.len());
+        for pixel in &self.objects {
+            state.write_u8(pixel.colour);
+            state.write_u8(pixel.attributes);
+        }
+        state.write_u8(self.step as u8);
+        state.write_u8(self.step_dots);
+        state.write_u8(self.tile_x);
+        state.write_u8(self.tile_index);
+        state.write_u8(self.data_low);
+        state.write_u8(self.data_high);
+        state.write_bool(self.first_fetch);
+        state.write_bool(self.window);
+        state.write_usize(self.x);
+        state.write_u8(self.discard);
+        state.write_usize(self.sprites.len());
+        for sprite in &self.sprites {
+            sprite.save_state(state);
+        }
+        state.write_u8(self.object_dots);
+        state.write_bool(self.object.is_some());
+        self.object.unwrap_or_default().save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.background.clear();
+        self.background.extend(state.read_bytes()?);
+        let objects: usize = state.read_usize()?;
+        if self.background.len() > 16 || objects > 8 { return Err(StateError::Invalid("pixel fifo")); }
+        self.objects.clear();
+        for _ in 0..objects {
+            let colour: u8 = state.read_u8()?;
+            let attributes: u8 = state.read_u8()?;
+            self.objects.push_back(ObjectPixel { colour, attributes });
+        }
+        match state.read_u8()? {
+            0 => { self.step = FetchStep::Tile; }
+            1 => { self.step = FetchStep::DataLow; }
+            2 => { self.step = FetchStep::DataHigh; }
+            3 => { self.step = FetchStep::Push; }
+            _ => { return Err(StateError::Invalid("fetcher step")); }
+        }
+        self.step_dots = state.read_u8()?;
+        self.tile_x = state.read_u8()?;
+        self.tile_index = state.read_u8()?;
+        self.data_low = state.read_u8()?;
+        self.data_high = state.read_u8()?;
+        self.first_fetch = state.read_bool()?;
+        self.window = state.read_bool()?;
+        self.x = state.read_usize()?;
+        self.discard = state.read_u8()?;
+        let sprites: usize = state.read_usize()?;
+        if self.x > SCREEN_WIDTH || sprites > SPRITES_PER_LINE { return Err(StateError::Invalid("pixel fifo")); }
+        self.sprites.clear();
+        for _ in 0..sprites {
+            let mut sprite: Sprite = Sprite::default();
+            sprite.load_state(state)?;
+            self.sprites.push(sprite);
+        }
+        self.object_dots = state.read_u8()?;
+        let fetching_object: bool = state.read_bool()?;
+        let mut object: Sprite = Sprite::default();
+        object.load_state(state)?;
+        self.object = if fetching_object { Some(object) } else { None };
+        return Ok(());
+    }
+}
+
+impl PPU {
+    pub(super) fn start_fifo_line(&mut self) {
+        let fifo: &mut PixelFifo = &mut self.fifo;
+        fifo.background.clear();
+        fifo.objects.clear();
+        fifo.restart_fetch();
+        fifo.tile_x = 0;
+        fifo.first_fetch = true;
+        fifo.window = false;
+        fifo.x = 0;
+        fifo.discard = self.scx % 8;
+        fifo.object = None;
+        fifo.object_dots = 0;
+        // smaller x first, a stable sort leaves ties in OAM order
+        fifo.sprites.clear();
+        fifo.sprites.extend_from_slice(&self.line_sprites[..self.line_sprite_count]);
+        fifo.sprites.sort_by_key(|sprite| sprite.x);
+    }
+
+    // one dot of mode 3
+    pub(super) fn step_fifo(&mut self) {
+        if self.fifo.object.is_some() {
+            self.step_object_fetch();
+            return;
+        }
+
+        if self.fifo.discard == 0 && self.object_due() {
+            // the object fetch waits for the background fetcher to finish the tile it's on
+            if !self.fetcher_ready() { self.step_fetcher(); }
+            if self.fetcher_ready() {
+                self.fifo.object = Some(self.fifo.sprites.remove(0));
+                self.fifo.object_dots = OBJECT_FETCH_DOTS;
+                self.step_object_fetch();
+            }
+            return;
+        }
+
+        if self.window_starts() {
+            self.fifo.window = true;
+            self.fifo.background.clear();
+            self.fifo.restart_fetch();
+            self.fifo.tile_x = 0;
+            if self.wx < 7 { self.fifo.discard = 7 - self.wx; }
+        }
+
+        self.step_fetcher();
+        self.shift_pixel();
+    }
+
+    fn object_due(&self) -> bool {
+        if self.lcdc & LCDC_OBJECT_ENABLE == 0 { return false; }
+        match self.fifo.sprites.first() {
+            Some(sprite) => { return sprite.x as usize <= self.fifo.x + 8; }
+            None => { return false; }
+        }
+    }
+
+    fn fetcher_ready(&self) -> bool {
+        return self.fifo.step == FetchStep::Push && !self.fifo.background.is_empty();
+    }
+
+    fn window_starts(&self) -> bool {
+        if self.fifo.window || self.lcdc & LCDC_WINDOW_ENABLE == 0 || !self.window_triggered { return false; }
+        if self.wx < 7 { return self.fifo.x == 0; }
+        return self.fifo.x + 7 == self.wx as usize;
+    }
+
+    fn step_fetcher(&mut self) {
+        if self.fifo.step == FetchStep::Push {
+            if !self.fifo.background.is_empty() { return; }
+            for bit in (0..8).rev() {
+                let colour: u8 = ((self.fifo.data_high >> bit) & 1) << 1 | ((self.fifo.data_low >> bit) & 1);
+                self.fifo.background.push_back(colour);
+            }
+            self.fifo.tile_x = self.fifo.tile_x.wrapping_add(1);
+            // pushing overlaps with the first dot of the next fetch
+            self.fifo.restart_fetch();
+        }
+
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < FETCH_STEP_DOTS { return; }
+        self.fifo.step_dots = 0;
+
+        match self.fifo.step {
+            FetchStep::Tile => {
+                self.fifo.tile_index = self.read_vram(self.fetch_map_address());
+                self.fifo.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                self.fifo.data_low = self.read_vram(self.fetch_data_address());
+                self.fifo.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                self.fifo.data_high = self.read_vram(self.fetch_data_address() + 1);
+                if self.fifo.first_fetch {
+                    // the first fetch of a line is thrown away and done again
+                    self.fifo.first_fetch = false;
+                    self.fifo.restart_fetch();
+                } else {
+                    self.fifo.step = FetchStep::Push;
+                }
+            }
+            FetchStep::Push => {}
+        }
+    }
+
+    fn fetch_map_address(&self) -> u16 {
+        if self.fifo.window {
+            let map: u16 = if self.lcdc & LCDC_WINDOW_MAP != 0 { 0x9C00 } else { 0x9800 };
+            return map + (self.window_line as u16 / 8) * 32 + (self.fifo.tile_x & 31) as u16;
+        }
+        let map: u16 = if self.lcdc & LCDC_BACKGROUND_MAP != 0 { 0x9C00 } else { 0x9800 };
+        let y: u8 = self.ly.wrapping_add(self.scy);
+        let x: u8 = (self.scx / 8).wrapping_add(self.fifo.tile_x) & 31;
+        return map + (y as u16 / 8) * 32 + x as u16;
+    }
+
+    fn fetch_data_address(&self) -> u16 {
+        let row: u8 = if self.fifo.window { self.window_line % 8 } else { self.ly.wrapping_add(self.scy) % 8 };
+        return self.tile_address(self.fifo.tile_index) + row as u16 * 2;
+    }
+
+    fn step_object_fetch(&mut self) {
+        self.fifo.object_dots -= 1;
+        if self.fifo.object_dots > 0 { return; }
+        let Some(sprite) = self.fifo.object.take() else { return; };
+
+        let height: u8 = self.sprite_height();
+        let mut row: u8 = (self.ly + 16).wrapping_sub(sprite.y) & (height - 1);
+        if sprite.attributes & ATTRIBUTE_Y_FLIP != 0 { row = height - 1 - row; }
+        let tile: u8 = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        let address: u16 = 0x8000 + tile as u16 * 16 + row as u16 * 2;
+
+        for column in 0..8u8 {
+            // objects hanging off the left edge lose the columns already behind x
+            let position: usize = sprite.x as usize + column as usize;
+            if position < self.fifo.x + 8 { continue; }
+            let index: usize = position - self.fifo.x - 8;
+
+            let flipped: u8 = if sprite.attributes & ATTRIBUTE_X_FLIP != 0 { 7 - column } else { column };
+            let pixel: ObjectPixel = ObjectPixel {
+                colour: self.tile_pixel(address, flipped),
+                attributes: sprite.attributes,
+            };
+            // an opaque pixel already queued belongs to a higher priority object
+            if index < self.fifo.objects.len() {
+                if self.fifo.objects[index].colour == 0 { self.fifo.objects[index] = pixel; }
+            } else {
+                self.fifo.objects.push_back(pixel);
+            }
+        }
+    }
+
+    fn shift_pixel(&mut self) {
+        let Some(mut colour) = self.fifo.background.pop_front() else { return; };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        // on the dmg a disabled background takes the window with it and shows colour 0
+        if self.lcdc & LCDC_BACKGROUND_ENABLE == 0 { colour = 0; }
+        let mut shade: u8 = palette_shade(self.bgp, colour);
+
+        if let Some(object) = self.fifo.objects.pop_front() {
+            let visible: bool = object.colour != 0 && self.lcdc & LCDC_OBJECT_ENABLE != 0;
+            if visible && (object.attributes & ATTRIBUTE_BEHIND_BACKGROUND == 0 || colour == 0) {
+                let palette: u8 = if object.attributes & ATTRIBUTE_PALETTE != 0 { self.obp1 } else { self.obp0 };
+                shade = palette_shade(palette, object.colour);
+            }
+        }
+
+        self.framebuffer[self.ly as usize * SCREEN_WIDTH + self.fifo.x] = shade;
+        self.fifo.x += 1;
+    }
+}
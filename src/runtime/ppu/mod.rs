@@ -0,0 +1,619 @@
+mod scanline;
+mod fifo;
+
+use fifo::PixelFifo;
+
+use super::save_state::{SaveState, StateError, StateReader, StateWriter};
+use super::cpu::memory::{
+    VRAM_START, OAM_START, LCD_CONTROL_REGISTER, LCD_STATUS_REGISTER, SCROLL_Y_REGISTER, SCROLL_X_REGISTER,
+    LCD_Y_REGISTER, LCD_Y_COMPARE_REGISTER, BACKGROUND_PALETTE_REGISTER, OBJECT_PALETTE_0_REGISTER,
+    OBJECT_PALETTE_1_REGISTER, WINDOW_Y_REGISTER, WINDOW_X_REGISTER,
+};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// a line is 456 dots, 4 dots to a machine cycle, mode 3 is 172 dots at the least
+const DOTS_PER_CYCLE: usize = 4;
+const DOTS_PER_LINE: usize = 456;
+const OAM_SCAN_DOTS: usize = 80;
+const DRAWING_DOTS: usize = 172;
+const LINES_PER_FRAME: u8 = 154;
+const SPRITES_PER_LINE: usize = 10;
+
+// LCDC bits
+const LCDC_BACKGROUND_ENABLE: u8 = 1 << 0;
+const LCDC_OBJECT_ENABLE: u8 = 1 << 1;
+const LCDC_OBJECT_SIZE: u8 = 1 << 2;
+const LCDC_BACKGROUND_MAP: u8 = 1 << 3;
+const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+const LCDC_WINDOW_MAP: u8 = 1 << 6;
+const LCDC_LCD_ENABLE: u8 = 1 << 7;
+
+// STAT bits
+const STAT_COINCIDENCE: u8 = 1 << 2;
+const STAT_HBLANK_SOURCE: u8 = 1 << 3;
+const STAT_VBLANK_SOURCE: u8 = 1 << 4;
+const STAT_OAM_SOURCE: u8 = 1 << 5;
+const STAT_COINCIDENCE_SOURCE: u8 = 1 << 6;
+const STAT_WRITABLE: u8 = 0x78;
+
+// attribute bits of an OAM entry
+const ATTRIBUTE_PALETTE: u8 = 1 << 4;
+const ATTRIBUTE_X_FLIP: u8 = 1 << 5;
+const ATTRIBUTE_Y_FLIP: u8 = 1 << 6;
+const ATTRIBUTE_BEHIND_BACKGROUND: u8 = 1 << 7;
+
+// how mode 3 is drawn
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Renderer {
+    Scanline, // the whole line at once with a fixed length mode 3, cheap
+    PixelFifo, // a dot at a time through the fetcher and fifos, mode 3 length varies like on hardware
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+// an OAM entry picked for the current line
+#[derive(Clone, Copy, Default)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+}
+
+// the lcd controller, owns vram and oam and draws into a buffer of shades (0 is white, 3 is black)
+pub struct PPU {
+    vram: [u8; 0x2000],
+    oam: [u8; 0xA0],
+    framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    lcdc: u8,
+    stat: u8, // only the interrupt source bits, the rest is derived
+    stat_line: bool, // the interrupt sources ORed together
+    coincidence: bool, // LY matched LYC at the last compare
+    vblank_oam_edge: bool, // entering vblank also counts as mode 2 for the oam source
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    line: u8, // the line being drawn, LY only differs on line 153
+    dot: usize, // dots into the current line
+    lcd_warming_up: bool, // first line after the lcd was switched on
+    blank_frame: bool, // first frame after the lcd was switched on
+    window_line: u8, // the window keeps its own line counter, it only advances on lines it was drawn on
+    window_triggered: bool, // LY matched WY at some point this frame
+    line_sprites: [Sprite; SPRITES_PER_LINE],
+    line_sprite_count: usize,
+    renderer: Renderer,
+    line_renderer: Renderer, // picked up at the start of mode 3 so a switch never lands mid line
+    fifo: PixelFifo,
+    frame_count: usize,
+    pub vblank_requested: bool,
+    pub stat_requested: bool,
+}
+
+impl PPU {
+    pub fn initialize() -> PPU {
+        return PPU {
+            vram: [0; 0x2000],
+            oam: [0; 0xA0],
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            // what the boot rom leaves behind
+            lcdc: 0x91,
+            stat: 0,
+            stat_line: false,
+            coincidence: true,
+            vblank_oam_edge: false,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamScan,
+            line: 0,
+            dot: 0,
+            lcd_warming_up: false,
+            blank_frame: false,
+            window_line: 0,
+            window_triggered: false,
+            line_sprites: [Sprite::default(); SPRITES_PER_LINE],
+            line_sprite_count: 0,
+            renderer: Renderer::Scanline,
+            line_renderer: Renderer::Scanline,
+            fifo: PixelFifo::initialize(),
+            frame_count: 0,
+            vblank_requested: false,
+            stat_requested: false,
+        };
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        return &self.framebuffer;
+    }
+
+    // frames finished since startup, bumped on entering vblank
+    pub fn frame_count(&self) -> usize {
+        return self.frame_count;
+    }
+
+    pub fn mode(&self) -> Mode {
+        return self.mode;
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        return self.lcdc & LCDC_LCD_ENABLE != 0;
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        if !self.lcd_enabled() { return; }
+        for _ in 0..cycles {
+            self.tick_cycle();
+        }
+    }
+
+    fn tick_cycle(&mut self) {
+        for _ in 0..DOTS_PER_CYCLE {
+            self.tick_dot();
+        }
+    }
+
+    fn tick_dot(&mut self) {
+        self.dot += 1;
+        match self.mode {
+            Mode::OamScan => {
+                if self.dot == OAM_SCAN_DOTS { self.start_drawing(); }
+            }
+            Mode::Drawing => {
+                match self.line_renderer {
+                    Renderer::Scanline => {
+                        if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS {
+                            self.render_scanline();
+                            self.set_mode(Mode::HBlank);
+                        }
+                    }
+                    Renderer::PixelFifo => {
+                        self.step_fifo();
+                        if self.fifo.finished() {
+                            if self.fifo.window_drawn() { self.window_line += 1; }
+                            self.set_mode(Mode::HBlank);
+                        }
+                    }
+                }
+            }
+            Mode::HBlank | Mode::VBlank => {
+                if self.lcd_warming_up && self.dot == OAM_SCAN_DOTS {
+                    // the first line after switching the lcd on has no oam scan, it sits in mode 0 instead
+                    self.lcd_warming_up = false;
+                    self.start_drawing();
+                } else if self.dot == DOTS_PER_CYCLE && self.mode == Mode::VBlank {
+                    if self.line == LINES_PER_FRAME - 1 {
+                        // LY only reads 153 for the first cycle of the last line, then it already reads 0
+                        self.ly = 0;
+                        self.compare_ly();
+                    }
+                    // drops the mode 2 source that was raised for a cycle on entering vblank
+                    self.update_stat_line();
+                } else if self.dot == DOTS_PER_LINE {
+                    self.dot = 0;
+                    self.next_line();
+                }
+            }
+        }
+    }
+
+    fn next_line(&mut self) {
+        self.line += 1;
+        if self.line == LINES_PER_FRAME {
+            self.line = 0;
+            self.window_line = 0;
+            self.window_triggered = false;
+        }
+        self.ly = self.line;
+
+        if self.line as usize == SCREEN_HEIGHT {
+            self.frame_count += 1;
+            self.vblank_requested = true;
+            if self.blank_frame {
+                // nothing reaches the screen in the frame after the lcd is switched on
+                self.blank_frame = false;
+                self.framebuffer.fill(0);
+            }
+            self.mode = Mode::VBlank;
+            // the oam source sees the start of line 144 as mode 2 as well
+            self.vblank_oam_edge = true;
+            self.compare_ly();
+            self.vblank_oam_edge = false;
+        } else if (self.line as usize) < SCREEN_HEIGHT {
+            self.mode = Mode::OamScan;
+            self.compare_ly();
+            if self.ly == self.wy { self.window_triggered = true; }
+        } else {
+            self.compare_ly();
+        }
+    }
+
+    fn start_drawing(&mut self) {
+        self.scan_oam();
+        self.line_renderer = self.renderer;
+        if self.line_renderer == Renderer::PixelFifo { self.start_fifo_line(); }
+        self.set_mode(Mode::Drawing);
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.update_stat_line();
+    }
+
+    fn compare_ly(&mut self) {
+        self.coincidence = self.ly == self.lyc;
+        self.update_stat_line();
+    }
+
+    // the four interrupt sources are ORed into one line and only its rising edge requests an interrupt,
+    // so a source going high while another is already holding the line up is lost
+    fn update_stat_line(&mut self) {
+        let mut line: bool = false;
+        if self.lcd_enabled() {
+            let sources: u8 = self.stat;
+            line |= sources & STAT_HBLANK_SOURCE != 0 && self.mode == Mode::HBlank;
+            line |= sources & STAT_VBLANK_SOURCE != 0 && self.mode == Mode::VBlank;
+            line |= sources & STAT_OAM_SOURCE != 0 && (self.mode == Mode::OamScan || self.vblank_oam_edge);
+            line |= sources & STAT_COINCIDENCE_SOURCE != 0 && self.coincidence;
+        }
+        if line && !self.stat_line { self.stat_requested = true; }
+        self.stat_line = line;
+    }
+
+    // picks the first 10 objects in OAM that cover this line, x doesn't matter here
+    fn scan_oam(&mut self) {
+        // object y is the bottom of a 16 pixel tall sprite, so everything is shifted down by 16
+        let height: u16 = self.sprite_height() as u16;
+        let line: u16 = self.ly as u16 + 16;
+        self.line_sprite_count = 0;
+        for entry in self.oam.chunks_exact(4) {
+            if self.line_sprite_count == SPRITES_PER_LINE { break; }
+            let top: u16 = entry[0] as u16;
+            if line < top || line >= top + height { continue; }
+            self.line_sprites[self.line_sprite_count] = Sprite {
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                attributes: entry[3],
+            };
+            self.line_sprite_count += 1;
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & LCDC_OBJECT_SIZE != 0 { return 16; }
+        return 8;
+    }
+
+    // background and window tiles are either 0-255 from 0x8000 or -128-127 from 0x9000
+    fn tile_address(&self, tile_index: u8) -> u16 {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            return 0x8000 + tile_index as u16 * 16;
+        }
+        return (0x9000i32 + tile_index as i8 as i32 * 16) as u16;
+    }
+
+    // colour number of column x (0 is leftmost) of the tile row at address
+    fn tile_pixel(&self, address: u16, x: u8) -> u8 {
+        let low: u8 = self.read_vram(address);
+        let high: u8 = self.read_vram(address + 1);
+        let bit: u8 = 7 - x;
+        return ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+    }
+
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled: bool = self.lcd_enabled();
+        self.lcdc = value;
+        if was_enabled && !self.lcd_enabled() {
+            // the screen goes blank and the ppu sits at the top of the frame, the LYC flag keeps its last value
+            self.line = 0;
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+            self.framebuffer.fill(0);
+            self.update_stat_line();
+        } else if !was_enabled && self.lcd_enabled() {
+            // starts straight on line 0 but without the oam scan, and the first frame isn't shown
+            self.line = 0;
+            self.ly = 0;
+            self.dot = 0;
+            self.window_line = 0;
+            self.window_triggered = self.wy == 0;
+            self.lcd_warming_up = true;
+            self.blank_frame = true;
+            self.mode = Mode::HBlank;
+            self.compare_ly();
+        }
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        return self.vram[(address - VRAM_START) as usize];
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        self.vram[(address - VRAM_START) as usize] = value;
+    }
+
+    pub fn read_oam(&self, address: u16) -> u8 {
+        return self.oam[(address - OAM_START) as usize];
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        self.oam[(address - OAM_START) as usize] = value;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            LCD_CONTROL_REGISTER => { return self.lcdc; }
+            LCD_STATUS_REGISTER => {
+                let coincidence: u8 = if self.coincidence { STAT_COINCIDENCE } else { 0 };
+                let mode: u8 = if self.lcd_enabled() { self.mode as u8 } else { 0 };
+                return 0x80 | self.stat | coincidence | mode;
+            }
+            SCROLL_Y_REGISTER => { return self.scy; }
+            SCROLL_X_REGISTER => { return self.scx; }
+            LCD_Y_REGISTER => { return self.ly; }
+            LCD_Y_COMPARE_REGISTER => { return self.lyc; }
+            BACKGROUND_PALETTE_REGISTER => { return self.bgp; }
+            OBJECT_PALETTE_0_REGISTER => { return self.obp0; }
+            OBJECT_PALETTE_1_REGISTER => { return self.obp1; }
+            WINDOW_Y_REGISTER => { return self.wy; }
+            WINDOW_X_REGISTER => { return self.wx; }
+            _ => { panic!("{:x} is not a ppu register", address); }
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            LCD_CONTROL_REGISTER => { self.write_lcdc(value); }
+            LCD_STATUS_REGISTER => {
+                self.stat = value & STAT_WRITABLE;
+                self.update_stat_line();
+            }
+            SCROLL_Y_REGISTER => { self.scy = value; }
+            SCROLL_X_REGISTER => { self.scx = value; }
+            LCD_Y_REGISTER => {} // read only
+            LCD_Y_COMPARE_REGISTER => {
+                self.lyc = value;
+                // the comparison is frozen while the lcd is off
+                if self.lcd_enabled() { self.compare_ly(); }
+            }
+            BACKGROUND_PALETTE_REGISTER => { self.bgp = value; }
+            OBJECT_PALETTE_0_REGISTER => { self.obp0 = value; }
+            OBJECT_PALETTE_1_REGISTER => { self.obp1 = value; }
+            WINDOW_Y_REGISTER => { self.wy = value; }
+            WINDOW_X_REGISTER => { self.wx = value; }
+            _ => { panic!("{:x} is not a ppu register", address); }
+        }
+    }
+}
+
+fn palette_shade(palette: u8, colour: u8) -> u8 {
+    return (palette >> (colour * 2)) & 0b11;
+}
+
+impl SaveState for Sprite {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.y);
+        state.write_u8(self.x);
+        state.write_u8(self.tile);
+        state.write_u8(self.attributes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.y = state.read_u8()?;
+        self.x = state.read_u8()?;
+        self.tile = state.read_u8()?;
+        self.attributes = state.read_u8()?;
+        return Ok(());
+    }
+}
+
+// the renderer picked by the embedder isn't part of the state, the one drawing the current line is
+impl SaveState for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.oam);
+        state.write_bytes(&self.framebuffer);
+        for register in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            state.write_u8(register);
+        }
+        state.write_bool(self.stat_line);
+        state.write_bool(self.coincidence);
+        state.write_bool(self.vblank_oam_edge);
+        state.write_u8(self.mode as u8);
+        state.write_u8(self.line);
+        state.write_usize(self.dot);
+        state.write_bool(self.lcd_warming_up);
+        state.write_bool(self.blank_frame);
+        state.write_u8(self.window_line);
+        state.write_bool(self.window_triggered);
+        for sprite in &self.line_sprites {
+            sprite.save_state(state);
+        }
+        state.write_usize(self.line_sprite_count);
+        state.write_bool(self.line_renderer == Renderer::PixelFifo);
+        self.fifo.save_state(state);
+        state.write_usize(self.frame_count);
+        state.write_bool(self.vblank_requested);
+        state.write_bool(self.stat_requested);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.vram)?;
+        state.read_into(&mut self.oam)?;
+        state.read_into(&mut self.framebuffer)?;
+        for register in [
+            &mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+            &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx,
+        ] {
+            *register = state.read_u8()?;
+        }
+        self.stat &= STAT_WRITABLE;
+        self.stat_line = state.read_bool()?;
+        self.coincidence = state.read_bool()?;
+        self.vblank_oam_edge = state.read_bool()?;
+        match state.read_u8()? {
+            0 => { self.mode = Mode::HBlank; }
+            1 => { self.mode = Mode::VBlank; }
+            2 => { self.mode = Mode::OamScan; }
+            3 => { self.mode = Mode::Drawing; }
+            _ => { return Err(StateError::Invalid("ppu mode")); }
+        }
+        self.line = state.read_u8()?;
+        self.dot = state.read_usize()?;
+        self.lcd_warming_up = state.read_bool()?;
+        self.blank_frame = state.read_bool()?;
+        self.window_line = state.read_u8()?;
+        self.window_triggered = state.read_bool()?;
+        for sprite in &mut self.line_sprites {
+            sprite.load_state(state)?;
+        }
+        self.line_sprite_count = state.read_usize()?;
+        self.line_renderer = if state.read_bool()? { Renderer::PixelFifo } else { Renderer::Scanline };
+        self.fifo.load_state(state)?;
+        self.frame_count = state.read_usize()?;
+        self.vblank_requested = state.read_bool()?;
+        self.stat_requested = state.read_bool()?;
+        if self.line >= LINES_PER_FRAME || self.dot > DOTS_PER_LINE || self.line_sprite_count > SPRITES_PER_LINE {
+            return Err(StateError::Invalid("ppu position"));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CYCLES_PER_LINE: usize = DOTS_PER_LINE / DOTS_PER_CYCLE;
+
+    // a frame and then some lines with the window covering the whole screen
+    fn run_window(renderer: Renderer, lcdc: u8) -> PPU {
+        let mut ppu: PPU = PPU::initialize();
+        ppu.set_renderer(renderer);
+        // window tiles come from 0x9C00 and are a stripe pattern, the background map is tile 0
+        for row in 0..8u16 {
+            ppu.write_vram(0x8010 + row * 2, 0xF0);
+            ppu.write_vram(0x8011 + row * 2, 0x0F << (row % 4));
+        }
+        for index in 0..0x400u16 {
+            ppu.write_vram(0x9C00 + index, (index % 2) as u8);
+        }
+        ppu.write_register(WINDOW_Y_REGISTER, 0);
+        ppu.write_register(WINDOW_X_REGISTER, 7);
+        ppu.write_register(LCD_CONTROL_REGISTER, lcdc);
+        ppu.tick(CYCLES_PER_LINE * LINES_PER_FRAME as usize + CYCLES_PER_LINE * 50);
+        return ppu;
+    }
+
+    #[test]
+    fn window_line_matches_between_renderers_with_the_background_off() {
+        let lcdc: u8 = LCDC_LCD_ENABLE | LCDC_WINDOW_ENABLE | LCDC_WINDOW_MAP | LCDC_TILE_DATA;
+        let scanline: PPU = run_window(Renderer::Scanline, lcdc);
+        let fifo: PPU = run_window(Renderer::PixelFifo, lcdc);
+        assert_eq!(scanline.window_line, 50);
+        assert_eq!(fifo.window_line, 50);
+    }
+
+    #[test]
+    fn window_output_matches_between_renderers() {
+        let lcdc: u8 = LCDC_LCD_ENABLE | LCDC_WINDOW_ENABLE | LCDC_WINDOW_MAP | LCDC_TILE_DATA | LCDC_BACKGROUND_ENABLE;
+        let scanline: PPU = run_window(Renderer::Scanline, lcdc);
+        let fifo: PPU = run_window(Renderer::PixelFifo, lcdc);
+        assert_eq!(scanline.window_line, fifo.window_line);
+        assert_eq!(&scanline.framebuffer[..50 * SCREEN_WIDTH], &fifo.framebuffer[..50 * SCREEN_WIDTH]);
+        assert!(scanline.framebuffer[..50 * SCREEN_WIDTH].iter().any(|shade| *shade != 0));
+    }
+
+    fn stat_mode(ppu: &PPU) -> u8 {
+        return ppu.read_register(LCD_STATUS_REGISTER) & 0b11;
+    }
+
+    #[test]
+    fn back_to_back_stat_sources_raise_one_interrupt() {
+        let mut ppu: PPU = PPU::initialize();
+        ppu.write_register(LCD_STATUS_REGISTER, STAT_HBLANK_SOURCE | STAT_OAM_SOURCE);
+        // line 0 is already in mode 2
+        assert!(ppu.stat_requested);
+        ppu.stat_requested = false;
+        ppu.tick((OAM_SCAN_DOTS + DRAWING_DOTS) / DOTS_PER_CYCLE);
+        assert_eq!(stat_mode(&ppu), Mode::HBlank as u8);
+        assert!(ppu.stat_requested);
+        ppu.stat_requested = false;
+        // mode 0 runs straight into the next line's mode 2, the line never drops
+        ppu.tick(CYCLES_PER_LINE - (OAM_SCAN_DOTS + DRAWING_DOTS) / DOTS_PER_CYCLE + 1);
+        assert_eq!(ppu.read_register(LCD_Y_REGISTER), 1);
+        assert_eq!(stat_mode(&ppu), Mode::OamScan as u8);
+        assert!(!ppu.stat_requested);
+        // mode 3 drops it, so the next mode 0 is a new edge
+        ppu.tick((OAM_SCAN_DOTS + DRAWING_DOTS) / DOTS_PER_CYCLE);
+        assert!(ppu.stat_requested);
+    }
+
+    #[test]
+    fn ly_reads_0_after_the_first_cycle_of_line_153() {
+        let mut ppu: PPU = PPU::initialize();
+        ppu.write_register(LCD_Y_COMPARE_REGISTER, 0);
+        ppu.write_register(LCD_STATUS_REGISTER, STAT_COINCIDENCE_SOURCE);
+        ppu.tick(CYCLES_PER_LINE * 153);
+        assert_eq!(ppu.read_register(LCD_Y_REGISTER), 153);
+        assert_eq!(ppu.read_register(LCD_STATUS_REGISTER) & STAT_COINCIDENCE, 0);
+        ppu.stat_requested = false;
+        ppu.tick(1);
+        assert_eq!(ppu.read_register(LCD_Y_REGISTER), 0);
+        assert_ne!(ppu.read_register(LCD_STATUS_REGISTER) & STAT_COINCIDENCE, 0);
+        assert!(ppu.stat_requested);
+        // still line 153 as far as the timing goes
+        assert_eq!(stat_mode(&ppu), Mode::VBlank as u8);
+        ppu.tick(CYCLES_PER_LINE - 1);
+        assert_eq!(ppu.read_register(LCD_Y_REGISTER), 0);
+        assert_eq!(stat_mode(&ppu), Mode::OamScan as u8);
+    }
+
+    #[test]
+    fn switching_the_lcd_on_skips_the_oam_scan_and_blanks_a_frame() {
+        let mut ppu: PPU = PPU::initialize();
+        ppu.write_register(LCD_CONTROL_REGISTER, LCDC_TILE_DATA | LCDC_BACKGROUND_ENABLE);
+        // tile 0 is solid colour 3, which the palette shows as black
+        for address in 0x8000..0x8010 {
+            ppu.write_vram(address, 0xFF);
+        }
+        ppu.write_register(LCD_CONTROL_REGISTER, LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_BACKGROUND_ENABLE);
+        assert_eq!(ppu.read_register(LCD_Y_REGISTER), 0);
+        assert_eq!(stat_mode(&ppu), Mode::HBlank as u8);
+        ppu.tick(OAM_SCAN_DOTS / DOTS_PER_CYCLE);
+        assert_eq!(stat_mode(&ppu), Mode::Drawing as u8);
+
+        ppu.tick(CYCLES_PER_LINE * SCREEN_HEIGHT);
+        assert!(ppu.vblank_requested);
+        assert!(ppu.framebuffer().iter().all(|shade| *shade == 0));
+        ppu.tick(CYCLES_PER_LINE * LINES_PER_FRAME as usize);
+        assert!(ppu.framebuffer().iter().all(|shade| *shade == 3));
+    }
+}
@@ -0,0 +1,85 @@
+use super::*;
+
+// draws the whole line at once at the end of mode 3, mid line register writes aren't seen
+impl PPU {
+    pub(super) fn render_scanline(&mut self) {
+        let line: usize = self.ly as usize;
+        // the raw colour numbers of the background, sprites check these for priority
+        let mut background: [u8; SCREEN_WIDTH] = [0; SCREEN_WIDTH];
+
+        if self.lcdc & LCDC_BACKGROUND_ENABLE != 0 {
+            let y: u8 = self.ly.wrapping_add(self.scy);
+            let map: u16 = if self.lcdc & LCDC_BACKGROUND_MAP != 0 { 0x9C00 } else { 0x9800 };
+            for (x, colour) in background.iter_mut().enumerate() {
+                *colour = self.map_pixel(map, (x as u8).wrapping_add(self.scx), y);
+            }
+        }
+
+        if self.window_visible() {
+            // on the dmg the window shows colour 0 along with the background, but it's still
+            // fetched and its line counter still moves on, same as in the pixel fifo
+            if self.lcdc & LCDC_BACKGROUND_ENABLE != 0 {
+                let map: u16 = if self.lcdc & LCDC_WINDOW_MAP != 0 { 0x9C00 } else { 0x9800 };
+                let start: usize = (self.wx as usize).saturating_sub(7);
+                let offset: usize = 7usize.saturating_sub(self.wx as usize); // WX under 7 cuts off the left edge
+                for (x, colour) in background.iter_mut().enumerate().skip(start) {
+                    *colour = self.map_pixel(map, (x - start + offset) as u8, self.window_line);
+                }
+            }
+            self.window_line += 1;
+        }
+
+        for (x, colour) in background.iter().enumerate() {
+            self.framebuffer[line * SCREEN_WIDTH + x] = palette_shade(self.bgp, *colour);
+        }
+
+        if self.lcdc & LCDC_OBJECT_ENABLE != 0 {
+            self.render_sprites(line, &background);
+        }
+    }
+
+    fn window_visible(&self) -> bool {
+        return self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.window_triggered && self.wx <= 166;
+    }
+
+    // colour number of a pixel in a 256x256 tile map
+    fn map_pixel(&self, map: u16, x: u8, y: u8) -> u8 {
+        let tile_index: u8 = self.read_vram(map + (y as u16 / 8) * 32 + x as u16 / 8);
+        let address: u16 = self.tile_address(tile_index) + (y as u16 % 8) * 2;
+        return self.tile_pixel(address, x % 8);
+    }
+
+    fn render_sprites(&mut self, line: usize, background: &[u8; SCREEN_WIDTH]) {
+        // smaller x wins and ties go to the earlier OAM entry, a stable sort keeps OAM order
+        let mut sprites: Vec<Sprite> = self.line_sprites[..self.line_sprite_count].to_vec();
+        sprites.sort_by_key(|sprite| sprite.x);
+
+        let height: u8 = self.sprite_height();
+        for (x, background_colour) in background.iter().enumerate() {
+            let screen_x: usize = x + 8;
+            for sprite in sprites.iter() {
+                let left: usize = sprite.x as usize;
+                if screen_x < left || screen_x >= left + 8 { continue; }
+
+                let mut column: u8 = (screen_x - left) as u8;
+                if sprite.attributes & ATTRIBUTE_X_FLIP != 0 { column = 7 - column; }
+                // masked in case the object size was changed since the oam scan
+                let mut row: u8 = (line as u8 + 16).wrapping_sub(sprite.y) & (height - 1);
+                if sprite.attributes & ATTRIBUTE_Y_FLIP != 0 { row = height - 1 - row; }
+                // tall sprites ignore the lowest bit of the tile number
+                let tile: u8 = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+
+                let colour: u8 = self.tile_pixel(0x8000 + tile as u16 * 16 + row as u16 * 2, column);
+                // a transparent pixel lets the next sprite through
+                if colour == 0 { continue; }
+
+                // the highest priority opaque sprite decides, even if the background then covers it
+                if sprite.attributes & ATTRIBUTE_BEHIND_BACKGROUND == 0 || *background_colour == 0 {
+                    let palette: u8 = if sprite.attributes & ATTRIBUTE_PALETTE != 0 { self.obp1 } else { self.obp0 };
+                    self.framebuffer[line * SCREEN_WIDTH + x] = palette_shade(palette, colour);
+                }
+                break;
+            }
+        }
+    }
+}
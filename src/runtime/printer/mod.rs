@@ -0,0 +1,381 @@
+mod png;
+
+use super::serial::{Serial, SerialPeripheral};
+use super::CYCLES_PER_SECOND;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// every packet starts with these two bytes
+const MAGIC: [u8; 2] = [0x88, 0x33];
+// the printer answers the first of the two bytes after the checksum with this, then its status
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+// status bits
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+// the paper is 160 pixels wide, and a DATA packet carries a band of 2 rows of 20 tiles
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+const TILE_SIZE: usize = 16;
+const BAND_SIZE: usize = 0x280;
+// the printer holds a screen's worth of bands before it has to print
+const BUFFER_BANDS: usize = 9;
+const IMAGE_SIZE: usize = BUFFER_BANDS * BAND_SIZE;
+// how long the head takes to print, games wait for the busy bit to clear
+const PRINT_CYCLES: usize = CYCLES_PER_SECOND / 2;
+
+// gray levels for shades 0 (white) to 3 (black)
+const GRAY_LEVELS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy, PartialEq)]
+enum PacketState {
+    Magic(usize), // magic bytes matched so far
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// the game boy printer, on the serial port with the game boy clocking. tile data comes in DATA
+// packets and goes on paper with PRINT, paper is saved as png strips. prints with no bottom
+// margin are joined to the next one, like they'd come out on the roll
+pub struct Printer {
+    path: PathBuf,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16, // what the packet adds up to so far
+    received_checksum: u16,
+    incoming: u8, // bits of the byte coming in
+    outgoing: u8, // the byte being shifted back
+    image: Vec<u8>, // decompressed tile data waiting to print
+    status: u8,
+    busy_cycles: usize, // machine cycles left printing
+    strip: Vec<u8>, // printed rows of shades waiting for the paper to be cut
+    strips_saved: usize,
+    callback: Option<Box<dyn FnMut(io::Result<PathBuf>)>>,
+}
+
+impl Printer {
+    // strips are saved next to the path with a number, so print.png gives print.1.png, print.2.png...
+    pub fn initialize(path: &Path) -> Printer {
+        return Printer {
+            path: path.to_path_buf(),
+            state: PacketState::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            incoming: 0,
+            outgoing: 0x00,
+            image: Vec::new(),
+            status: 0,
+            busy_cycles: 0,
+            strip: Vec::new(),
+            strips_saved: 0,
+            callback: None,
+        };
+    }
+
+    // told about every strip as it's saved, or the error saving it
+    pub fn set_callback(&mut self, callback: Box<dyn FnMut(io::Result<PathBuf>)>) {
+        self.callback = Some(callback);
+    }
+
+    fn status(&self) -> u8 {
+        let mut status: u8 = self.status;
+        if self.busy_cycles > 0 { status |= STATUS_PRINTING; }
+        if !self.image.is_empty() { status |= STATUS_UNPROCESSED; }
+        if self.image.len() >= IMAGE_SIZE { status |= STATUS_IMAGE_FULL; }
+        return status;
+    }
+
+    // a whole byte has come in, returns the byte to send back during the next one
+    fn receive_byte(&mut self, byte: u8) -> u8 {
+        match self.state {
+            PacketState::Magic(matched) => {
+                if byte == MAGIC[matched] {
+                    self.state = if matched + 1 == MAGIC.len() { PacketState::Command } else { PacketState::Magic(matched + 1) };
+                } else {
+                    self.state = PacketState::Magic((byte == MAGIC[0]) as usize);
+                }
+            }
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.state = PacketState::Compression;
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthLow;
+            }
+            PacketState::LengthLow => {
+                self.length = byte as usize;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthHigh;
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                self.state = if self.length == 0 { PacketState::ChecksumLow } else { PacketState::Data };
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length { self.state = PacketState::ChecksumLow; }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                self.state = PacketState::ChecksumHigh;
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.state = PacketState::Alive;
+                return ALIVE;
+            }
+            PacketState::Alive => {
+                // the packet's done, the status that goes back already has its effect
+                self.execute();
+                self.state = PacketState::Status;
+                return self.status();
+            }
+            PacketState::Status => {
+                self.state = PacketState::Magic(0);
+            }
+        }
+        return 0x00;
+    }
+
+    fn execute(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        match self.command {
+            COMMAND_INIT => {
+                self.image.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                // an empty DATA packet only marks the end of the image
+                let data: Vec<u8> = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                // past a full buffer the data has nowhere to go
+                let room: usize = IMAGE_SIZE - self.image.len();
+                self.image.extend_from_slice(&data[..data.len().min(room)]);
+            }
+            COMMAND_PRINT => {
+                if self.data.len() < 4 {
+                    self.status |= STATUS_PACKET_ERROR;
+                    return;
+                }
+                // sheets, margins (top in the high nibble), palette, exposure
+                let margins: u8 = self.data[1];
+                let palette: u8 = self.data[2];
+                self.print(palette);
+                self.busy_cycles = PRINT_CYCLES;
+                if margins & 0x0F != 0 { self.cut(); }
+            }
+            COMMAND_STATUS => {}
+            _ => {
+                self.status |= STATUS_PACKET_ERROR;
+            }
+        }
+    }
+
+    // turns the buffered tiles into rows of shades on the strip
+    fn print(&mut self, palette: u8) {
+        // a palette of 0 means the usual one
+        let palette: u8 = if palette == 0 { 0xE4 } else { palette };
+        let tile_rows: usize = self.image.len() / (TILES_PER_ROW * TILE_SIZE);
+        for y in 0..tile_rows * 8 {
+            for x in 0..WIDTH {
+                let tile: usize = (y / 8) * TILES_PER_ROW + x / 8;
+                let address: usize = tile * TILE_SIZE + (y % 8) * 2;
+                let bit: usize = 7 - x % 8;
+                let colour: u8 = ((self.image[address + 1] >> bit) & 1) << 1 | ((self.image[address] >> bit) & 1);
+                self.strip.push((palette >> (colour * 2)) & 0b11);
+            }
+        }
+        self.image.clear();
+    }
+
+    // saves what's been printed since the last cut as one strip
+    fn cut(&mut self) {
+        if self.strip.is_empty() { return; }
+        self.strips_saved += 1;
+        let name: String = self.path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let path: PathBuf = self.path.with_file_name(format!("{}.{}.png", name, self.strips_saved));
+        let pixels: Vec<u8> = self.strip.iter().map(|shade| GRAY_LEVELS[*shade as usize]).collect();
+        let result: io::Result<()> = png::write_grayscale(&path, WIDTH, self.strip.len() / WIDTH, &pixels);
+        self.strip.clear();
+        if let Some(callback) = &mut self.callback { callback(result.map(|_| path)); }
+    }
+
+    // saves anything printed but not cut yet, for when the printer is unplugged
+    pub fn finish(&mut self) {
+        self.cut();
+    }
+}
+
+impl SerialPeripheral for Printer {
+    fn update(&mut self, serial: &mut Serial, cycles: usize) {
+        self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+        // the printer only ever has the game boy clocking it
+        let Some(bit) = serial.outgoing_bit() else { return; };
+        if serial.bits_shifted() == 0 {
+            self.incoming = 0;
+        }
+        let shift: u8 = 7 - serial.bits_shifted();
+        serial.complete_bit(self.outgoing >> shift & 1 != 0);
+        self.incoming = self.incoming << 1 | bit as u8;
+        if serial.bits_shifted() == 8 {
+            self.outgoing = self.receive_byte(self.incoming);
+        }
+    }
+}
+
+// the printer's run length encoding. a control byte with bit 7 set repeats the next byte
+// (control & 0x7F) + 2 times, without it the next control + 1 bytes are copied as they are
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut index: usize = 0;
+    while index < data.len() {
+        let control: u8 = data[index];
+        index += 1;
+        if control & 0x80 != 0 {
+            let Some(byte) = data.get(index) else { break; };
+            output.extend(std::iter::repeat_n(*byte, (control & 0x7F) as usize + 2));
+            index += 1;
+        } else {
+            let end: usize = (index + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[index..end]);
+            index = end;
+        }
+    }
+    return output;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sends a whole packet, returns what the printer answers to the two bytes after the checksum
+    fn send(printer: &mut Printer, command: u8, compression: u8, data: &[u8], checksum: u16) -> (u8, u8) {
+        let mut bytes: Vec<u8> = MAGIC.to_vec();
+        bytes.extend_from_slice(&[command, compression]);
+        bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        // each answer goes back while the next byte comes in
+        bytes.extend_from_slice(&[0x00, 0x00]);
+        let answers: Vec<u8> = bytes.into_iter().map(|byte| printer.receive_byte(byte)).collect();
+        return (answers[answers.len() - 3], answers[answers.len() - 2]);
+    }
+
+    fn send_packet(printer: &mut Printer, command: u8, compression: u8, data: &[u8]) -> (u8, u8) {
+        let length: [u8; 2] = (data.len() as u16).to_le_bytes();
+        let checksum: u16 = [command, compression, length[0], length[1]].iter().chain(data)
+            .fold(0, |sum: u16, byte| sum.wrapping_add(*byte as u16));
+        return send(printer, command, compression, data, checksum);
+    }
+
+    fn printer() -> Printer {
+        return Printer::initialize(Path::new("print.png"));
+    }
+
+    #[test]
+    fn answers_a_status_packet() {
+        let mut printer: Printer = printer();
+        assert_eq!(send_packet(&mut printer, COMMAND_STATUS, 0, &[]), (ALIVE, 0x00));
+    }
+
+    #[test]
+    fn finds_the_magic_after_noise() {
+        let mut printer: Printer = printer();
+        for byte in [0x00, 0x33, 0x88] {
+            printer.receive_byte(byte);
+        }
+        // the 0x88 above and the one starting the packet, the second starts the match over
+        assert_eq!(send_packet(&mut printer, COMMAND_STATUS, 0, &[]), (ALIVE, 0x00));
+    }
+
+    #[test]
+    fn a_bad_checksum_is_reported_and_the_packet_dropped() {
+        let mut printer: Printer = printer();
+        let (_, status) = send(&mut printer, COMMAND_DATA, 0, &[0xFF; 4], 0x1234);
+        assert_eq!(status, STATUS_CHECKSUM_ERROR);
+        assert!(printer.image.is_empty());
+        // INIT clears it
+        assert_eq!(send_packet(&mut printer, COMMAND_INIT, 0, &[]), (ALIVE, 0x00));
+    }
+
+    #[test]
+    fn unknown_commands_are_packet_errors() {
+        let mut printer: Printer = printer();
+        assert_eq!(send_packet(&mut printer, 0x03, 0, &[]).1, STATUS_PACKET_ERROR);
+    }
+
+    #[test]
+    fn data_is_buffered_until_printed() {
+        let mut printer: Printer = printer();
+        assert_eq!(send_packet(&mut printer, COMMAND_DATA, 0, &[0xAA; BAND_SIZE]).1, STATUS_UNPROCESSED);
+        // 3 copies of 0x55 then 2 bytes as they are
+        send_packet(&mut printer, COMMAND_DATA, 1, &[0x81, 0x55, 0x01, 0x12, 0x34]);
+        assert_eq!(printer.image.len(), BAND_SIZE + 5);
+        assert_eq!(printer.image[BAND_SIZE..], [0x55, 0x55, 0x55, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn the_buffer_stops_at_nine_bands() {
+        let mut printer: Printer = printer();
+        for _ in 0..BUFFER_BANDS - 1 {
+            send_packet(&mut printer, COMMAND_DATA, 0, &[0x00; BAND_SIZE]);
+        }
+        assert_eq!(send_packet(&mut printer, COMMAND_DATA, 0, &[0x00; BAND_SIZE]).1, STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+        send_packet(&mut printer, COMMAND_DATA, 0, &[0x00; BAND_SIZE]);
+        assert_eq!(printer.image.len(), IMAGE_SIZE);
+    }
+
+    #[test]
+    fn print_turns_the_tiles_into_shades() {
+        let mut printer: Printer = printer();
+        // every pixel colour 1, then colour 3
+        let mut band: Vec<u8> = [0xFF, 0x00].repeat(BAND_SIZE / 4);
+        band.extend_from_slice(&[0xFF; BAND_SIZE / 2]);
+        send_packet(&mut printer, COMMAND_DATA, 0, &band);
+        // no margins, so nothing is cut and saved
+        let (_, status) = send_packet(&mut printer, COMMAND_PRINT, 0, &[0x01, 0x00, 0xE4, 0x40]);
+        assert_eq!(status, STATUS_PRINTING);
+        assert_eq!(printer.strip.len(), WIDTH * 16);
+        assert!(printer.strip[..WIDTH * 8].iter().all(|shade| *shade == 1));
+        assert!(printer.strip[WIDTH * 8..].iter().all(|shade| *shade == 3));
+        assert_eq!(send_packet(&mut printer, COMMAND_PRINT, 0, &[0x01]).1 & STATUS_PACKET_ERROR, STATUS_PACKET_ERROR);
+    }
+
+    #[test]
+    fn decompress_stops_at_a_cut_short_run() {
+        assert_eq!(decompress(&[0x02, 0x01, 0x02, 0x03, 0x80, 0x09]), [0x01, 0x02, 0x03, 0x09, 0x09]);
+        assert_eq!(decompress(&[0x85]), []);
+        assert_eq!(decompress(&[0x03, 0x01]), [0x01]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const COLOR_TYPE_GRAYSCALE: u8 = 0;
+// the most a stored deflate block can hold
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+// writes an 8 bit grayscale png. the image data goes in uncompressed deflate blocks, which keeps
+// this to a few lines and still opens everywhere
+pub fn write_grayscale(path: &Path, width: usize, height: usize, pixels: &[u8]) -> io::Result<()> {
+    assert!(pixels.len() == width * height, "png needs width * height pixels");
+    let mut file: Vec<u8> = SIGNATURE.to_vec();
+
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth, color type, then default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, COLOR_TYPE_GRAYSCALE, 0, 0, 0]);
+    write_chunk(&mut file, b"IHDR", &header);
+
+    // every row starts with its filter type, 0 for none
+    let mut raw: Vec<u8> = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width.max(1)).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(&mut file, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut file, b"IEND", &[]);
+    return fs::write(path, file);
+}
+
+fn write_chunk(file: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    file.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start: usize = file.len();
+    file.extend_from_slice(kind);
+    file.extend_from_slice(data);
+    // the crc covers the type and the data
+    let crc: u32 = crc32(&file[start..]);
+    file.extend_from_slice(&crc.to_be_bytes());
+}
+
+// a zlib stream of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream: Vec<u8> = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        // an empty stream still needs a final block
+        stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last: bool = blocks.peek().is_none();
+        stream.push(last as u8); // BFINAL, and BTYPE 00 for stored
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    return stream;
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask: u32 = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return b << 16 | a;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn adler32_check_value() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(&[]), 1);
+    }
+
+    #[test]
+    fn zlib_stored_splits_into_blocks() {
+        assert_eq!(zlib_stored(&[]), [0x78, 0x01, 0x01, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x01]);
+
+        let data: Vec<u8> = vec![0x5A; STORED_BLOCK_SIZE + 1];
+        let stream: Vec<u8> = zlib_stored(&data);
+        assert_eq!(stream[2..7], [0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        let second: usize = 7 + STORED_BLOCK_SIZE;
+        assert_eq!(stream[second..second + 6], [0x01, 0x01, 0x00, 0xFE, 0xFF, 0x5A]);
+        assert_eq!(stream.len(), second + 6 + 4);
+    }
+}
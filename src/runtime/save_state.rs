@@ -0,0 +1,270 @@
+use super::cartridge::header::CartridgeHeader;
+use std::fmt;
+use std::io;
+
+// a state file is the magic, the format version, which cartridge it's for, then the machine.
+// the version goes up whenever the layout of the machine's state changes, states from any other
+// version are turned away rather than loaded into the wrong fields
+pub const STATE_MAGIC: [u8; 8] = *b"DMGESTAT";
+pub const STATE_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    NotAState, // the magic doesn't match
+    UnsupportedVersion(u16),
+    WrongCartridge(String), // the title of the cartridge the state is from
+    Corrupt, // cut short, or the checksum doesn't match
+    Invalid(&'static str), // a value no machine could be in
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(error) => { write!(f, "{}", error) }
+            StateError::NotAState => { write!(f, "not a save state") }
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} can't be loaded, this build reads version {}", version, STATE_VERSION)
+            }
+            StateError::WrongCartridge(title) => { write!(f, "save state is for a different cartridge ({})", title) }
+            StateError::Corrupt => { write!(f, "save state is damaged") }
+            StateError::Invalid(what) => { write!(f, "save state has an invalid {}", what) }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(error: io::Error) -> StateError {
+        return StateError::Io(error);
+    }
+}
+
+// every part of the machine writes its fields out and reads them back in the same order
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+// little endian fields one after the other
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn initialize() -> StateWriter {
+        return StateWriter { data: Vec::new() };
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        return self.data;
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    // bytes with their length in front, for anything that isn't a fixed size
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn initialize(data: &'a [u8]) -> StateReader<'a> {
+        return StateReader { data, position: 0 };
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.position == self.data.len();
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < length { return Err(StateError::Corrupt); }
+        let bytes: &'a [u8] = &self.data[self.position..self.position + length];
+        self.position += length;
+        return Ok(bytes);
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => { return Ok(false); }
+            1 => { return Ok(true); }
+            _ => { return Err(StateError::Invalid("flag")); }
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes: &[u8] = self.take(2)?;
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes: &[u8] = self.take(4)?;
+        return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes: [u8; 8] = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, StateError> {
+        return usize::try_from(self.read_u64()?).map_err(|_| StateError::Invalid("count"));
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length: usize = self.read_u32()? as usize;
+        return self.take(length);
+    }
+
+    // length prefixed bytes into a buffer that has to be exactly that size, like ram
+    pub fn read_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let bytes: &[u8] = self.read_bytes()?;
+        if bytes.len() != buffer.len() { return Err(StateError::Invalid("memory size")); }
+        buffer.copy_from_slice(bytes);
+        return Ok(());
+    }
+}
+
+// puts the file header in front of a machine's state
+pub fn encode(cartridge: &CartridgeHeader, machine: &[u8]) -> Vec<u8> {
+    let mut state: StateWriter = StateWriter::initialize();
+    for byte in STATE_MAGIC {
+        state.write_u8(byte);
+    }
+    state.write_u16(STATE_VERSION);
+    write_cartridge(&mut state, cartridge);
+    state.write_u64(checksum(machine));
+    state.write_bytes(machine);
+    return state.into_bytes();
+}
+
+// checks the file header against this build and the loaded cartridge, returns the machine's state
+pub fn decode<'a>(cartridge: &CartridgeHeader, data: &'a [u8]) -> Result<&'a [u8], StateError> {
+    if data.len() < STATE_MAGIC.len() || data[..STATE_MAGIC.len()] != STATE_MAGIC {
+        return Err(StateError::NotAState);
+    }
+    let mut state: StateReader = StateReader::initialize(&data[STATE_MAGIC.len()..]);
+    let version: u16 = state.read_u16()?;
+    if version != STATE_VERSION { return Err(StateError::UnsupportedVersion(version)); }
+
+    let title: String = String::from_utf8_lossy(state.read_bytes()?).into_owned();
+    let cartridge_type: u8 = state.read_u8()?;
+    let rom_size: usize = state.read_usize()?;
+    let ram_size: usize = state.read_usize()?;
+    let matches: bool = title == cartridge.title && cartridge_type == cartridge.cartridge_type
+        && rom_size == cartridge.rom_size && ram_size == cartridge.ram_size;
+    if !matches { return Err(StateError::WrongCartridge(title)); }
+
+    let expected: u64 = state.read_u64()?;
+    let machine: &[u8] = state.read_bytes()?;
+    if !state.is_finished() || checksum(machine) != expected { return Err(StateError::Corrupt); }
+    return Ok(machine);
+}
+
+// enough of the header to be sure the banking layout and ram are the same
+fn write_cartridge(state: &mut StateWriter, cartridge: &CartridgeHeader) {
+    state.write_bytes(cartridge.title.as_bytes());
+    state.write_u8(cartridge.cartridge_type);
+    state.write_usize(cartridge.rom_size);
+    state.write_usize(cartridge.ram_size);
+}
+
+// fnv-1a, enough to tell a damaged file from a good one
+pub fn checksum(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    return hash;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cartridge::header::TITLE_START;
+
+    fn header(title: &[u8]) -> CartridgeHeader {
+        let mut rom: Vec<u8> = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title);
+        return CartridgeHeader::parse(&rom).unwrap();
+    }
+
+    const MACHINE: [u8; 5] = [1, 2, 3, 4, 5];
+
+    #[test]
+    fn decode_returns_the_encoded_machine() {
+        let cartridge: CartridgeHeader = header(b"GAME");
+        let state: Vec<u8> = encode(&cartridge, &MACHINE);
+        assert_eq!(decode(&cartridge, &state).unwrap(), &MACHINE);
+    }
+
+    #[test]
+    fn bad_magic_is_not_a_state() {
+        let cartridge: CartridgeHeader = header(b"GAME");
+        let mut state: Vec<u8> = encode(&cartridge, &MACHINE);
+        state[0] = b'X';
+        assert!(matches!(decode(&cartridge, &state), Err(StateError::NotAState)));
+        assert!(matches!(decode(&cartridge, b"DMG"), Err(StateError::NotAState)));
+    }
+
+    #[test]
+    fn other_versions_are_turned_away() {
+        let cartridge: CartridgeHeader = header(b"GAME");
+        let mut state: Vec<u8> = encode(&cartridge, &MACHINE);
+        state[STATE_MAGIC.len()..STATE_MAGIC.len() + 2].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(decode(&cartridge, &state), Err(StateError::UnsupportedVersion(version)) if version == STATE_VERSION + 1));
+    }
+
+    #[test]
+    fn damaged_states_are_corrupt() {
+        let cartridge: CartridgeHeader = header(b"GAME");
+        let mut state: Vec<u8> = encode(&cartridge, &MACHINE);
+        let last: usize = state.len() - 1;
+        state[last] ^= 0xFF;
+        assert!(matches!(decode(&cartridge, &state), Err(StateError::Corrupt)));
+        state.truncate(last);
+        assert!(matches!(decode(&cartridge, &state), Err(StateError::Corrupt)));
+    }
+
+    #[test]
+    fn states_from_another_cartridge_are_turned_away() {
+        let state: Vec<u8> = encode(&header(b"GAME"), &MACHINE);
+        let result: Result<&[u8], StateError> = decode(&header(b"OTHER"), &state);
+        assert!(matches!(result, Err(StateError::WrongCartridge(title)) if title == "GAME"));
+    }
+}
@@ -0,0 +1,278 @@
+use super::save_state::{SaveState, StateError, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SERIAL_DATA_REGISTER: u16 = 0xFF01; // SB
+pub const SERIAL_CONTROL_REGISTER: u16 = 0xFF02; // SC
+
+// SC bits
+const TRANSFER_START: u8 = 0x80;
+const INTERNAL_CLOCK: u8 = 0x01;
+// the unused SC bits read back as 1 on the dmg
+const CONTROL_READ_MASK: u8 = 0x7E;
+
+// the internal clock runs at 8192Hz, a bit every 128 machine cycles
+pub const CYCLES_PER_BIT: usize = 128;
+
+// something plugged into the serial port, handed the port after every step with the machine
+// cycles it took. a peripheral answers this side's clock edges (outgoing_bit / complete_bit) and
+// can clock it from the other end (external_clock)
+pub trait SerialPeripheral {
+    fn update(&mut self, serial: &mut Serial, cycles: usize);
+}
+
+// lets the embedder keep a handle on a peripheral after plugging it in
+impl<T: SerialPeripheral> SerialPeripheral for Rc<RefCell<T>> {
+    fn update(&mut self, serial: &mut Serial, cycles: usize) {
+        self.borrow_mut().update(serial, cycles);
+    }
+}
+
+// the serial port, shifts SB out a bit at a time while shifting the partner's bits in.
+// with the internal clock this side drives the transfer, with the external clock it waits for
+// the partner to clock each bit
+pub struct Serial {
+    data: u8, // SB, part way shifted during a transfer
+    control: u8, // SC
+    sending: u8, // SB as it was when the transfer started
+    bits: u8, // bits shifted so far in this transfer
+    timer: usize, // machine cycles until the next internal clock bit
+    connected: bool, // a cable is plugged in, internal clock bits wait for it to answer
+    waiting: bool, // a clock edge is out, waiting on the cable for the bit coming back
+    callback: Option<Box<dyn FnMut(u8)>>,
+    pub interrupt_requested: bool,
+}
+
+impl Serial {
+    pub fn initialize() -> Serial {
+        return Serial {
+            data: 0x00,
+            control: 0x00,
+            sending: 0x00,
+            bits: 0,
+            timer: 0,
+            connected: false,
+            waiting: false,
+            callback: None,
+            interrupt_requested: false,
+        };
+    }
+
+    // the callback is handed every byte the game boy sends, as each transfer finishes
+    pub fn set_callback(&mut self, callback: Box<dyn FnMut(u8)>) {
+        self.callback = Some(callback);
+    }
+
+    pub fn transferring(&self) -> bool {
+        return self.control & TRANSFER_START != 0;
+    }
+
+    pub fn internal_clock(&self) -> bool {
+        return self.control & INTERNAL_CLOCK != 0;
+    }
+
+    // SB as it was when the current transfer started
+    pub fn sending_byte(&self) -> u8 {
+        return self.sending;
+    }
+
+    // bits through so far in the current transfer, 8 once it's done
+    pub fn bits_shifted(&self) -> u8 {
+        return self.bits;
+    }
+
+    pub fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        if !connected && self.waiting {
+            self.complete_bit(true);
+        }
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        if !self.transferring() || !self.internal_clock() { return; }
+        let mut remaining: usize = cycles;
+        while self.transferring() && !self.waiting && remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = CYCLES_PER_BIT;
+            if self.connected {
+                self.waiting = true;
+            } else {
+                // nothing is plugged in, the input line floats high
+                self.shift(true);
+            }
+        }
+        if self.transferring() { self.timer = self.timer.saturating_sub(remaining); }
+    }
+
+    // the bit on the output line while an internal clock edge waits for the cable
+    pub fn outgoing_bit(&self) -> Option<bool> {
+        if !self.waiting { return None; }
+        return Some(self.data & 0x80 != 0);
+    }
+
+    // the cable answering a clock edge with the partner's bit
+    pub fn complete_bit(&mut self, incoming: bool) {
+        if !self.waiting { return; }
+        self.waiting = false;
+        self.shift(incoming);
+    }
+
+    // the partner clocking a bit in slave mode, returns the bit sent back or None when this
+    // side isn't waiting on an external clock and the line just stays high
+    pub fn external_clock(&mut self, incoming: bool) -> Option<bool> {
+        if !self.transferring() || self.internal_clock() { return None; }
+        return Some(self.shift(incoming));
+    }
+
+    // moves one bit through SB, returns the bit shifted out
+    fn shift(&mut self, incoming: bool) -> bool {
+        let outgoing: bool = self.data & 0x80 != 0;
+        self.data = self.data << 1 | incoming as u8;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.control &= !TRANSFER_START;
+            self.interrupt_requested = true;
+            if let Some(callback) = &mut self.callback { callback(self.sending); }
+        }
+        return outgoing;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            SERIAL_DATA_REGISTER => { return self.data; }
+            _ => { return self.control | CONTROL_READ_MASK; }
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            SERIAL_DATA_REGISTER => {
+                self.data = value;
+            }
+            _ => {
+                self.control = value & (TRANSFER_START | INTERNAL_CLOCK);
+                self.waiting = false;
+                if self.transferring() {
+                    self.sending = self.data;
+                    self.bits = 0;
+                    self.timer = CYCLES_PER_BIT;
+                }
+            }
+        }
+    }
+}
+
+// what's plugged in and the callback belong to the embedder, not the state
+impl SaveState for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u8(self.sending);
+        state.write_u8(self.bits);
+        state.write_usize(self.timer);
+        state.write_bool(self.waiting);
+        state.write_bool(self.interrupt_requested);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()? & (TRANSFER_START | INTERNAL_CLOCK);
+        self.sending = state.read_u8()?;
+        self.bits = state.read_u8()?;
+        self.timer = state.read_usize()?;
+        self.waiting = state.read_bool()?;
+        self.interrupt_requested = state.read_bool()?;
+        if self.bits > 8 || self.timer > CYCLES_PER_BIT { return Err(StateError::Invalid("serial transfer")); }
+        if self.waiting && !self.connected {
+            // saved mid edge with a cable in, without one the line is high
+            self.complete_bit(true);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(serial: &mut Serial, data: u8, control: u8) {
+        serial.write_register(SERIAL_DATA_REGISTER, data);
+        serial.write_register(SERIAL_CONTROL_REGISTER, control);
+    }
+
+    #[test]
+    fn the_internal_clock_shifts_a_bit_every_128_cycles() {
+        let mut serial: Serial = Serial::initialize();
+        start(&mut serial, 0x00, TRANSFER_START | INTERNAL_CLOCK);
+        serial.tick(CYCLES_PER_BIT - 1);
+        assert_eq!(serial.bits_shifted(), 0);
+        serial.tick(1);
+        assert_eq!(serial.bits_shifted(), 1);
+        // nothing plugged in, so 1s come back
+        assert_eq!(serial.read_register(SERIAL_DATA_REGISTER), 0x01);
+        serial.tick(CYCLES_PER_BIT * 3);
+        assert_eq!(serial.bits_shifted(), 4);
+    }
+
+    #[test]
+    fn the_interrupt_comes_after_8_bits() {
+        let mut serial: Serial = Serial::initialize();
+        start(&mut serial, 0x42, TRANSFER_START | INTERNAL_CLOCK);
+        serial.tick(CYCLES_PER_BIT * 8 - 1);
+        assert!(serial.transferring());
+        assert!(!serial.interrupt_requested);
+        serial.tick(1);
+        assert!(!serial.transferring());
+        assert!(serial.interrupt_requested);
+        assert_eq!(serial.read_register(SERIAL_DATA_REGISTER), 0xFF);
+        assert_eq!(serial.read_register(SERIAL_CONTROL_REGISTER), 0x7F);
+    }
+
+    #[test]
+    fn the_external_clock_waits_for_the_partner() {
+        let mut serial: Serial = Serial::initialize();
+        start(&mut serial, 0b1010_0000, TRANSFER_START);
+        serial.tick(CYCLES_PER_BIT * 16);
+        assert_eq!(serial.bits_shifted(), 0);
+        let mut sent: Vec<bool> = Vec::new();
+        for bit in 0..8 {
+            sent.push(serial.external_clock(bit % 2 == 0).unwrap());
+        }
+        assert_eq!(sent, [true, false, true, false, false, false, false, false]);
+        assert_eq!(serial.read_register(SERIAL_DATA_REGISTER), 0b1010_1010);
+        assert!(serial.interrupt_requested);
+        // done, more clocks don't reach it
+        assert_eq!(serial.external_clock(true), None);
+    }
+
+    #[test]
+    fn a_cable_answers_each_bit() {
+        let mut serial: Serial = Serial::initialize();
+        serial.set_connected(true);
+        start(&mut serial, 0x80, TRANSFER_START | INTERNAL_CLOCK);
+        serial.tick(CYCLES_PER_BIT * 4);
+        // the clock stops on the first edge until the cable answers
+        assert_eq!(serial.outgoing_bit(), Some(true));
+        assert_eq!(serial.bits_shifted(), 0);
+        serial.complete_bit(false);
+        assert_eq!(serial.bits_shifted(), 1);
+        assert_eq!(serial.outgoing_bit(), None);
+        // unplugging mid edge reads the floating line
+        serial.tick(CYCLES_PER_BIT);
+        serial.set_connected(false);
+        assert_eq!(serial.read_register(SERIAL_DATA_REGISTER), 0x01);
+    }
+
+    #[test]
+    fn the_callback_gets_the_byte_once_it_is_sent() {
+        let sent: Rc<RefCell<Vec<u8>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut serial: Serial = Serial::initialize();
+        let log: Rc<RefCell<Vec<u8>>> = sent.clone();
+        serial.set_callback(Box::new(move |byte| log.borrow_mut().push(byte)));
+        start(&mut serial, 0x42, TRANSFER_START | INTERNAL_CLOCK);
+        serial.tick(CYCLES_PER_BIT * 8 - 1);
+        assert!(sent.borrow().is_empty());
+        serial.tick(1);
+        assert_eq!(*sent.borrow(), [0x42]);
+    }
+}
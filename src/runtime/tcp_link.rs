@@ -0,0 +1,332 @@
+use super::serial::{Serial, SerialPeripheral};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// a link cable between two processes over tcp.
+//
+// every frame is a type byte, a payload length byte, then the payload:
+//   HELLO    0x01 [version]         sent by both ends as soon as they connect
+//   SYNC     0x02 [cycles, u64 le]  machine cycles run since connecting, every SYNC_INTERVAL cycles
+//   TRANSFER 0x03 [byte]            the sender's internal clock started shifting this byte out
+//   REPLY    0x04 [byte]            what was shifted back for the other end's TRANSFER, 0xFF if
+//                                   nothing was waiting on the external clock
+//
+// transfers go a byte at a time. the end on the internal clock sends TRANSFER at its first clock
+// edge and holds its serial port until the REPLY comes back, the other end shifts the whole byte
+// through its port when the TRANSFER arrives. both ends run freely apart from that, the SYNC
+// frames keep either end from getting more than MAX_SKEW cycles ahead of the other, so a reply
+// never has to wait on a partner that's far behind
+const PROTOCOL_VERSION: u8 = 1;
+const FRAME_HELLO: u8 = 0x01;
+const FRAME_SYNC: u8 = 0x02;
+const FRAME_TRANSFER: u8 = 0x03;
+const FRAME_REPLY: u8 = 0x04;
+
+const SYNC_INTERVAL: usize = 4096; // machine cycles, about 4ms
+const MAX_SKEW: usize = 16384; // machine cycles, about 16ms
+const POLL_INTERVAL: usize = 64; // machine cycles between checks for frames from the partner
+
+enum Frame {
+    Hello(u8),
+    Sync(u64),
+    Transfer(u8),
+    Reply(u8),
+}
+
+pub struct TcpLink {
+    stream: TcpStream,
+    frames: Receiver<Frame>, // filled by a thread reading the socket
+    closed: bool, // the partner went away, the port acts as if nothing's plugged in
+    cycles: usize, // machine cycles run since connecting
+    next_sync: usize,
+    next_poll: usize,
+    partner_cycles: usize, // from the partner's last SYNC
+    reply: u8, // the byte coming back for the transfer in progress
+    awaiting_reply: bool, // a TRANSFER went out and its REPLY hasn't come back
+    stale_replies: usize, // replies to drop, from transfers both ends started at once
+}
+
+impl TcpLink {
+    // waits for a partner on this machine to connect on the given port. anyone who can reach the
+    // port can drive the serial port, so it's only open to localhost
+    pub fn listen(port: u16) -> io::Result<TcpLink> {
+        return TcpLink::listen_on(("127.0.0.1", port));
+    }
+
+    // waits for a partner on an address picked by the caller, for linking across machines
+    pub fn listen_on<A: ToSocketAddrs>(address: A) -> io::Result<TcpLink> {
+        let listener: TcpListener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        return TcpLink::start(stream);
+    }
+
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<TcpLink> {
+        return TcpLink::start(TcpStream::connect(address)?);
+    }
+
+    fn start(stream: TcpStream) -> io::Result<TcpLink> {
+        // every exchange is a handful of bytes that the other end is waiting on
+        stream.set_nodelay(true)?;
+        let reader: TcpStream = stream.try_clone()?;
+        let (sender, frames) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader: TcpStream = reader;
+            while let Ok(frame) = read_frame(&mut reader) {
+                if sender.send(frame).is_err() { break; }
+            }
+        });
+
+        let mut link: TcpLink = TcpLink {
+            stream,
+            frames,
+            closed: false,
+            cycles: 0,
+            next_sync: SYNC_INTERVAL,
+            next_poll: 0,
+            partner_cycles: 0,
+            reply: 0xFF,
+            awaiting_reply: false,
+            stale_replies: 0,
+        };
+        link.send(FRAME_HELLO, &[PROTOCOL_VERSION]);
+        match link.frames.recv() {
+            Ok(Frame::Hello(PROTOCOL_VERSION)) => { return Ok(link); }
+            Ok(Frame::Hello(version)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("partner speaks link protocol {}, not {}", version, PROTOCOL_VERSION)));
+            }
+            _ => { return Err(io::Error::new(io::ErrorKind::InvalidData, "partner didn't say hello")); }
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        return !self.closed;
+    }
+
+    fn send(&mut self, kind: u8, payload: &[u8]) {
+        if self.closed { return; }
+        let mut frame: Vec<u8> = vec![kind, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        if self.stream.write_all(&frame).is_err() { self.closed = true; }
+    }
+
+    // the next frame, waiting for it if blocking, None if there isn't one or the link is down
+    fn receive(&mut self, blocking: bool) -> Option<Frame> {
+        if self.closed { return None; }
+        let frame: Result<Frame, TryRecvError> = if blocking {
+            self.frames.recv().map_err(|_| TryRecvError::Disconnected)
+        } else {
+            self.frames.try_recv()
+        };
+        match frame {
+            Ok(frame) => { return Some(frame); }
+            Err(TryRecvError::Empty) => { return None; }
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                return None;
+            }
+        }
+    }
+
+    fn handle(&mut self, frame: Frame, serial: &mut Serial) {
+        match frame {
+            Frame::Hello(_) => {}
+            Frame::Sync(cycles) => {
+                self.partner_cycles = cycles as usize;
+            }
+            Frame::Transfer(incoming) => {
+                let mut outgoing: u8 = 0xFF;
+                if self.awaiting_reply {
+                    // both ends started a transfer at once, each keeps the other's byte
+                    outgoing = serial.sending_byte();
+                    self.reply = incoming;
+                    self.awaiting_reply = false;
+                    self.stale_replies += 1;
+                } else if serial.transferring() && !serial.internal_clock() {
+                    outgoing = 0;
+                    for bit in (0..8).rev() {
+                        let shifted: bool = serial.external_clock(incoming >> bit & 1 != 0).unwrap_or(true);
+                        outgoing = outgoing << 1 | shifted as u8;
+                    }
+                }
+                self.send(FRAME_REPLY, &[outgoing]);
+            }
+            Frame::Reply(byte) => {
+                if self.stale_replies > 0 {
+                    self.stale_replies -= 1;
+                } else if self.awaiting_reply {
+                    self.reply = byte;
+                    self.awaiting_reply = false;
+                }
+            }
+        }
+    }
+}
+
+impl SerialPeripheral for TcpLink {
+    fn update(&mut self, serial: &mut Serial, cycles: usize) {
+        self.cycles += cycles;
+        if self.closed {
+            // same as pulling the cable out
+            if serial.outgoing_bit().is_some() { serial.complete_bit(true); }
+            return;
+        }
+
+        if self.cycles >= self.next_poll {
+            self.next_poll = self.cycles + POLL_INTERVAL;
+            while let Some(frame) = self.receive(false) {
+                self.handle(frame, serial);
+            }
+        }
+
+        if serial.outgoing_bit().is_some() {
+            if serial.bits_shifted() == 0 {
+                let sending: u8 = serial.sending_byte();
+                self.send(FRAME_TRANSFER, &[sending]);
+                self.reply = 0xFF;
+                self.awaiting_reply = true;
+                // the partner answers within its next poll, anything else arriving meanwhile is handled as usual
+                while self.awaiting_reply {
+                    let Some(frame) = self.receive(true) else { break; };
+                    self.handle(frame, serial);
+                }
+                self.awaiting_reply = false;
+            }
+            // the rest of the byte comes in on this side's own clock edges
+            let bit: u8 = 7 - serial.bits_shifted();
+            serial.complete_bit(self.reply >> bit & 1 != 0);
+        }
+
+        if self.cycles >= self.next_sync {
+            self.next_sync += SYNC_INTERVAL;
+            let cycles: u64 = self.cycles as u64;
+            self.send(FRAME_SYNC, &cycles.to_le_bytes());
+        }
+        // too far ahead, wait for the partner to catch up
+        while !self.closed && self.cycles > self.partner_cycles + MAX_SKEW {
+            if let Some(frame) = self.receive(true) {
+                self.handle(frame, serial);
+            }
+        }
+    }
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Frame> {
+    let mut header: [u8; 2] = [0; 2];
+    stream.read_exact(&mut header)?;
+    let mut payload: Vec<u8> = vec![0; header[1] as usize];
+    stream.read_exact(&mut payload)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad link frame");
+    match (header[0], payload.len()) {
+        (FRAME_HELLO, 1) => { return Ok(Frame::Hello(payload[0])); }
+        (FRAME_SYNC, 8) => { return Ok(Frame::Sync(u64::from_le_bytes(payload.try_into().map_err(|_| invalid())?))); }
+        (FRAME_TRANSFER, 1) => { return Ok(Frame::Transfer(payload[0])); }
+        (FRAME_REPLY, 1) => { return Ok(Frame::Reply(payload[0])); }
+        _ => { return Err(invalid()); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::serial::{SERIAL_CONTROL_REGISTER, SERIAL_DATA_REGISTER, CYCLES_PER_BIT};
+    use std::net::SocketAddr;
+
+    // the two ends of a loopback connection
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener: TcpListener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        return (server, client);
+    }
+
+    // two links that have said hello to each other
+    fn link_pair() -> (TcpLink, TcpLink) {
+        let listener: TcpListener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || TcpLink::connect(address).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let server: TcpLink = TcpLink::start(stream).unwrap();
+        return (server, client.join().unwrap());
+    }
+
+    // a serial port on a cable, shifting data out on its own clock
+    fn master(data: u8) -> Serial {
+        let mut serial: Serial = Serial::initialize();
+        serial.set_connected(true);
+        serial.write_register(SERIAL_DATA_REGISTER, data);
+        serial.write_register(SERIAL_CONTROL_REGISTER, 0x81);
+        return serial;
+    }
+
+    #[test]
+    fn frames_are_read_by_type_and_length() {
+        let (mut reader, mut writer) = stream_pair();
+        writer.write_all(&[FRAME_HELLO, 1, PROTOCOL_VERSION]).unwrap();
+        writer.write_all(&[FRAME_SYNC, 8, 0x00, 0x10, 0, 0, 0, 0, 0, 0]).unwrap();
+        writer.write_all(&[FRAME_REPLY, 1, 0x42]).unwrap();
+        assert!(matches!(read_frame(&mut reader), Ok(Frame::Hello(PROTOCOL_VERSION))));
+        assert!(matches!(read_frame(&mut reader), Ok(Frame::Sync(0x1000))));
+        assert!(matches!(read_frame(&mut reader), Ok(Frame::Reply(0x42))));
+    }
+
+    #[test]
+    fn bad_frames_are_errors() {
+        for frame in [&[FRAME_SYNC, 4, 0, 0, 0, 0][..], &[FRAME_TRANSFER, 2, 0x12, 0x34], &[FRAME_HELLO, 0], &[0x09, 1, 0x00]] {
+            let (mut reader, mut writer) = stream_pair();
+            writer.write_all(frame).unwrap();
+            let error: io::Error = read_frame(&mut reader).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        // cut off part way through the payload
+        let (mut reader, mut writer) = stream_pair();
+        writer.write_all(&[FRAME_SYNC, 8, 0x00]).unwrap();
+        drop(writer);
+        assert_eq!(read_frame(&mut reader).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_transfer_answers_with_the_slaves_byte() {
+        let (mut first, mut second) = link_pair();
+        let mut slave: Serial = Serial::initialize();
+        slave.write_register(SERIAL_DATA_REGISTER, 0x34);
+        slave.write_register(SERIAL_CONTROL_REGISTER, 0x80);
+        first.handle(Frame::Transfer(0x12), &mut slave);
+        assert_eq!(slave.read_register(SERIAL_DATA_REGISTER), 0x12);
+        assert!(slave.interrupt_requested);
+        assert!(matches!(second.receive(true), Some(Frame::Reply(0x34))));
+    }
+
+    #[test]
+    fn crossed_transfers_keep_each_others_byte_and_drop_the_late_reply() {
+        let (mut first, mut second) = link_pair();
+        let mut serial: Serial = master(0x12);
+        // this end's TRANSFER is out when the partner's arrives
+        first.awaiting_reply = true;
+        first.handle(Frame::Transfer(0x34), &mut serial);
+        assert!(!first.awaiting_reply);
+        assert_eq!(first.reply, 0x34);
+        assert_eq!(first.stale_replies, 1);
+        assert!(matches!(second.receive(true), Some(Frame::Reply(0x12))));
+        // the partner's answer to this end's TRANSFER is left over
+        first.handle(Frame::Reply(0xFF), &mut serial);
+        assert_eq!(first.reply, 0x34);
+        assert_eq!(first.stale_replies, 0);
+    }
+
+    #[test]
+    fn a_partner_going_away_mid_transfer_reads_as_the_line_high() {
+        let (mut first, second) = link_pair();
+        let mut serial: Serial = master(0x12);
+        drop(second);
+        for _ in 0..8 {
+            serial.tick(CYCLES_PER_BIT);
+            first.update(&mut serial, CYCLES_PER_BIT);
+        }
+        assert!(!first.is_connected());
+        assert!(!serial.transferring());
+        assert!(serial.interrupt_requested);
+        assert_eq!(serial.read_register(SERIAL_DATA_REGISTER), 0xFF);
+    }
+}
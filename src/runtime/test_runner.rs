@@ -0,0 +1,226 @@
+use super::{Runtime, CYCLES_PER_SECOND};
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// mooneye roms hit this (LD B,B) once they're done
+const BREAKPOINT_OPCODE: u8 = 0x40;
+// and report success by loading the fibonacci numbers into B, C, D, E, H and L
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// blargg roms without serial output write their status to cartridge ram behind this signature
+const BLARGG_STATUS_ADDRESS: u16 = 0xA000;
+const BLARGG_SIGNATURE_ADDRESS: u16 = 0xA001;
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_TEXT_ADDRESS: u16 = 0xA004;
+const BLARGG_RUNNING: u8 = 0x80;
+
+// how often the wall clock is checked, in steps
+const TIME_CHECK_INTERVAL: usize = 0x10000;
+
+pub enum TestResult {
+    Passed(String),
+    Failed(String),
+    Timeout(String),
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestResult::Passed(output) => { write!(f, "PASSED\n{}", output) }
+            TestResult::Failed(output) => { write!(f, "FAILED\n{}", output) }
+            TestResult::Timeout(output) => { write!(f, "TIMEOUT\n{}", output) }
+        }
+    }
+}
+
+pub struct TestLimits {
+    pub cycles: usize, // emulated machine cycles
+    pub time: Option<Duration>, // host wall clock time
+}
+
+impl TestLimits {
+    pub fn from_seconds(emulated_seconds: usize) -> TestLimits {
+        return TestLimits { cycles: emulated_seconds * CYCLES_PER_SECOND, time: None };
+    }
+}
+
+// runs a blargg or mooneye test rom until it reports a result or a limit is hit
+pub fn run_test_rom(runtime: &mut Runtime, limits: &TestLimits) -> TestResult {
+    let output: Rc<RefCell<Vec<u8>>> = Rc::new(RefCell::new(Vec::new()));
+    let callback_output: Rc<RefCell<Vec<u8>>> = output.clone();
+    runtime.set_serial_callback(move |byte| callback_output.borrow_mut().push(byte));
+    let start_cycles: usize = runtime.cycles();
+    let start_time: Instant = Instant::now();
+    let mut steps: usize = 0;
+
+    loop {
+        let pc: u16 = runtime.pc();
+        let at_breakpoint: bool = runtime.read_byte(pc) == BREAKPOINT_OPCODE;
+        runtime.step();
+        steps += 1;
+
+        if at_breakpoint && runtime.pc() == pc.wrapping_add(1) {
+            return mooneye_result(runtime);
+        }
+        if let Some(result) = serial_result(&output.borrow()) {
+            return result;
+        }
+        if let Some(result) = memory_result(runtime) {
+            return result;
+        }
+
+        if runtime.cycles() - start_cycles >= limits.cycles {
+            return TestResult::Timeout(output_text(&output.borrow()));
+        }
+        if let Some(time) = limits.time {
+            if steps.is_multiple_of(TIME_CHECK_INTERVAL) && start_time.elapsed() >= time {
+                return TestResult::Timeout(output_text(&output.borrow()));
+            }
+        }
+    }
+}
+
+fn mooneye_result(runtime: &Runtime) -> TestResult {
+    let registers = runtime.registers();
+    let values: [u8; 6] = [registers.b, registers.c, registers.d, registers.e, registers.h, registers.l];
+    let description: String = format!(
+        "B: {:02x} C: {:02x} D: {:02x} E: {:02x} H: {:02x} L: {:02x}",
+        values[0], values[1], values[2], values[3], values[4], values[5],
+    );
+    if values == MOONEYE_PASS {
+        return TestResult::Passed(description);
+    }
+    return TestResult::Failed(description);
+}
+
+fn serial_result(output: &[u8]) -> Option<TestResult> {
+    // only worth searching when a line was just finished
+    if output.last() != Some(&b'\n') { return None; }
+    let text: String = output_text(output);
+    if text.contains("Passed") {
+        return Some(TestResult::Passed(text));
+    }
+    if text.contains("Failed") {
+        return Some(TestResult::Failed(text));
+    }
+    return None;
+}
+
+fn memory_result(runtime: &Runtime) -> Option<TestResult> {
+    let signature: [u8; 3] = [
+        runtime.read_byte(BLARGG_SIGNATURE_ADDRESS),
+        runtime.read_byte(BLARGG_SIGNATURE_ADDRESS + 1),
+        runtime.read_byte(BLARGG_SIGNATURE_ADDRESS + 2),
+    ];
+    if signature != BLARGG_SIGNATURE { return None; }
+    let status: u8 = runtime.read_byte(BLARGG_STATUS_ADDRESS);
+    if status == BLARGG_RUNNING { return None; }
+
+    let mut text: String = String::new();
+    let mut address: u16 = BLARGG_TEXT_ADDRESS;
+    while address < 0xC000 {
+        let byte: u8 = runtime.read_byte(address);
+        if byte == 0 { break; }
+        text.push(byte as char);
+        address += 1;
+    }
+    if status == 0x00 {
+        return Some(TestResult::Passed(text));
+    }
+    return Some(TestResult::Failed(format!("status 0x{:02x}\n{}", status, text)));
+}
+
+fn output_text(output: &[u8]) -> String {
+    return output.iter().map(|byte| *byte as char).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the code goes after the cartridge header, with a JP 0x0150 at the entry point
+    fn runtime_with_code(code: &[u8], cartridge_type: u8, ram_size: u8) -> Runtime {
+        let mut rom: Vec<u8> = vec![0x00; 0x8000];
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0150 + code.len()].copy_from_slice(code);
+        rom[0x0147] = cartridge_type;
+        rom[0x0149] = ram_size;
+        return Runtime::from_rom_bytes(rom).unwrap();
+    }
+
+    fn run(code: &[u8]) -> TestResult {
+        return run_test_rom(&mut runtime_with_code(code, 0x00, 0x00), &TestLimits::from_seconds(1));
+    }
+
+    // LD A,value / LD (address),A
+    fn store(code: &mut Vec<u8>, address: u16, value: u8) {
+        code.extend_from_slice(&[0x3E, value, 0xEA, address as u8, (address >> 8) as u8]);
+    }
+
+    // each byte out over serial on the internal clock, waiting for SC bit 7 to drop
+    fn serial_code(text: &str) -> Vec<u8> {
+        let mut code: Vec<u8> = Vec::new();
+        for byte in text.bytes() {
+            code.extend_from_slice(&[0x3E, byte, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA]);
+        }
+        code.extend_from_slice(&[0x18, 0xFE]);
+        return code;
+    }
+
+    // the blargg status block in cartridge ram, running first and then the given status
+    fn blargg_memory_code(status: u8, text: &str) -> Vec<u8> {
+        let mut code: Vec<u8> = Vec::new();
+        store(&mut code, 0x0000, 0x0A);
+        store(&mut code, BLARGG_STATUS_ADDRESS, BLARGG_RUNNING);
+        for (index, byte) in BLARGG_SIGNATURE.iter().enumerate() {
+            store(&mut code, BLARGG_SIGNATURE_ADDRESS + index as u16, *byte);
+        }
+        for (index, byte) in text.bytes().enumerate() {
+            store(&mut code, BLARGG_TEXT_ADDRESS + index as u16, byte);
+        }
+        store(&mut code, BLARGG_STATUS_ADDRESS, status);
+        code.extend_from_slice(&[0x18, 0xFE]);
+        return code;
+    }
+
+    #[test]
+    fn ld_b_b_with_the_fibonacci_numbers_passes() {
+        // LD B,3 / LD C,5 / LD D,8 / LD E,13 / LD H,21 / LD L,34 / LD B,B
+        let code: [u8; 13] = [0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, 0x40];
+        assert!(matches!(run(&code), TestResult::Passed(_)));
+        // LD B,3 / LD B,B
+        assert!(matches!(run(&[0x06, 3, 0x40]), TestResult::Failed(_)));
+    }
+
+    #[test]
+    fn serial_output_reports_the_result() {
+        let TestResult::Passed(output) = run(&serial_code("cpu_instrs\n\nPassed\n")) else { panic!("not a pass"); };
+        assert_eq!(output, "cpu_instrs\n\nPassed\n");
+        assert!(matches!(run(&serial_code("Failed #3\n")), TestResult::Failed(_)));
+        // nothing counts until the line is finished
+        assert!(matches!(run(&serial_code("Passed")), TestResult::Timeout(_)));
+    }
+
+    #[test]
+    fn the_status_block_in_cartridge_ram_reports_the_result() {
+        // MBC1 with 8KiB of ram
+        let mut runtime: Runtime = runtime_with_code(&blargg_memory_code(0x00, "ok"), 0x03, 0x02);
+        let TestResult::Passed(text) = run_test_rom(&mut runtime, &TestLimits::from_seconds(1)) else { panic!("not a pass"); };
+        assert_eq!(text, "ok");
+        let mut runtime: Runtime = runtime_with_code(&blargg_memory_code(0x03, "bad"), 0x03, 0x02);
+        let TestResult::Failed(text) = run_test_rom(&mut runtime, &TestLimits::from_seconds(1)) else { panic!("not a failure"); };
+        assert_eq!(text, "status 0x03\nbad");
+    }
+
+    #[test]
+    fn roms_that_never_finish_time_out() {
+        let mut runtime: Runtime = runtime_with_code(&[0x18, 0xFE], 0x00, 0x00);
+        assert!(matches!(run_test_rom(&mut runtime, &TestLimits::from_seconds(1)), TestResult::Timeout(_)));
+        assert!((CYCLES_PER_SECOND..CYCLES_PER_SECOND + 8).contains(&runtime.cycles()));
+
+        let limits: TestLimits = TestLimits { cycles: usize::MAX, time: Some(Duration::from_millis(10)) };
+        assert!(matches!(run_test_rom(&mut runtime, &limits), TestResult::Timeout(_)));
+    }
+}
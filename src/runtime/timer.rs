@@ -0,0 +1,248 @@
+use super::timer_control::TimerControl;
+use super::save_state::{SaveState, StateError, StateReader, StateWriter};
+use super::cpu::memory::{DIVIDER_REGISTER, TIMER_REGISTER, TIMER_MODULO_REGISTER, TIMER_CONTROL_REGISTER};
+
+// DIV, TIMA, TMA and TAC, all driven off one 16 bit counter that the cpu clock increments
+pub struct Timer {
+    system_counter: u16, // counts t-cycles, DIV is the top byte
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload_pending: bool, // TIMA overflowed last cycle and reads 0, the reload happens at the end of this cycle
+    reloading: bool, // TIMA was reloaded this cycle, writes to it are lost and writes to TMA go through
+    pub interrupt_requested: bool,
+    apu_bit: u8, // the counter bit the apu frame sequencer counts, DIV bit 4 or bit 5 in double speed
+    pub frame_sequencer_clocks: usize, // falling edges of that bit not yet passed on to the apu
+}
+
+impl Timer {
+    pub fn initialize() -> Timer {
+        return Timer {
+            system_counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reload_pending: false,
+            reloading: false,
+            interrupt_requested: false,
+            apu_bit: 12,
+            frame_sequencer_clocks: 0,
+        };
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.tick_cycle();
+        }
+    }
+
+    fn tick_cycle(&mut self) {
+        self.reloading = false;
+        if self.reload_pending {
+            self.reload_pending = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            self.interrupt_requested = true;
+        }
+
+        let before: bool = self.timer_signal();
+        let apu_before: bool = self.apu_signal();
+        self.system_counter = self.system_counter.wrapping_add(4);
+        if before && !self.timer_signal() {
+            self.increment_tima();
+        }
+        if apu_before && !self.apu_signal() {
+            self.frame_sequencer_clocks += 1;
+        }
+    }
+
+    fn apu_signal(&self) -> bool {
+        return (self.system_counter >> self.apu_bit) & 1 == 1;
+    }
+
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.apu_bit = if double_speed { 13 } else { 12 };
+    }
+
+    // the selected counter bit anded with the enable bit, TIMA counts on its falling edge
+    fn timer_signal(&self) -> bool {
+        let control: TimerControl = TimerControl::from(self.tac);
+        return control.enabled && (self.system_counter >> control.speed.counter_bit()) & 1 == 1;
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            // TIMA sits at 0 for a cycle before TMA is loaded and the interrupt is requested
+            self.reload_pending = true;
+        }
+    }
+
+    pub fn divider(&self) -> u8 {
+        return (self.system_counter >> 8) as u8;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            DIVIDER_REGISTER => { return self.divider(); }
+            TIMER_REGISTER => { return self.tima; }
+            TIMER_MODULO_REGISTER => { return self.tma; }
+            _ => { return self.tac | 0xF8; }
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            DIVIDER_REGISTER => {
+                // resetting the counter can drop the selected bit, which counts as a falling edge
+                let before: bool = self.timer_signal();
+                let apu_before: bool = self.apu_signal();
+                self.system_counter = 0;
+                if before { self.increment_tima(); }
+                if apu_before { self.frame_sequencer_clocks += 1; }
+            }
+            TIMER_REGISTER => {
+                if self.reloading { return; }
+                // a write in the cycle after an overflow cancels the reload and the interrupt
+                self.reload_pending = false;
+                self.tima = value;
+            }
+            TIMER_MODULO_REGISTER => {
+                self.tma = value;
+                if self.reloading { self.tima = value; }
+            }
+            TIMER_CONTROL_REGISTER => {
+                // switching the enable or the selected bit is the same and gate, so it can glitch an increment
+                let before: bool = self.timer_signal();
+                self.tac = value & 0x07;
+                if before && !self.timer_signal() { self.increment_tima(); }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.system_counter);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+        state.write_bool(self.reload_pending);
+        state.write_bool(self.reloading);
+        state.write_bool(self.interrupt_requested);
+        state.write_u8(self.apu_bit);
+        state.write_usize(self.frame_sequencer_clocks);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.system_counter = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()? & 0x07;
+        self.reload_pending = state.read_bool()?;
+        self.reloading = state.read_bool()?;
+        self.interrupt_requested = state.read_bool()?;
+        self.apu_bit = state.read_u8()?;
+        if self.apu_bit != 12 && self.apu_bit != 13 { return Err(StateError::Invalid("timer")); }
+        self.frame_sequencer_clocks = state.read_usize()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TAC 0x05 counts on bit 3, TIMA goes up every 4 machine cycles
+    fn fast_timer() -> Timer {
+        let mut timer: Timer = Timer::initialize();
+        timer.write_register(TIMER_CONTROL_REGISTER, 0x05);
+        return timer;
+    }
+
+    #[test]
+    fn div_reset_with_the_bit_high_increments_tima() {
+        let mut timer: Timer = fast_timer();
+        timer.tick(2);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0);
+        timer.write_register(DIVIDER_REGISTER, 0x00);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 1);
+
+        // with the bit low the reset doesn't count
+        timer.tick(1);
+        timer.write_register(DIVIDER_REGISTER, 0x00);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 1);
+        // and the next increment is a whole period away
+        timer.tick(3);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 1);
+        timer.tick(1);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 2);
+    }
+
+    #[test]
+    fn tac_change_with_the_bit_high_increments_tima() {
+        let mut timer: Timer = fast_timer();
+        timer.tick(2);
+        timer.write_register(TIMER_CONTROL_REGISTER, 0x00);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 1);
+
+        // switching to a bit that's low is a falling edge too
+        let mut timer: Timer = fast_timer();
+        timer.tick(2);
+        timer.write_register(TIMER_CONTROL_REGISTER, 0x04);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 1);
+
+        // with the bit low nothing happens
+        let mut timer: Timer = fast_timer();
+        timer.tick(1);
+        timer.write_register(TIMER_CONTROL_REGISTER, 0x00);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0);
+    }
+
+    // a timer that overflows on its next machine cycle
+    fn overflowing_timer() -> Timer {
+        let mut timer: Timer = fast_timer();
+        timer.write_register(TIMER_MODULO_REGISTER, 0xAB);
+        timer.write_register(TIMER_REGISTER, 0xFF);
+        timer.tick(3);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0xFF);
+        timer.tick(1);
+        return timer;
+    }
+
+    #[test]
+    fn overflow_reloads_a_cycle_late() {
+        let mut timer: Timer = overflowing_timer();
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0x00);
+        assert!(!timer.interrupt_requested);
+        timer.tick(1);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0xAB);
+        assert!(timer.interrupt_requested);
+    }
+
+    #[test]
+    fn tima_write_before_the_reload_cancels_it() {
+        let mut timer: Timer = overflowing_timer();
+        timer.write_register(TIMER_REGISTER, 0x12);
+        timer.tick(1);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0x12);
+        assert!(!timer.interrupt_requested);
+    }
+
+    #[test]
+    fn writes_on_the_reload_cycle() {
+        let mut timer: Timer = overflowing_timer();
+        timer.tick(1);
+        // TIMA keeps the reloaded value, TMA goes through to it
+        timer.write_register(TIMER_REGISTER, 0x12);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0xAB);
+        timer.write_register(TIMER_MODULO_REGISTER, 0x34);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0x34);
+        // a cycle later TIMA can be written again
+        timer.tick(1);
+        timer.write_register(TIMER_REGISTER, 0x12);
+        assert_eq!(timer.read_register(TIMER_REGISTER), 0x12);
+    }
+}
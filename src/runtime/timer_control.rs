@@ -33,21 +33,21 @@ impl std::convert::From<u8> for TimerSpeed {
     }
 }
 
-// using mem-cycles for timers atm
-impl std::convert::From<TimerSpeed> for usize {
-    fn from(speed: TimerSpeed) -> Self {
-        match speed {
+impl TimerSpeed {
+    // the bit of the system counter (counting t-cycles) whose falling edge clocks TIMA
+    pub fn counter_bit(self) -> u8 {
+        match self {
             TimerSpeed::Clock1024 => {
-                256
+                9
             }
             TimerSpeed::Clock16 => {
-                4
+                3
             }
             TimerSpeed::Clock64 => {
-                16
+                5
             }
             TimerSpeed::Clock256 => {
-                64
+                7
             }
         }
     }
@@ -65,11 +65,3 @@ impl std::convert::From<u8> for TimerControl {
         }
     }
 }
-
-pub fn calc_increments(speed: TimerSpeed, past: usize, stepped: usize) -> u8 {
-    let speed_modulo: usize = usize::from(speed);
-    let modulo_past: usize = past / speed_modulo;
-    let modulo_now: usize = (past + stepped) / speed_modulo;
-
-    return (modulo_now - modulo_past) as u8
-}